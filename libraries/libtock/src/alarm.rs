@@ -1,8 +1,13 @@
 use core::ops::Generator;
 
-use crate::result::{Error, Result};
+use crate::executor::Source;
+use crate::futures::wait_for;
+use crate::queue::{OverflowPolicy, Queue};
+use crate::result::{library_error, Error, Result};
 use crate::syscalls::{command, subscribe, CallbackMessage};
 use crate::task::{DriverTask, DriverTaskClient};
+use crate::time::{Duration, Frequency, Instant};
+use crate::virtual_alarm::{AlarmMux, MAX_VIRTUAL_ALARMS};
 
 const DRIVER_NUM: usize = 0;
 
@@ -18,20 +23,46 @@ mod command_num {
     pub const START: usize = 4;
 }
 
-static mut ALARM_MESSAGE: Option<CallbackMessage> = None;
+static mut ALARM_MESSAGE: Queue<CallbackMessage> = Queue::new();
 
 #[derive(Copy, Clone)]
 pub enum AlarmClientMessage {
     Event(AlarmEventData),
 }
 
-static mut ALARM_CLIENT_MESSAGE: Option<AlarmClientMessage> = None;
+// The hardware alarm is shared by all virtual alarms. It is always programmed
+// for the earliest armed virtual alarm, see `update_hardware_alarm`.
+static mut ALARM_MUX: AlarmMux = AlarmMux::new();
+
+// Queried on first use by `AlarmClient::frequency`
+static mut ALARM_FREQUENCY: Option<Frequency> = None;
+
+// Tick the hardware alarm is currently programmed for
+static mut ALARM_PROGRAMMED: Option<usize> = None;
+
+static mut VIRTUAL_ALARM_IN_USE: [bool; MAX_VIRTUAL_ALARMS] = [false; MAX_VIRTUAL_ALARMS];
+
+// Virtual alarms used by the library itself, see `AlarmClient::internal_oneshot`.
+// They are left out of the any-alarm view of `AlarmClient`.
+static mut VIRTUAL_ALARM_INTERNAL: [bool; MAX_VIRTUAL_ALARMS] = [false; MAX_VIRTUAL_ALARMS];
+
+// Client messages, one queue per virtual alarm
+static mut VIRTUAL_ALARM_MESSAGE: [Queue<AlarmClientMessage>; MAX_VIRTUAL_ALARMS] = [
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+];
 
 extern "C" fn alarm_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
     let cb_message = CallbackMessage::new(arg0, arg1, arg2, userdata);
 
     unsafe {
-        ALARM_MESSAGE = Some(cb_message);
+        let _ = ALARM_MESSAGE.push(cb_message);
     }
 }
 
@@ -55,29 +86,95 @@ impl AlarmEventData {
     }
 }
 
-pub struct Alarm;
+pub struct Alarm(());
 
 impl Alarm {
-    pub fn new() -> Alarm {
-        Alarm
+    pub(crate) fn new() -> Alarm {
+        Alarm(())
     }
 
-    // Safety : This coroutine is called whenever there is an incoming callback
-    //          message. When called, it *must* consume the incoming callback
-    //          message before yielding.
-    pub unsafe fn get_task(&self) -> impl Generator<Yield = (), Return = ()> + '_ {
+    // This coroutine is resumed whenever there are incoming callback messages.
+    // When resumed, it consumes all incoming callback messages before yielding.
+    // `Drivers::take` hands out a single `Alarm`, so there is only ever one
+    // such coroutine.
+    pub fn get_task(&self) -> impl Generator<Yield = (), Return = ()> + '_ {
         || loop {
-            if let Some(cb_message) = ALARM_MESSAGE.take() {
-                let now = cb_message.get_arg0();
-                let expiration = cb_message.get_arg1();
+            unsafe {
+                while let Some(cb_message) = ALARM_MESSAGE.pop() {
+                    // The hardware alarm has fired, so it is no longer
+                    // programmed.
+                    ALARM_PROGRAMMED = None;
 
-                ALARM_CLIENT_MESSAGE = Some(AlarmClientMessage::Event(AlarmEventData::new(
-                    now, expiration,
-                )));
+                    // A failing syscall leaves the hardware alarm stopped. The
+                    // next call that arms a virtual alarm programs it again.
+                    let _ = update_hardware_alarm(cb_message.get_arg0());
+                }
             }
+
             yield;
         }
     }
+}
+
+impl DriverTask for Alarm {
+    fn has_message(&self) -> bool {
+        unsafe { !ALARM_MESSAGE.is_empty() }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { ALARM_MESSAGE.dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { ALARM_MESSAGE.configure(capacity, policy) }
+    }
+}
+
+// Dispatches the virtual alarms that have expired at `now` and programs the
+// hardware alarm for the earliest remaining one.
+unsafe fn update_hardware_alarm(now: usize) -> Result<()> {
+    let alarm = AlarmClient::new();
+    let mut now = now;
+
+    loop {
+        ALARM_MUX.expire(now, |i, expiration| {
+            let _ = VIRTUAL_ALARM_MESSAGE[i].push(AlarmClientMessage::Event(AlarmEventData::new(
+                now, expiration,
+            )));
+        });
+
+        match ALARM_MUX.next_expiration() {
+            Some(expiration) => {
+                if ALARM_PROGRAMMED != Some(expiration) {
+                    alarm.start(expiration)?;
+                    ALARM_PROGRAMMED = Some(expiration);
+                }
+
+                // If the earliest alarm expired while we were programming the
+                // hardware alarm, the hardware alarm would only fire after the
+                // tick counter wraps.
+                now = alarm.get_tic()?;
+                if !ALARM_MUX.has_expired(now) {
+                    return Ok(());
+                }
+            }
+            None => {
+                if let Some(tic) = ALARM_PROGRAMMED.take() {
+                    alarm.stop(tic)?;
+                }
+
+                return Ok(());
+            }
+        }
+    }
+}
+
+pub struct AlarmClient(());
+
+impl AlarmClient {
+    pub(crate) fn new() -> AlarmClient {
+        AlarmClient(())
+    }
 
     pub fn initiate(&self) -> Result<()> {
         unsafe {
@@ -91,11 +188,32 @@ impl Alarm {
         }
     }
 
-    // Safety : Assumes `get_clock_frequency` will not fail
-    pub unsafe fn millisecond_to_tic(&self, ms: usize) -> usize {
-        let frequency = self.get_clock_frequency().unwrap();
+    // The clock frequency never changes, so the kernel is only asked once
+    pub fn frequency(&self) -> Result<Frequency> {
+        unsafe {
+            if let Some(frequency) = ALARM_FREQUENCY {
+                return Ok(frequency);
+            }
+
+            let frequency = Frequency::from_hz(self.get_clock_frequency()? as u32)
+                .ok_or_else(|| library_error(Error::FAIL))?;
+            ALARM_FREQUENCY = Some(frequency);
+
+            Ok(frequency)
+        }
+    }
+
+    pub fn now(&self) -> Result<Instant> {
+        self.get_tic().map(|tic| Instant::from_ticks(tic as u32))
+    }
+
+    // `Err(Error::EINVAL)` if `ms` is longer than `time::MAX_TICKS`
+    pub fn duration_from_ms(&self, ms: u32) -> Result<Duration> {
+        Duration::from_ms(ms, self.frequency()?).ok_or_else(|| library_error(Error::EINVAL))
+    }
 
-        (ms / 1000) * frequency + (ms % 1000) * (frequency / 1000)
+    pub fn duration_from_us(&self, us: u32) -> Result<Duration> {
+        Duration::from_us(us, self.frequency()?).ok_or_else(|| library_error(Error::EINVAL))
     }
 
     pub fn is_present(&self) -> Result<usize> {
@@ -110,52 +228,621 @@ impl Alarm {
         unsafe { command(DRIVER_NUM, command_num::TICK, 0, 0) }
     }
 
-    pub fn stop(&self, tic: usize) -> Result<usize> {
+    // The hardware alarm is owned by the virtual alarms
+    fn stop(&self, tic: usize) -> Result<usize> {
         unsafe { command(DRIVER_NUM, command_num::STOP, tic, 0) }
     }
 
-    pub fn start(&self, tic: usize) -> Result<usize> {
+    fn start(&self, tic: usize) -> Result<usize> {
         unsafe { command(DRIVER_NUM, command_num::START, tic, 0) }
     }
+
+    // Virtual alarm that expires once, `dt` from now
+    pub fn oneshot(&self, dt: Duration) -> Result<VirtualAlarm> {
+        let alarm = self.allocate()?;
+        alarm.arm(dt.ticks() as usize, 0)?;
+        Ok(alarm)
+    }
+
+    // Virtual alarm that expires every `period`, until it is cancelled
+    pub fn periodic(&self, period: Duration) -> Result<VirtualAlarm> {
+        if period.ticks() == 0 {
+            return Err(library_error(Error::EINVAL));
+        }
+
+        let alarm = self.allocate()?;
+        alarm.arm(period.ticks() as usize, period.ticks() as usize)?;
+        Ok(alarm)
+    }
+
+    // Like `oneshot`, but the alarm is not seen by the `AlarmClient` methods
+    // that cover every virtual alarm, so that its messages are not reaped by
+    // other clients.
+    pub(crate) fn internal_oneshot(&self, dt: Duration) -> Result<VirtualAlarm> {
+        let alarm = self.allocate()?;
+
+        unsafe {
+            VIRTUAL_ALARM_INTERNAL[alarm.index] = true;
+        }
+
+        alarm.arm(dt.ticks() as usize, 0)?;
+        Ok(alarm)
+    }
+
+    pub async fn sleep(&self, duration: Duration) -> Result<AlarmEventData> {
+        self.oneshot(duration)?.wait().await
+    }
+
+    // Reaps the oldest message of the first virtual alarm that has one.
+    // Internal alarms are skipped.
+    //
+    // `Err(Error::ENOMEM)` reports alarm events that were dropped under the
+    // `OverflowPolicy::Error` policy. The queued events are still available.
+    pub fn reap_get_data(&self) -> Result<AlarmEventData> {
+        unsafe {
+            client_queues()
+                .find(|q| !q.is_empty())
+                .ok_or_else(|| library_error(Error::EINVAL))
+                .and_then(|q| reap_data(q))
+        }
+    }
+
+    fn allocate(&self) -> Result<VirtualAlarm> {
+        self.initiate()?;
+
+        unsafe {
+            let index = VIRTUAL_ALARM_IN_USE
+                .iter()
+                .position(|in_use| !in_use)
+                .ok_or_else(|| library_error(Error::ENOMEM))?;
+
+            VIRTUAL_ALARM_IN_USE[index] = true;
+
+            Ok(VirtualAlarm { index })
+        }
+    }
 }
 
-impl DriverTask for Alarm {
+// Queues of the virtual alarms that are not internal
+unsafe fn client_queues() -> impl Iterator<Item = &'static mut Queue<AlarmClientMessage>> {
+    VIRTUAL_ALARM_MESSAGE
+        .iter_mut()
+        .zip(VIRTUAL_ALARM_INTERNAL.iter())
+        .filter(|(_, internal)| !**internal)
+        .map(|(q, _)| q)
+}
+
+// Any virtual alarm that is not internal
+impl DriverTaskClient for AlarmClient {
     fn has_message(&self) -> bool {
-        unsafe { ALARM_MESSAGE.is_some() }
+        unsafe { client_queues().any(|q| !q.is_empty()) }
+    }
+
+    fn reap_message(&self) {
+        let _ = self.reap_get_data();
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { client_queues().map(|q| q.dropped()).sum() }
+    }
+
+    // Applies to the queue of every virtual alarm that is not internal
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe {
+            client_queues()
+                .map(|q| q.configure(capacity, policy))
+                .fold(Ok(()), |res, r| res.and(r))
+        }
+    }
+}
+
+fn reap_data(queue: &mut Queue<AlarmClientMessage>) -> Result<AlarmEventData> {
+    if queue.take_overflow() {
+        return Err(library_error(Error::ENOMEM));
     }
+
+    queue
+        .pop()
+        .ok_or_else(|| library_error(Error::EINVAL))
+        .map(|x| match x {
+            AlarmClientMessage::Event(d) => d,
+        })
 }
 
-pub struct AlarmClient;
+// Handle to a virtual alarm, created by `AlarmClient::oneshot` or
+// `AlarmClient::periodic`. Every expiration is queued as a client message.
+// Dropping the handle cancels the alarm.
+pub struct VirtualAlarm {
+    index: usize,
+}
 
-impl AlarmClient {
-    pub fn new() -> AlarmClient {
-        AlarmClient
+impl VirtualAlarm {
+    fn arm(&self, dt: usize, period: usize) -> Result<()> {
+        let now = AlarmClient::new().get_tic()?;
+
+        unsafe {
+            ALARM_MUX.arm(now, self.index, dt, period)?;
+            update_hardware_alarm(now)
+        }
     }
 
-    pub fn reap_get_data(&self) -> Result<AlarmEventData> {
+    // Disarms the alarm and discards its queued messages. The hardware alarm
+    // is re-programmed for the next virtual alarm, if any.
+    pub fn cancel(&self) -> Result<()> {
         unsafe {
-            let a = ALARM_CLIENT_MESSAGE.clone();
-            a.ok_or(Error::EINVAL).map(|x| match x {
-                AlarmClientMessage::Event(d) => {
-                    ALARM_CLIENT_MESSAGE = None;
-                    d
-                }
-            })
+            VIRTUAL_ALARM_MESSAGE[self.index].clear();
+
+            if ALARM_MUX.disarm(self.index) {
+                let now = AlarmClient::new().get_tic()?;
+                update_hardware_alarm(now)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    // Arms the alarm again to expire once, `dt` from now. Queued messages are
+    // discarded.
+    pub fn rearm(&self, dt: Duration) -> Result<()> {
+        unsafe {
+            VIRTUAL_ALARM_MESSAGE[self.index].clear();
         }
+
+        self.arm(dt.ticks() as usize, 0)
+    }
+
+    // A one-shot alarm is disarmed once it has expired
+    pub fn is_armed(&self) -> bool {
+        unsafe { ALARM_MUX.is_armed(self.index) }
+    }
+
+    pub async fn wait(&self) -> Result<AlarmEventData> {
+        let index = self.index;
+
+        wait_for(Source::Alarm, move || unsafe {
+            !VIRTUAL_ALARM_MESSAGE[index].is_empty()
+        })
+        .await;
+
+        self.reap_get_data()
+    }
+
+    pub fn reap_get_data(&self) -> Result<AlarmEventData> {
+        unsafe { reap_data(&mut VIRTUAL_ALARM_MESSAGE[self.index]) }
     }
 }
 
-impl DriverTaskClient for AlarmClient {
+impl DriverTaskClient for VirtualAlarm {
     fn has_message(&self) -> bool {
-        unsafe { ALARM_CLIENT_MESSAGE.is_some() }
+        unsafe { !VIRTUAL_ALARM_MESSAGE[self.index].is_empty() }
     }
 
     fn reap_message(&self) {
         unsafe {
-            let a = ALARM_CLIENT_MESSAGE.clone();
-            a.map(|_| {
-                ALARM_CLIENT_MESSAGE = None;
-            });
+            VIRTUAL_ALARM_MESSAGE[self.index].pop();
         }
     }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { VIRTUAL_ALARM_MESSAGE[self.index].dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { VIRTUAL_ALARM_MESSAGE[self.index].configure(capacity, policy) }
+    }
+}
+
+impl Drop for VirtualAlarm {
+    fn drop(&mut self) {
+        let _ = self.cancel();
+
+        unsafe {
+            VIRTUAL_ALARM_MESSAGE[self.index] = Queue::new();
+            VIRTUAL_ALARM_IN_USE[self.index] = false;
+            VIRTUAL_ALARM_INTERNAL[self.index] = false;
+        }
+    }
+}
+
+// Internal alarm that follows the next deadline of a timer driven state
+// machine, such as the gesture recognizer or the LED animator. The virtual
+// alarm is allocated with the first deadline.
+pub(crate) struct DeadlineAlarm {
+    alarm: Option<VirtualAlarm>,
+}
+
+impl DeadlineAlarm {
+    pub(crate) const fn new() -> DeadlineAlarm {
+        DeadlineAlarm { alarm: None }
+    }
+
+    // The deadline has been reached
+    pub(crate) fn is_due(&self) -> bool {
+        self.alarm.as_ref().map_or(false, |a| a.has_message())
+    }
+
+    // Calls `expire` with the current time, and arms the alarm for the
+    // deadline it returns, or cancels it for `None`. A deadline that is reached
+    // while the alarm is being armed expires without a callback message, so
+    // `expire` is then called again right away.
+    pub(crate) fn update<F>(&mut self, mut expire: F) -> Result<()>
+    where
+        F: FnMut(Instant) -> Option<Instant>,
+    {
+        loop {
+            if let Some(alarm) = self.alarm.as_ref() {
+                while alarm.has_message() {
+                    alarm.reap_message();
+                }
+            }
+
+            let now = AlarmClient::new().now()?;
+
+            let deadline = match expire(now) {
+                Some(deadline) => deadline,
+                None => {
+                    return match self.alarm.as_ref() {
+                        Some(alarm) => alarm.cancel(),
+                        None => Ok(()),
+                    };
+                }
+            };
+
+            let dt = if deadline.is_after(now) {
+                deadline.duration_since(now)
+            } else {
+                Duration::from_ticks(0)
+            };
+
+            match self.alarm.as_ref() {
+                Some(alarm) => alarm.rearm(dt)?,
+                None => self.alarm = Some(AlarmClient::new().internal_oneshot(dt)?),
+            }
+
+            if !self.is_due() {
+                return Ok(());
+            }
+        }
+    }
+
+    // Gives the virtual alarm back
+    pub(crate) fn release(&mut self) {
+        self.alarm = None;
+    }
+
+    // Forgets the alarm instead of dropping it, for when the alarm state has
+    // already been reset
+    #[cfg(not(target_arch = "arm"))]
+    pub(crate) fn forget(&mut self) {
+        core::mem::forget(self.alarm.take());
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    ALARM_MESSAGE = Queue::new();
+    ALARM_MUX = AlarmMux::new();
+    ALARM_FREQUENCY = None;
+    ALARM_PROGRAMMED = None;
+    VIRTUAL_ALARM_IN_USE = [false; MAX_VIRTUAL_ALARMS];
+    VIRTUAL_ALARM_INTERNAL = [false; MAX_VIRTUAL_ALARMS];
+    VIRTUAL_ALARM_MESSAGE = [
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::pin::Pin;
+    use std::vec;
+    use std::vec::Vec;
+
+    use crate::syscalls::fake::{Kernel, SyscallClass, SyscallRecord};
+    use crate::syscalls::yieldk;
+
+    #[test]
+    fn commands_use_alarm_driver() {
+        let kernel = Kernel::new();
+        kernel.set_result(
+            SyscallClass::Command,
+            DRIVER_NUM,
+            command_num::TICK,
+            Ok(1234),
+        );
+
+        let alarm = AlarmClient::new();
+
+        assert_eq!(alarm.get_tic(), Ok(1234));
+        assert_eq!(alarm.start(2000), Ok(0));
+        assert_eq!(alarm.stop(2000), Ok(0));
+
+        assert_eq!(
+            kernel.syscalls(),
+            vec![
+                SyscallRecord::Command {
+                    major: DRIVER_NUM,
+                    minor: command_num::TICK,
+                    arg1: 0,
+                    arg2: 0,
+                },
+                SyscallRecord::Command {
+                    major: DRIVER_NUM,
+                    minor: command_num::START,
+                    arg1: 2000,
+                    arg2: 0,
+                },
+                SyscallRecord::Command {
+                    major: DRIVER_NUM,
+                    minor: command_num::STOP,
+                    arg1: 2000,
+                    arg2: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn command_errors_are_returned() {
+        let kernel = Kernel::new();
+        kernel.push_result(
+            SyscallClass::Command,
+            DRIVER_NUM,
+            command_num::PRESENT,
+            Err(Error::ENODEVICE),
+        );
+
+        let alarm = AlarmClient::new();
+
+        assert_eq!(alarm.is_present(), Err(Error::ENODEVICE));
+        assert_eq!(alarm.is_present(), Ok(0));
+    }
+
+    #[test]
+    fn frequency_is_cached() {
+        let kernel = Kernel::new();
+        kernel.set_result(
+            SyscallClass::Command,
+            DRIVER_NUM,
+            command_num::CLOCK_FREQUENCY,
+            Ok(32768),
+        );
+
+        let alarm = AlarmClient::new();
+
+        assert_eq!(alarm.duration_from_ms(2000).map(|d| d.ticks()), Ok(65536));
+        assert_eq!(alarm.duration_from_us(100).map(|d| d.ticks()), Ok(4));
+        assert_eq!(alarm.duration_from_ms(u32::max_value()), Err(Error::EINVAL));
+        assert_eq!(alarm.frequency().map(|f| f.hz()), Ok(32768));
+
+        assert_eq!(kernel.syscalls().len(), 1);
+    }
+
+    #[test]
+    fn zero_frequency_is_an_error() {
+        let _kernel = Kernel::new();
+
+        assert_eq!(AlarmClient::new().frequency(), Err(Error::FAIL));
+    }
+
+    fn start(tic: usize) -> SyscallRecord {
+        SyscallRecord::Command {
+            major: DRIVER_NUM,
+            minor: command_num::START,
+            arg1: tic,
+            arg2: 0,
+        }
+    }
+
+    fn stop(tic: usize) -> SyscallRecord {
+        SyscallRecord::Command {
+            major: DRIVER_NUM,
+            minor: command_num::STOP,
+            arg1: tic,
+            arg2: 0,
+        }
+    }
+
+    fn commands(kernel: &Kernel) -> Vec<SyscallRecord> {
+        kernel
+            .syscalls()
+            .into_iter()
+            .filter(|s| match s {
+                SyscallRecord::Command { minor, .. } => *minor != command_num::TICK,
+                _ => false,
+            })
+            .collect()
+    }
+
+    fn fire(kernel: &Kernel, alarm: &Alarm, now: usize) {
+        let mut alarm_task = alarm.get_task();
+
+        kernel.set_result(
+            SyscallClass::Command,
+            DRIVER_NUM,
+            command_num::TICK,
+            Ok(now),
+        );
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, now, now, 0);
+        yieldk();
+        Pin::new(&mut alarm_task).resume();
+    }
+
+    #[test]
+    fn virtual_alarms_share_hardware_alarm() {
+        let kernel = Kernel::new();
+        let alarm = Alarm::new();
+        let alarm_client = AlarmClient::new();
+
+        let slow = alarm_client.oneshot(Duration::from_ticks(300)).unwrap();
+        let fast = alarm_client.oneshot(Duration::from_ticks(100)).unwrap();
+        assert!(kernel.is_subscribed(DRIVER_NUM, subscribe_num::CALLBACK));
+        assert_eq!(commands(&kernel), vec![start(300), start(100)]);
+
+        kernel.clear_syscalls();
+        fire(&kernel, &alarm, 100);
+
+        assert!(fast.has_message());
+        assert!(!fast.is_armed());
+        assert!(!slow.has_message());
+        assert_eq!(commands(&kernel), vec![start(300)]);
+
+        let data = fast.reap_get_data().unwrap();
+        assert_eq!(data.get_now(), 100);
+        assert_eq!(data.get_expiration(), 100);
+        assert_eq!(fast.reap_get_data().err(), Some(Error::EINVAL));
+
+        kernel.clear_syscalls();
+        fire(&kernel, &alarm, 300);
+
+        assert!(slow.has_message());
+        assert_eq!(commands(&kernel), vec![]);
+    }
+
+    #[test]
+    fn cancel_reprograms_hardware_alarm() {
+        let kernel = Kernel::new();
+        let alarm_client = AlarmClient::new();
+
+        let a = alarm_client.oneshot(Duration::from_ticks(100)).unwrap();
+        let b = alarm_client.oneshot(Duration::from_ticks(200)).unwrap();
+
+        kernel.clear_syscalls();
+        a.cancel().unwrap();
+        assert!(!a.is_armed());
+        assert_eq!(commands(&kernel), vec![start(200)]);
+
+        kernel.clear_syscalls();
+        drop(b);
+        assert_eq!(commands(&kernel), vec![stop(200)]);
+    }
+
+    #[test]
+    fn periodic_alarm_rearms() {
+        let kernel = Kernel::new();
+        let alarm = Alarm::new();
+        let alarm_client = AlarmClient::new();
+
+        assert_eq!(
+            alarm_client.periodic(Duration::from_ticks(0)).err(),
+            Some(Error::EINVAL)
+        );
+
+        let tick = alarm_client.periodic(Duration::from_ticks(50)).unwrap();
+
+        fire(&kernel, &alarm, 50);
+        fire(&kernel, &alarm, 100);
+
+        assert_eq!(tick.reap_get_data().unwrap().get_expiration(), 50);
+        assert_eq!(tick.reap_get_data().unwrap().get_expiration(), 100);
+        assert!(tick.is_armed());
+        assert_eq!(commands(&kernel).last(), Some(&start(150)));
+    }
+
+    #[test]
+    fn expired_while_programming() {
+        let kernel = Kernel::new();
+        let alarm_client = AlarmClient::new();
+
+        // The counter has moved past the deadline by the time the hardware
+        // alarm is programmed
+        kernel.push_result(SyscallClass::Command, DRIVER_NUM, command_num::TICK, Ok(0));
+        kernel.set_result(SyscallClass::Command, DRIVER_NUM, command_num::TICK, Ok(10));
+
+        let a = alarm_client.oneshot(Duration::from_ticks(5)).unwrap();
+
+        assert!(a.has_message());
+        assert_eq!(commands(&kernel), vec![start(5), stop(5)]);
+    }
+
+    #[test]
+    fn virtual_alarms_are_bounded() {
+        let _kernel = Kernel::new();
+        let alarm_client = AlarmClient::new();
+
+        let alarms: Vec<VirtualAlarm> = (0..MAX_VIRTUAL_ALARMS)
+            .map(|i| {
+                alarm_client
+                    .oneshot(Duration::from_ticks(i as u32 + 1))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            alarm_client.oneshot(Duration::from_ticks(1)).err(),
+            Some(Error::ENOMEM)
+        );
+
+        drop(alarms);
+        assert!(alarm_client.oneshot(Duration::from_ticks(1)).is_ok());
+    }
+
+    #[test]
+    fn internal_alarms_are_not_seen_by_any_alarm_client() {
+        let kernel = Kernel::new();
+        let alarm = Alarm::new();
+        let alarm_client = AlarmClient::new();
+
+        let internal = alarm_client
+            .internal_oneshot(Duration::from_ticks(100))
+            .unwrap();
+        let user = alarm_client.oneshot(Duration::from_ticks(100)).unwrap();
+        fire(&kernel, &alarm, 100);
+
+        assert!(alarm_client.has_message());
+        alarm_client.reap_message();
+        assert!(!alarm_client.has_message());
+        assert_eq!(alarm_client.reap_get_data().err(), Some(Error::EINVAL));
+        assert!(!user.has_message());
+        assert!(internal.has_message());
+
+        // The slot is an ordinary alarm again once it has been released
+        drop(internal);
+        drop(user);
+        let reused = alarm_client.oneshot(Duration::from_ticks(0)).unwrap();
+        assert!(reused.has_message());
+        assert!(alarm_client.has_message());
+    }
+
+    #[test]
+    fn deadline_alarm_follows_the_next_deadline() {
+        let kernel = Kernel::new();
+        kernel.set_result(
+            SyscallClass::Command,
+            DRIVER_NUM,
+            command_num::TICK,
+            Ok(100),
+        );
+
+        let mut deadlines = DeadlineAlarm::new();
+        let mut calls = 0;
+
+        // A deadline that has been reached is expired again right away
+        deadlines
+            .update(|now| {
+                calls += 1;
+                if calls == 1 {
+                    Some(now)
+                } else {
+                    Some(now.wrapping_add(Duration::from_ticks(50)))
+                }
+            })
+            .unwrap();
+        assert_eq!(calls, 2);
+        assert!(!deadlines.is_due());
+        assert_eq!(commands(&kernel).last(), Some(&start(150)));
+
+        // Internal, so not seen by other clients
+        assert!(!AlarmClient::new().has_message());
+
+        deadlines.update(|_| None).unwrap();
+        assert_eq!(commands(&kernel).last(), Some(&stop(150)));
+    }
 }
@@ -1,11 +1,22 @@
 use core::ops::Generator;
 
-use crate::result::{Error, Result};
+use crate::alarm::AlarmClient;
+use crate::executor::Source;
+use crate::futures::wait_for;
+use crate::gesture::GestureClient;
+use crate::queue::{OverflowPolicy, Queue};
+use crate::result::{library_error, Error, Result};
 use crate::syscalls::{command, subscribe, CallbackMessage};
 use crate::task::{DriverTask, DriverTaskClient};
+use crate::time::Instant;
+use crate::timeout::Cancellable;
 
 const DRIVER_NUM: usize = 3;
 
+// Buttons with a higher number have no queue of their own, see
+// `ButtonClient::button`
+pub const MAX_BUTTONS: usize = 8;
+
 mod subscribe_num {
     pub const CALLBACK: usize = 0;
 }
@@ -14,25 +25,75 @@ mod command_num {
     pub const NUM_BUTTONS: usize = 0;
     pub const ENABLE_INTERRUPT: usize = 1;
     pub const DISABLE_INTERRUPT: usize = 2;
-    pub const CURRENT_STATE: usize = 2;
+    pub const READ: usize = 3;
 }
 
-static mut BUTTON_MESSAGE: Option<CallbackMessage> = None;
+// Callback message with the time of the upcall, if an event client or the
+// gesture recognizer needs it and the clock could be read
+#[derive(Copy, Clone)]
+struct ButtonUpcall {
+    message: CallbackMessage,
+    timestamp: Option<Instant>,
+}
+
+static mut BUTTON_MESSAGE: Queue<ButtonUpcall> = Queue::new();
 
 #[derive(Copy, Clone)]
 pub enum ButtonClientMessage {
     Event(ButtonEventData),
 }
 
-static mut BUTTON_CLIENT_PRESSED_MESSAGE: Option<ButtonClientMessage> = None;
+static mut BUTTON_CLIENT_PRESSED_MESSAGE: Queue<ButtonClientMessage> = Queue::new();
+
+static mut BUTTON_CLIENT_NOT_PRESSED_MESSAGE: Queue<ButtonClientMessage> = Queue::new();
+
+// Events of all buttons, in the order they were received
+static mut BUTTON_EVENT_MESSAGE: Queue<ButtonEvent> = Queue::new();
+
+// Set by `ButtonClient::events`. Events are not queued before, so that they do
+// not pile up when no one reads them.
+static mut BUTTON_EVENT_ENABLED: bool = false;
+
+// Events of a single button, one queue per button
+static mut SINGLE_BUTTON_MESSAGE: [Queue<ButtonEvent>; MAX_BUTTONS] = [
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+    Queue::new(),
+];
+
+// Set by `ButtonClient::button`, per button
+static mut SINGLE_BUTTON_ENABLED: [bool; MAX_BUTTONS] = [false; MAX_BUTTONS];
+
+// Bit `n` is set while button `n` is pressed
+static mut BUTTON_PRESSED_MASK: u32 = 0;
 
-static mut BUTTON_CLIENT_NOT_PRESSED_MESSAGE: Option<ButtonClientMessage> = None;
+// Timestamped events for the gesture recognizer, queued while gestures are
+// enabled, see `gesture::GestureClient::poll`. These are not client messages.
+static mut GESTURE_INPUT: Queue<ButtonEvent> = Queue::new();
+
+static mut GESTURE_INPUT_ENABLED: bool = false;
 
 extern "C" fn button_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
     let cb_message = CallbackMessage::new(arg0, arg1, arg2, userdata);
 
+    // Read here rather than in the task, so that edges that are queued while
+    // the app is busy keep their own time
+    let timestamp = if unsafe { is_timed(arg0) } {
+        AlarmClient::new().now().ok()
+    } else {
+        None
+    };
+
     unsafe {
-        BUTTON_MESSAGE = Some(cb_message);
+        let _ = BUTTON_MESSAGE.push(ButtonUpcall {
+            message: cb_message,
+            timestamp,
+        });
     }
 }
 
@@ -56,39 +117,163 @@ impl ButtonEventData {
     }
 }
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum ButtonState {
     NotPressed,
     Pressed,
 }
 
-pub struct Button;
+// `timestamp` is the time of the upcall that reported the event, `None` if the
+// alarm driver is not available.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ButtonEvent {
+    pub button: usize,
+    pub state: ButtonState,
+    pub timestamp: Option<Instant>,
+}
+
+// Snapshot of the state of the first 32 buttons, as last reported by the
+// driver. Buttons start out as not pressed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ButtonStates(u32);
+
+impl ButtonStates {
+    // Bit `n` is set while button `n` is pressed
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn is_pressed(&self, button_num: usize) -> bool {
+        button_num < 32 && self.0 & (1 << button_num) != 0
+    }
+
+    pub fn any_pressed(&self) -> bool {
+        self.0 != 0
+    }
+}
+
+// Events of `button_num` are queued for a client that needs their time
+unsafe fn is_timed(button_num: usize) -> bool {
+    BUTTON_EVENT_ENABLED
+        || SINGLE_BUTTON_ENABLED.get(button_num) == Some(&true)
+        || GESTURE_INPUT_ENABLED
+}
+
+// Records `event` in the pressed mask and queues it for the event clients that
+// have been created
+unsafe fn dispatch_event(event: ButtonEvent) {
+    if event.button < 32 {
+        let bit = 1 << event.button;
+
+        if event.state == ButtonState::Pressed {
+            BUTTON_PRESSED_MASK |= bit;
+        } else {
+            BUTTON_PRESSED_MASK &= !bit;
+        }
+    }
+
+    if BUTTON_EVENT_ENABLED {
+        let _ = BUTTON_EVENT_MESSAGE.push(event);
+    }
+
+    if SINGLE_BUTTON_ENABLED.get(event.button) == Some(&true) {
+        let _ = SINGLE_BUTTON_MESSAGE[event.button].push(event);
+    }
+}
+
+pub struct Button(());
 
 impl Button {
-    pub fn new() -> Button {
-        Button
+    pub(crate) fn new() -> Button {
+        Button(())
     }
 
-    // Safety : This coroutine is called whenever there is an incoming callback
-    //          message. When called, it *must* consume the incoming callback
-    //          message before yielding.
-    pub unsafe fn get_task(&self) -> impl Generator<Yield = (), Return = ()> + '_ {
+    // This coroutine is resumed whenever there are incoming callback messages.
+    // When resumed, it consumes all incoming callback messages before yielding.
+    // `Drivers::take` hands out a single `Button`, so there is only ever one
+    // such coroutine.
+    pub fn get_task(&self) -> impl Generator<Yield = (), Return = ()> + '_ {
         || loop {
-            if let Some(cb_message) = BUTTON_MESSAGE.take() {
-                let button_num = cb_message.get_arg0();
-                if cb_message.get_arg1() == 0 {
-                    BUTTON_CLIENT_NOT_PRESSED_MESSAGE = Some(ButtonClientMessage::Event(
-                        ButtonEventData::new(button_num, ButtonState::NotPressed),
-                    ));
-                } else {
-                    BUTTON_CLIENT_PRESSED_MESSAGE = Some(ButtonClientMessage::Event(
-                        ButtonEventData::new(button_num, ButtonState::Pressed),
-                    ));
-                };
+            unsafe {
+                while let Some(upcall) = BUTTON_MESSAGE.pop() {
+                    let cb_message = upcall.message;
+                    let button_num = cb_message.get_arg0();
+                    let (state, queue) = if cb_message.get_arg1() == 0 {
+                        (
+                            ButtonState::NotPressed,
+                            &mut BUTTON_CLIENT_NOT_PRESSED_MESSAGE,
+                        )
+                    } else {
+                        (ButtonState::Pressed, &mut BUTTON_CLIENT_PRESSED_MESSAGE)
+                    };
+
+                    let data = ButtonEventData::new(button_num, state);
+                    let _ = queue.push(ButtonClientMessage::Event(data));
+
+                    let event = ButtonEvent {
+                        button: button_num,
+                        state,
+                        timestamp: upcall.timestamp,
+                    };
+                    dispatch_event(event);
+
+                    // Without a clock gestures can not be timed
+                    if GESTURE_INPUT_ENABLED && event.timestamp.is_some() {
+                        let _ = GESTURE_INPUT.push(event);
+                    }
+                }
             }
+
             yield;
         }
     }
+}
+
+impl DriverTask for Button {
+    fn has_message(&self) -> bool {
+        unsafe { !BUTTON_MESSAGE.is_empty() }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { BUTTON_MESSAGE.dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { BUTTON_MESSAGE.configure(capacity, policy) }
+    }
+}
+
+// Number of a button that the driver has, see `ButtonClient::index`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ButtonIndex(usize);
+
+impl ButtonIndex {
+    pub fn num(&self) -> usize {
+        self.0
+    }
+}
+
+pub struct ButtonClient(());
+
+// `Err(Error::ENOMEM)` reports button events that were dropped under the
+// `OverflowPolicy::Error` policy. The queued events are still available.
+fn reap_data(queue: &mut Queue<ButtonClientMessage>) -> Result<ButtonEventData> {
+    if queue.take_overflow() {
+        return Err(library_error(Error::ENOMEM));
+    }
+
+    queue
+        .pop()
+        .ok_or_else(|| library_error(Error::EINVAL))
+        .map(|x| match x {
+            ButtonClientMessage::Event(d) => d,
+        })
+}
+
+impl ButtonClient {
+    pub(crate) fn new() -> ButtonClient {
+        ButtonClient(())
+    }
 
     pub fn initiate(&self) -> Result<()> {
         unsafe {
@@ -106,17 +291,44 @@ impl Button {
         unsafe { command(DRIVER_NUM, command_num::NUM_BUTTONS, 0, 0) }
     }
 
-    pub fn enable_button_interrupt(&self, button_num: usize) -> Result<()> {
-        unsafe { command(DRIVER_NUM, command_num::ENABLE_INTERRUPT, button_num, 0).map(|_| ()) }
+    // `Err(Error::EINVAL)` if the driver has no button `button_num`
+    pub fn index(&self, button_num: usize) -> Result<ButtonIndex> {
+        if button_num < self.get_num_buttons()? {
+            Ok(ButtonIndex(button_num))
+        } else {
+            Err(library_error(Error::EINVAL))
+        }
+    }
+
+    // Every button of the driver
+    pub fn indices(&self) -> Result<impl Iterator<Item = ButtonIndex>> {
+        self.get_num_buttons().map(|n| (0..n).map(ButtonIndex))
     }
 
-    pub fn disable_button_interrupt(&self, button_num: usize) -> Result<()> {
-        unsafe { command(DRIVER_NUM, command_num::DISABLE_INTERRUPT, button_num, 0).map(|_| ()) }
+    pub fn enable_button_interrupt(&self, button: ButtonIndex) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::ENABLE_INTERRUPT, button.0, 0).map(|_| ()) }
     }
 
-    pub fn get_button_state(&self, button_num: usize) -> Result<ButtonState> {
+    pub fn disable_button_interrupt(&self, button: ButtonIndex) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::DISABLE_INTERRUPT, button.0, 0).map(|_| ()) }
+    }
+
+    // Stops at the first button that fails
+    pub fn enable_all_interrupts(&self) -> Result<()> {
+        self.indices()?
+            .map(|button| self.enable_button_interrupt(button))
+            .collect()
+    }
+
+    pub fn disable_all_interrupts(&self) -> Result<()> {
+        self.indices()?
+            .map(|button| self.disable_button_interrupt(button))
+            .collect()
+    }
+
+    pub fn get_button_state(&self, button: ButtonIndex) -> Result<ButtonState> {
         unsafe {
-            command(DRIVER_NUM, command_num::CURRENT_STATE, button_num, 0).map(|r| {
+            command(DRIVER_NUM, command_num::READ, button.0, 0).map(|r| {
                 if r == 0 {
                     ButtonState::NotPressed
                 } else {
@@ -125,90 +337,718 @@ impl Button {
             })
         }
     }
-}
 
-impl DriverTask for Button {
-    fn has_message(&self) -> bool {
-        unsafe { BUTTON_MESSAGE.is_some() }
+    // Reads the state of the first 32 buttons from the driver. Unlike
+    // `states`, this also covers buttons without interrupts.
+    pub fn read_states(&self) -> Result<ButtonStates> {
+        let mut bits = 0;
+
+        for button in self.indices()?.take(32) {
+            if self.get_button_state(button)? == ButtonState::Pressed {
+                bits |= 1 << button.0;
+            }
+        }
+
+        Ok(ButtonStates(bits))
     }
-}
 
-pub struct ButtonClient;
+    // Interrupts for the buttons of interest need to be enabled by the caller.
+    pub async fn wait_pressed(&self) -> Result<ButtonEventData> {
+        self.initiate()?;
 
-impl ButtonClient {
-    pub fn new() -> ButtonClient {
-        ButtonClient
+        wait_for(Source::Button, || ButtonClient::new().has_pressed_message()).await;
+
+        self.reap_get_pressed_data()
+    }
+
+    // Wait for a button press that can be cancelled, see
+    // `timeout::with_timeout`. Interrupts for the buttons of interest need to
+    // be enabled by the caller.
+    pub fn wait_pressed_op(&self) -> WaitPressedOp {
+        WaitPressedOp(())
+    }
+
+    // Gestures recognized from the button events, see `gesture`
+    pub fn gestures(&self) -> GestureClient {
+        GestureClient::new()
+    }
+
+    // Events of all buttons, in the order they were received. Only events
+    // received after the first call are queued.
+    pub fn events(&self) -> ButtonEventClient {
+        unsafe {
+            BUTTON_EVENT_ENABLED = true;
+        }
+
+        ButtonEventClient(())
+    }
+
+    // Events of `button` only. `Err(Error::EINVAL)` if its number is not below
+    // `MAX_BUTTONS`. Only events received after the first call for the button
+    // are queued.
+    pub fn button(&self, button: ButtonIndex) -> Result<SingleButtonClient> {
+        if button.0 < MAX_BUTTONS {
+            unsafe {
+                SINGLE_BUTTON_ENABLED[button.0] = true;
+            }
+
+            Ok(SingleButtonClient { button })
+        } else {
+            Err(library_error(Error::EINVAL))
+        }
+    }
+
+    pub fn states(&self) -> ButtonStates {
+        unsafe { ButtonStates(BUTTON_PRESSED_MASK) }
+    }
+
+    // `DriverTaskClient` that only sees pressed messages
+    pub fn pressed(&self) -> ButtonPressedClient {
+        ButtonPressedClient(())
     }
 
     pub fn has_pressed_message(&self) -> bool {
-        unsafe { BUTTON_CLIENT_PRESSED_MESSAGE.is_some() }
+        unsafe { !BUTTON_CLIENT_PRESSED_MESSAGE.is_empty() }
     }
 
     pub fn has_not_pressed_message(&self) -> bool {
-        unsafe { BUTTON_CLIENT_NOT_PRESSED_MESSAGE.is_some() }
+        unsafe { !BUTTON_CLIENT_NOT_PRESSED_MESSAGE.is_empty() }
     }
 
     pub fn reap_pressed_message(&self) -> Result<()> {
+        self.reap_get_pressed_data().map(|_| ())
+    }
+
+    pub fn reap_not_pressed_message(&self) -> Result<()> {
+        self.reap_get_not_pressed_data().map(|_| ())
+    }
+
+    pub fn reap_get_pressed_data(&self) -> Result<ButtonEventData> {
+        unsafe { reap_data(&mut BUTTON_CLIENT_PRESSED_MESSAGE) }
+    }
+
+    pub fn reap_get_not_pressed_data(&self) -> Result<ButtonEventData> {
+        unsafe { reap_data(&mut BUTTON_CLIENT_NOT_PRESSED_MESSAGE) }
+    }
+}
+
+impl DriverTaskClient for ButtonClient {
+    fn has_message(&self) -> bool {
+        self.has_pressed_message() || self.has_not_pressed_message()
+    }
+
+    // Reaps the oldest pressed *and* the oldest not pressed message
+    fn reap_message(&self) {
         unsafe {
-            let b = BUTTON_CLIENT_PRESSED_MESSAGE.clone();
-            b.ok_or(Error::EINVAL).map(|_| {
-                BUTTON_CLIENT_PRESSED_MESSAGE = None;
-                ()
-            })
+            BUTTON_CLIENT_PRESSED_MESSAGE.pop();
+            BUTTON_CLIENT_NOT_PRESSED_MESSAGE.pop();
         }
     }
 
-    pub fn reap_not_pressed_message(&self) -> Result<()> {
+    fn dropped_messages(&self) -> usize {
         unsafe {
-            let b = BUTTON_CLIENT_NOT_PRESSED_MESSAGE.clone();
-            b.ok_or(Error::EINVAL).map(|_| {
-                BUTTON_CLIENT_NOT_PRESSED_MESSAGE = None;
-                ()
-            })
+            BUTTON_CLIENT_PRESSED_MESSAGE.dropped() + BUTTON_CLIENT_NOT_PRESSED_MESSAGE.dropped()
         }
     }
 
-    pub fn reap_get_pressed_data(&self) -> Result<ButtonEventData> {
+    // Applies to both the pressed and the not pressed queue
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
         unsafe {
-            let b = BUTTON_CLIENT_PRESSED_MESSAGE.clone();
-            b.ok_or(Error::EINVAL).map(|x| match x {
-                ButtonClientMessage::Event(d) => {
-                    BUTTON_CLIENT_PRESSED_MESSAGE = None;
-                    d
-                }
-            })
+            BUTTON_CLIENT_PRESSED_MESSAGE
+                .configure(capacity, policy)
+                .and_then(|_| BUTTON_CLIENT_NOT_PRESSED_MESSAGE.configure(capacity, policy))
         }
     }
+}
 
-    pub fn reap_get_not_pressed_data(&self) -> Result<ButtonEventData> {
+// Created by `ButtonClient::pressed`
+pub struct ButtonPressedClient(());
+
+impl DriverTaskClient for ButtonPressedClient {
+    fn has_message(&self) -> bool {
+        ButtonClient::new().has_pressed_message()
+    }
+
+    fn reap_message(&self) {
         unsafe {
-            let b = BUTTON_CLIENT_NOT_PRESSED_MESSAGE.clone();
-            b.ok_or(Error::EINVAL).map(|x| match x {
-                ButtonClientMessage::Event(d) => {
-                    BUTTON_CLIENT_NOT_PRESSED_MESSAGE = None;
-                    d
-                }
+            BUTTON_CLIENT_PRESSED_MESSAGE.pop();
+        }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { BUTTON_CLIENT_PRESSED_MESSAGE.dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { BUTTON_CLIENT_PRESSED_MESSAGE.configure(capacity, policy) }
+    }
+}
+
+// `Err(Error::ENOMEM)` reports events that were dropped under the
+// `OverflowPolicy::Error` policy. The queued events are still available.
+fn reap_event(queue: &mut Queue<ButtonEvent>) -> Result<ButtonEvent> {
+    if queue.take_overflow() {
+        return Err(library_error(Error::ENOMEM));
+    }
+
+    queue.pop().ok_or_else(|| library_error(Error::EINVAL))
+}
+
+// Created by `ButtonClient::events`, which starts queueing the events
+pub struct ButtonEventClient(());
+
+impl ButtonEventClient {
+    pub(crate) fn new() -> ButtonEventClient {
+        ButtonEventClient(())
+    }
+
+    // Interrupts for the buttons of interest need to be enabled by the caller.
+    pub async fn wait_event(&self) -> Result<ButtonEvent> {
+        ButtonClient::new().initiate()?;
+
+        wait_for(Source::Button, || ButtonEventClient::new().has_message()).await;
+
+        self.reap_get_event()
+    }
+
+    pub fn reap_get_event(&self) -> Result<ButtonEvent> {
+        unsafe { reap_event(&mut BUTTON_EVENT_MESSAGE) }
+    }
+}
+
+impl DriverTaskClient for ButtonEventClient {
+    fn has_message(&self) -> bool {
+        unsafe { !BUTTON_EVENT_MESSAGE.is_empty() }
+    }
+
+    fn reap_message(&self) {
+        unsafe {
+            BUTTON_EVENT_MESSAGE.pop();
+        }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { BUTTON_EVENT_MESSAGE.dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { BUTTON_EVENT_MESSAGE.configure(capacity, policy) }
+    }
+}
+
+// Events of a single button, created by `ButtonClient::button`. Every button
+// has a queue of its own, so events of other buttons are never lost while
+// waiting for this one.
+pub struct SingleButtonClient {
+    button: ButtonIndex,
+}
+
+impl SingleButtonClient {
+    pub fn index(&self) -> ButtonIndex {
+        self.button
+    }
+
+    // Waits for the next press of the button. Releases before it are
+    // discarded. Interrupts for the button need to be enabled by the caller.
+    pub async fn wait_pressed(&self) -> Result<ButtonEvent> {
+        self.wait_state(ButtonState::Pressed).await
+    }
+
+    pub async fn wait_released(&self) -> Result<ButtonEvent> {
+        self.wait_state(ButtonState::NotPressed).await
+    }
+
+    async fn wait_state(&self, state: ButtonState) -> Result<ButtonEvent> {
+        ButtonClient::new().initiate()?;
+
+        let num = self.button.0;
+
+        loop {
+            wait_for(Source::Button, move || unsafe {
+                !SINGLE_BUTTON_MESSAGE[num].is_empty()
             })
+            .await;
+
+            let event = self.reap_get_event()?;
+            if event.state == state {
+                return Ok(event);
+            }
         }
     }
+
+    pub fn reap_get_event(&self) -> Result<ButtonEvent> {
+        unsafe { reap_event(&mut SINGLE_BUTTON_MESSAGE[self.button.0]) }
+    }
 }
 
-impl DriverTaskClient for ButtonClient {
+impl DriverTaskClient for SingleButtonClient {
     fn has_message(&self) -> bool {
-        self.has_pressed_message() || self.has_not_pressed_message()
+        unsafe { !SINGLE_BUTTON_MESSAGE[self.button.0].is_empty() }
+    }
+
+    fn reap_message(&self) {
+        unsafe {
+            SINGLE_BUTTON_MESSAGE[self.button.0].pop();
+        }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { SINGLE_BUTTON_MESSAGE[self.button.0].dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { SINGLE_BUTTON_MESSAGE[self.button.0].configure(capacity, policy) }
+    }
+}
+
+// Any single button queue, see `has_client_messages`
+pub(crate) struct AnySingleButtonClient;
+
+impl DriverTaskClient for AnySingleButtonClient {
+    fn has_message(&self) -> bool {
+        unsafe { SINGLE_BUTTON_MESSAGE.iter().any(|q| !q.is_empty()) }
     }
 
+    // Reaps the oldest message of every single button queue
     fn reap_message(&self) {
         unsafe {
-            let b = BUTTON_CLIENT_PRESSED_MESSAGE.clone();
-            b.map(|_| {
-                BUTTON_CLIENT_PRESSED_MESSAGE = None;
-            });
+            for queue in SINGLE_BUTTON_MESSAGE.iter_mut() {
+                queue.pop();
+            }
+        }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { SINGLE_BUTTON_MESSAGE.iter().map(|q| q.dropped()).sum() }
+    }
+
+    // Applies to the queue of every button
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe {
+            SINGLE_BUTTON_MESSAGE
+                .iter_mut()
+                .map(|q| q.configure(capacity, policy))
+                .fold(Ok(()), |res, r| res.and(r))
+        }
+    }
+}
+
+// Starts or stops queueing events for the gesture recognizer. Queued events
+// are discarded either way.
+pub(crate) unsafe fn set_gesture_input(enabled: bool) {
+    GESTURE_INPUT_ENABLED = enabled;
+    GESTURE_INPUT.clear();
+}
+
+pub(crate) unsafe fn has_gesture_input() -> bool {
+    !GESTURE_INPUT.is_empty()
+}
+
+pub(crate) unsafe fn pop_gesture_input() -> Option<ButtonEvent> {
+    GESTURE_INPUT.pop()
+}
+
+pub struct WaitPressedOp(());
+
+impl Cancellable for WaitPressedOp {
+    type Output = ButtonEventData;
+
+    fn start(&mut self) -> Result<()> {
+        ButtonClient::new().initiate()
+    }
+
+    fn is_complete(&self) -> bool {
+        ButtonClient::new().has_pressed_message()
+    }
+
+    fn complete(&mut self) -> Result<ButtonEventData> {
+        ButtonClient::new().reap_get_pressed_data()
+    }
+
+    // Nothing to abort, the driver keeps reporting button presses
+    fn abort(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn discard(&mut self) {}
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    BUTTON_MESSAGE = Queue::new();
+    BUTTON_CLIENT_PRESSED_MESSAGE = Queue::new();
+    BUTTON_CLIENT_NOT_PRESSED_MESSAGE = Queue::new();
+    BUTTON_EVENT_MESSAGE = Queue::new();
+    BUTTON_EVENT_ENABLED = false;
+    SINGLE_BUTTON_MESSAGE = [
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+        Queue::new(),
+    ];
+    SINGLE_BUTTON_ENABLED = [false; MAX_BUTTONS];
+    BUTTON_PRESSED_MASK = 0;
+    GESTURE_INPUT = Queue::new();
+    GESTURE_INPUT_ENABLED = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::pin::Pin;
+    use std::vec;
+
+    use crate::syscalls::fake::{Kernel, SyscallClass, SyscallRecord};
+    use crate::syscalls::yieldk;
+
+    fn command_record(minor: usize, arg1: usize) -> SyscallRecord {
+        SyscallRecord::Command {
+            major: 3,
+            minor,
+            arg1,
+            arg2: 0,
+        }
+    }
+
+    #[test]
+    fn num_buttons_and_enable_interrupt() {
+        let kernel = Kernel::new();
+        kernel.set_result(SyscallClass::Command, 3, 0, Ok(4));
+
+        let button = ButtonClient::new();
+
+        assert_eq!(button.get_num_buttons(), Ok(4));
+        let index = button.index(3).unwrap();
+        assert_eq!(button.enable_button_interrupt(index), Ok(()));
+        assert_eq!(button.disable_button_interrupt(index), Ok(()));
+
+        assert_eq!(
+            kernel.syscalls(),
+            vec![
+                command_record(0, 0),
+                command_record(0, 0),
+                command_record(1, 3),
+                command_record(2, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn indices_are_checked() {
+        let kernel = Kernel::new();
+        kernel.set_result(SyscallClass::Command, 3, 0, Ok(2));
+
+        let button = ButtonClient::new();
+
+        assert_eq!(button.index(1).map(|b| b.num()), Ok(1));
+        assert_eq!(button.index(2), Err(Error::EINVAL));
+    }
+
+    #[test]
+    fn state_is_read_with_command_3() {
+        let kernel = Kernel::new();
+        kernel.set_result(SyscallClass::Command, 3, 0, Ok(3));
+        kernel.push_result(SyscallClass::Command, 3, 3, Ok(0));
+        kernel.push_result(SyscallClass::Command, 3, 3, Ok(1));
+        kernel.push_result(SyscallClass::Command, 3, 3, Ok(1));
+
+        let button = ButtonClient::new();
+
+        assert_eq!(button.read_states().map(|s| s.bits()), Ok(0b110));
+        assert_eq!(
+            kernel.syscalls(),
+            vec![
+                command_record(0, 0),
+                command_record(3, 0),
+                command_record(3, 1),
+                command_record(3, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn all_interrupts() {
+        let kernel = Kernel::new();
+        kernel.set_result(SyscallClass::Command, 3, 0, Ok(2));
+
+        let button = ButtonClient::new();
+
+        assert_eq!(button.enable_all_interrupts(), Ok(()));
+        assert_eq!(button.disable_all_interrupts(), Ok(()));
+        assert_eq!(
+            kernel.syscalls(),
+            vec![
+                command_record(0, 0),
+                command_record(1, 0),
+                command_record(1, 1),
+                command_record(0, 0),
+                command_record(2, 0),
+                command_record(2, 1),
+            ]
+        );
+
+        // Stops at the first failure
+        kernel.push_result(SyscallClass::Command, 3, 1, Err(Error::FAIL));
+        assert_eq!(button.enable_all_interrupts(), Err(Error::FAIL));
+        assert_eq!(kernel.syscalls().len(), 8);
+    }
+
+    #[test]
+    fn upcalls_become_pressed_and_not_pressed_events() {
+        let kernel = Kernel::new();
+        let button = Button::new();
+        let button_client = ButtonClient::new();
+        let mut button_task = button.get_task();
+
+        button_client.initiate().unwrap();
+
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 1, 1, 0);
+        yieldk();
+        Pin::new(&mut button_task).resume();
+
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 2, 0, 0);
+        yieldk();
+        Pin::new(&mut button_task).resume();
+
+        assert!(button_client.has_pressed_message());
+        assert!(button_client.has_not_pressed_message());
+
+        let pressed = button_client.reap_get_pressed_data().unwrap();
+        assert_eq!(pressed.get_num(), 1);
+        assert!(pressed.get_state() == ButtonState::Pressed);
+
+        let not_pressed = button_client.reap_get_not_pressed_data().unwrap();
+        assert_eq!(not_pressed.get_num(), 2);
+        assert!(not_pressed.get_state() == ButtonState::NotPressed);
+
+        assert!(!button_client.has_message());
+    }
+
+    #[test]
+    fn reap_message_clears_both_slots() {
+        let kernel = Kernel::new();
+        let button = Button::new();
+        let button_client = ButtonClient::new();
+        let mut button_task = button.get_task();
+
+        button_client.initiate().unwrap();
+
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 0, 1, 0);
+        yieldk();
+        Pin::new(&mut button_task).resume();
+
+        assert!(button_client.has_message());
+        button_client.reap_message();
+        assert!(!button_client.has_message());
+        assert_eq!(button_client.reap_pressed_message(), Err(Error::EINVAL));
+    }
+
+    #[test]
+    fn presses_are_queued_not_overwritten() {
+        let kernel = Kernel::new();
+        let button = Button::new();
+        let button_client = ButtonClient::new();
+        let mut button_task = button.get_task();
+
+        button_client.initiate().unwrap();
+
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 0, 1, 0);
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 1, 1, 0);
+        yieldk();
+        yieldk();
+        Pin::new(&mut button_task).resume();
+
+        assert_eq!(button_client.reap_get_pressed_data().unwrap().get_num(), 0);
+        assert_eq!(button_client.reap_get_pressed_data().unwrap().get_num(), 1);
+        assert!(!button_client.has_message());
+    }
+
+    #[test]
+    fn overflow_is_counted_and_reported() {
+        let kernel = Kernel::new();
+        let button = Button::new();
+        let button_client = ButtonClient::new();
+        let mut button_task = button.get_task();
+
+        button_client
+            .pressed()
+            .configure_queue(1, OverflowPolicy::Error)
+            .unwrap();
+        button_client.initiate().unwrap();
+
+        for num in 0..3 {
+            kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, num, 1, 0);
+            yieldk();
+        }
+        Pin::new(&mut button_task).resume();
+
+        assert_eq!(button.dropped_messages(), 0);
+        assert_eq!(button_client.dropped_messages(), 2);
+
+        assert_eq!(button_client.reap_pressed_message(), Err(Error::ENOMEM));
+        assert_eq!(button_client.reap_get_pressed_data().unwrap().get_num(), 0);
+        assert_eq!(button_client.reap_pressed_message(), Err(Error::EINVAL));
+    }
+
+    #[test]
+    fn events_are_tracked_per_button() {
+        const ALARM: usize = 0;
+        const TICK: usize = 2;
+
+        let kernel = Kernel::new();
+        let button = Button::new();
+        let button_client = ButtonClient::new();
+        let mut button_task = button.get_task();
+
+        kernel.set_result(SyscallClass::Command, DRIVER_NUM, 0, Ok(3));
+        button_client.initiate().unwrap();
+        let button_0 = button_client
+            .button(button_client.index(0).unwrap())
+            .unwrap();
+        let button_2 = button_client
+            .button(button_client.index(2).unwrap())
+            .unwrap();
+        let events = button_client.events();
+
+        for (tick, num, pressed) in [(10, 2, 1), (11, 0, 1), (20, 2, 0)].iter() {
+            kernel.set_result(SyscallClass::Command, ALARM, TICK, Ok(*tick));
+            kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, *num, *pressed, 0);
+            yieldk();
+            Pin::new(&mut button_task).resume();
+        }
+
+        assert_eq!(button_client.states().bits(), 0b001);
+        assert!(button_client.states().is_pressed(0));
+        assert!(!button_client.states().is_pressed(2));
+
+        // Neither press is lost
+        assert_eq!(
+            button_2.reap_get_event().map(|e| e.state),
+            Ok(ButtonState::Pressed)
+        );
+        assert_eq!(
+            button_2.reap_get_event().map(|e| e.state),
+            Ok(ButtonState::NotPressed)
+        );
+        assert!(!button_2.has_message());
+        assert_eq!(
+            button_0.reap_get_event(),
+            Ok(ButtonEvent {
+                button: 0,
+                state: ButtonState::Pressed,
+                timestamp: Some(Instant::from_ticks(11)),
+            })
+        );
+
+        // The driver has more buttons than there are queues
+        kernel.set_result(SyscallClass::Command, DRIVER_NUM, 0, Ok(MAX_BUTTONS + 1));
+        let last = button_client.index(MAX_BUTTONS).unwrap();
+        assert_eq!(button_client.button(last).err(), Some(Error::EINVAL));
+
+        let mut order = vec![];
+        while let Ok(event) = events.reap_get_event() {
+            order.push((
+                event.button,
+                event.state,
+                event.timestamp.map(|t| t.ticks()),
+            ));
+        }
+        assert_eq!(
+            order,
+            vec![
+                (2, ButtonState::Pressed, Some(10)),
+                (0, ButtonState::Pressed, Some(11)),
+                (2, ButtonState::NotPressed, Some(20)),
+            ]
+        );
+    }
+
+    #[test]
+    fn events_are_only_queued_for_created_clients() {
+        let kernel = Kernel::new();
+        let button = Button::new();
+        let button_client = ButtonClient::new();
+        let mut button_task = button.get_task();
+
+        button_client.initiate().unwrap();
+
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 1, 1, 0);
+        yieldk();
+        Pin::new(&mut button_task).resume();
+        button_client.reap_message();
+
+        assert!(button_client.states().is_pressed(1));
+        assert!(!crate::has_client_messages());
+
+        kernel.set_result(SyscallClass::Command, DRIVER_NUM, 0, Ok(2));
+        let button_1 = button_client
+            .button(button_client.index(1).unwrap())
+            .unwrap();
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 1, 0, 0);
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 0, 1, 0);
+        yieldk();
+        yieldk();
+        Pin::new(&mut button_task).resume();
+
+        assert_eq!(
+            button_1.reap_get_event().map(|e| e.state),
+            Ok(ButtonState::NotPressed)
+        );
+        assert!(!button_1.has_message());
+        assert!(!button_client.events().has_message());
+        assert!(!AnySingleButtonClient.has_message());
+    }
+
+    #[test]
+    fn events_are_timed_by_their_upcall() {
+        const ALARM: usize = 0;
+        const TICK: usize = 2;
+
+        let kernel = Kernel::new();
+        let button = Button::new();
+        let button_client = ButtonClient::new();
+        let mut button_task = button.get_task();
+
+        kernel.set_result(SyscallClass::Command, DRIVER_NUM, 0, Ok(2));
+        button_client.initiate().unwrap();
+
+        // The clock is not read without a client for the time
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 1, 1, 0);
+        yieldk();
+        let tick = SyscallRecord::Command {
+            major: ALARM,
+            minor: TICK,
+            arg1: 0,
+            arg2: 0,
+        };
+        assert!(!kernel.syscalls().contains(&tick));
+
+        let button_0 = button_client
+            .button(button_client.index(0).unwrap())
+            .unwrap();
+
+        // A press and release that the task only gets to later
+        for &(now, pressed) in [(10, 1), (510, 0)].iter() {
+            kernel.push_result(SyscallClass::Command, ALARM, TICK, Ok(now));
+            kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 0, pressed, 0);
+            yieldk();
+        }
+
+        // Without a clock the events have no time
+        kernel.set_result(SyscallClass::Command, ALARM, TICK, Err(Error::ENODEVICE));
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::CALLBACK, 0, 1, 0);
+        yieldk();
+        Pin::new(&mut button_task).resume();
 
-            let b = BUTTON_CLIENT_NOT_PRESSED_MESSAGE.clone();
-            b.map(|_| {
-                BUTTON_CLIENT_NOT_PRESSED_MESSAGE = None;
-            });
+        let mut times = vec![];
+        while let Ok(event) = button_0.reap_get_event() {
+            times.push(event.timestamp.map(|t| t.ticks()));
         }
+        assert_eq!(times, vec![Some(10), Some(510), None]);
     }
 }
@@ -0,0 +1,569 @@
+use core::cmp;
+use core::fmt;
+
+use crate::console_read::ReceiveClient;
+use crate::console_write::{self, ConsoleWriteClient};
+use crate::executor::Source;
+use crate::futures::wait_for;
+use crate::result::{Error, Result};
+
+// Logical channels over the console, for example log output, a shell and a
+// binary data channel side by side. Data is sent and received in frames
+// tagged with a channel id:
+//
+//     FRAME_SYNC  channel  len  payload[len]  checksum
+//
+// The checksum is the wrapping sum of the channel, len and payload bytes. A
+// frame fits in the kernel write buffer, so it is always written whole.
+//
+// Writes are queued per channel and drained in the background by the
+// `ConsoleWrite` task, one frame per channel in turn. Like printed output,
+// they keep the console busy for `ConsoleWriteClient` writes until drained.
+// Printed output is not framed; frames and printed output take turns, and the
+// host side sees printed output as bytes outside of any frame.
+//
+// Received frames are routed to the queue of their channel. Receiving uses
+// the continuous receive of `ReceiveClient`, see `ChannelMux::start_receive`.
+//
+// `tools/channel_demux` splits the console output back into channels on the
+// host.
+pub const MAX_CHANNELS: usize = 4;
+
+pub const FRAME_SYNC: u8 = 0xa5;
+
+// Sync, channel, len and checksum
+pub const FRAME_OVERHEAD: usize = 4;
+
+pub const MAX_PAYLOAD: usize = 64 - FRAME_OVERHEAD;
+
+// Size of the send and receive queue of each channel
+pub const CHANNEL_BUF_SIZE: usize = 128;
+
+struct ByteQueue {
+    buf: [u8; CHANNEL_BUF_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl ByteQueue {
+    const fn new() -> ByteQueue {
+        ByteQueue {
+            buf: [0; CHANNEL_BUF_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn free(&self) -> usize {
+        CHANNEL_BUF_SIZE - self.len
+    }
+
+    // Appends as much of `bytes` as fits. Returns the number of bytes appended.
+    fn push(&mut self, bytes: &[u8]) -> usize {
+        let n = cmp::min(bytes.len(), self.free());
+
+        for (i, &b) in bytes[..n].iter().enumerate() {
+            self.buf[(self.head + self.len + i) % CHANNEL_BUF_SIZE] = b;
+        }
+        self.len += n;
+
+        n
+    }
+
+    fn pop_into(&mut self, out: &mut [u8]) -> usize {
+        let n = cmp::min(out.len(), self.len);
+
+        for b in out[..n].iter_mut() {
+            *b = self.buf[self.head];
+            self.head = (self.head + 1) % CHANNEL_BUF_SIZE;
+        }
+        self.len -= n;
+
+        n
+    }
+}
+
+struct ChannelQueues {
+    tx: ByteQueue,
+    rx: ByteQueue,
+}
+
+impl ChannelQueues {
+    const fn new() -> ChannelQueues {
+        ChannelQueues {
+            tx: ByteQueue::new(),
+            rx: ByteQueue::new(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Parse {
+    Sync,
+    Channel,
+    Len,
+    Payload,
+    Checksum,
+}
+
+// Frame being received
+struct FrameParser {
+    state: Parse,
+    channel: usize,
+    len: usize,
+    payload: [u8; MAX_PAYLOAD],
+    received: usize,
+    sum: u8,
+}
+
+impl FrameParser {
+    const fn new() -> FrameParser {
+        FrameParser {
+            state: Parse::Sync,
+            channel: 0,
+            len: 0,
+            payload: [0; MAX_PAYLOAD],
+            received: 0,
+            sum: 0,
+        }
+    }
+
+    // Returns `Some(Ok(channel))` for a complete frame, whose payload is in
+    // `payload[..len]`, and `Some(Err(..))` for a corrupt one.
+    fn feed(&mut self, byte: u8) -> Option<Result<usize>> {
+        match self.state {
+            Parse::Sync => {
+                if byte == FRAME_SYNC {
+                    self.state = Parse::Channel;
+                }
+            }
+            Parse::Channel => {
+                if byte as usize >= MAX_CHANNELS {
+                    return self.corrupt();
+                }
+
+                self.channel = byte as usize;
+                self.sum = byte;
+                self.state = Parse::Len;
+            }
+            Parse::Len => {
+                if byte as usize > MAX_PAYLOAD {
+                    return self.corrupt();
+                }
+
+                self.len = byte as usize;
+                self.received = 0;
+                self.sum = self.sum.wrapping_add(byte);
+                self.state = if self.len == 0 {
+                    Parse::Checksum
+                } else {
+                    Parse::Payload
+                };
+            }
+            Parse::Payload => {
+                self.payload[self.received] = byte;
+                self.received += 1;
+                self.sum = self.sum.wrapping_add(byte);
+
+                if self.received == self.len {
+                    self.state = Parse::Checksum;
+                }
+            }
+            Parse::Checksum => {
+                if byte != self.sum {
+                    return self.corrupt();
+                }
+
+                self.state = Parse::Sync;
+                return Some(Ok(self.channel));
+            }
+        }
+
+        None
+    }
+
+    fn corrupt(&mut self) -> Option<Result<usize>> {
+        self.state = Parse::Sync;
+        Some(Err(Error::FAIL))
+    }
+}
+
+static mut CHANNELS: [ChannelQueues; MAX_CHANNELS] = [
+    ChannelQueues::new(),
+    ChannelQueues::new(),
+    ChannelQueues::new(),
+    ChannelQueues::new(),
+];
+
+// Channel that sends the next frame
+static mut CHANNEL_NEXT: usize = 0;
+
+static mut CHANNEL_RECEIVING: bool = false;
+
+static mut CHANNEL_PARSER: FrameParser = FrameParser::new();
+
+// Frames lost because they could not be written, or because the receive
+// queue of their channel was full
+static mut CHANNEL_DROPPED: usize = 0;
+
+// Corrupt frames received
+static mut CHANNEL_CORRUPT: usize = 0;
+
+// Builds a frame for `channel` from the head of its send queue, in `frame`.
+// Returns the length of the frame.
+fn build_frame(channel: usize, tx: &mut ByteQueue, frame: &mut [u8; 64]) -> usize {
+    let len = tx.pop_into(&mut frame[3..3 + MAX_PAYLOAD]);
+
+    frame[0] = FRAME_SYNC;
+    frame[1] = channel as u8;
+    frame[2] = len as u8;
+    frame[3 + len] = frame[1..3 + len]
+        .iter()
+        .fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+    len + FRAME_OVERHEAD
+}
+
+// Hands the next frame to the console, unless there is an ongoing write.
+// Channels with queued data take turns.
+pub(crate) unsafe fn drain() {
+    while !ConsoleWriteClient::new().is_active() {
+        let channel = match (0..MAX_CHANNELS)
+            .map(|i| (CHANNEL_NEXT + i) % MAX_CHANNELS)
+            .find(|&c| CHANNELS[c].tx.len > 0)
+        {
+            Some(c) => c,
+            None => return,
+        };
+        CHANNEL_NEXT = (channel + 1) % MAX_CHANNELS;
+
+        let mut frame = [0; 64];
+        let len = build_frame(channel, &mut CHANNELS[channel].tx, &mut frame);
+
+        // The frame is lost, so that the console can not stall the channels
+        // indefinitely
+        if console_write::write_channel_frame(&frame[..len]).is_err() {
+            lost();
+        }
+    }
+}
+
+// The frame handed out by `drain` has been written, or failed to
+pub(crate) unsafe fn frame_written(error: Option<Error>) {
+    if error.is_some() {
+        lost();
+    }
+}
+
+// Continuous receive is being started, by the app, by `start_receive` or by
+// `ConsoleReadClient::read_until`
+pub(crate) unsafe fn detach() {
+    CHANNEL_RECEIVING = false;
+}
+
+// See `ChannelMux::is_receiving`
+pub(crate) unsafe fn is_receiving() -> bool {
+    CHANNEL_RECEIVING && ReceiveClient::new().is_receiving()
+}
+
+// Bytes have been received while receiving for the channels
+pub(crate) unsafe fn received() {
+    if !CHANNEL_RECEIVING {
+        return;
+    }
+
+    let receiver = ReceiveClient::new();
+    let mut byte = [0];
+
+    while receiver.read(&mut byte) > 0 {
+        match CHANNEL_PARSER.feed(byte[0]) {
+            Some(Ok(c)) => {
+                let payload = &CHANNEL_PARSER.payload[..CHANNEL_PARSER.len];
+
+                // A frame is delivered whole or not at all
+                if CHANNELS[c].rx.free() < payload.len() {
+                    lost();
+                } else {
+                    CHANNELS[c].rx.push(payload);
+                }
+            }
+            Some(Err(_)) => CHANNEL_CORRUPT = CHANNEL_CORRUPT.wrapping_add(1),
+            None => {}
+        }
+    }
+}
+
+unsafe fn lost() {
+    CHANNEL_DROPPED = CHANNEL_DROPPED.wrapping_add(1);
+}
+
+pub struct ChannelMux(());
+
+impl ChannelMux {
+    pub(crate) fn new() -> ChannelMux {
+        ChannelMux(())
+    }
+
+    // `Err(Error::EINVAL)` unless `id < MAX_CHANNELS`
+    pub fn channel(&self, id: usize) -> Result<Channel> {
+        if id < MAX_CHANNELS {
+            Ok(Channel { id })
+        } else {
+            Err(Error::EINVAL)
+        }
+    }
+
+    // Starts routing received frames to the channels. The console is busy for
+    // other reads until `stop_receive`. `Err(Error::EBUSY)` while a buffer
+    // started by the app has not been released, see `ReceiveClient::release`,
+    // or while receiving for other reads, such as `Readline::read_line`.
+    pub fn start_receive(&self) -> Result<()> {
+        // Bytes are read one at a time, so that a frame is routed as soon as
+        // its last byte arrives
+        if !ReceiveClient::new().start_intake(1)? {
+            return Err(Error::EBUSY);
+        }
+
+        unsafe {
+            CHANNEL_PARSER = FrameParser::new();
+            CHANNEL_RECEIVING = true;
+        }
+
+        Ok(())
+    }
+
+    pub fn stop_receive(&self) -> Result<()> {
+        if !self.is_receiving() {
+            return Err(Error::EINVAL);
+        }
+
+        ReceiveClient::new().stop()
+    }
+
+    // Also false once receiving was stopped by a read error
+    pub fn is_receiving(&self) -> bool {
+        unsafe { is_receiving() }
+    }
+
+    // All queued data has been handed to the console
+    pub fn is_flushed(&self) -> bool {
+        unsafe { CHANNELS.iter().all(|c| c.tx.len == 0) }
+    }
+
+    // Frames that could not be written, or that did not fit in the receive
+    // queue of their channel
+    pub fn dropped(&self) -> usize {
+        unsafe { CHANNEL_DROPPED }
+    }
+
+    // Frames received with a bad channel, length or checksum
+    pub fn corrupt(&self) -> usize {
+        unsafe { CHANNEL_CORRUPT }
+    }
+}
+
+pub struct Channel {
+    id: usize,
+}
+
+impl Channel {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    // Queues as much of `data` as fits, without waiting. Returns the number
+    // of bytes queued.
+    pub fn write(&self, data: &[u8]) -> usize {
+        unsafe {
+            let n = CHANNELS[self.id].tx.push(data);
+            drain();
+
+            n
+        }
+    }
+
+    // Queues all of `data`, waiting for room as needed
+    pub async fn write_all(&self, data: &[u8]) -> Result<usize> {
+        let mut queued = self.write(data);
+
+        while queued < data.len() {
+            let id = self.id;
+            wait_for(Source::ConsoleWrite, move || unsafe {
+                CHANNELS[id].tx.free() > 0
+            })
+            .await;
+
+            queued += self.write(&data[queued..]);
+        }
+
+        Ok(queued)
+    }
+
+    // Bytes queued that have not been handed to the console yet
+    pub fn pending(&self) -> usize {
+        unsafe { CHANNELS[self.id].tx.len }
+    }
+
+    // Received bytes that have not been read
+    pub fn available(&self) -> usize {
+        unsafe { CHANNELS[self.id].rx.len }
+    }
+
+    // Moves up to `buf.len()` received bytes to `buf`, without waiting.
+    // Returns the number of bytes moved.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        unsafe { CHANNELS[self.id].rx.pop_into(buf) }
+    }
+
+    // Waits until bytes have been received on the channel, then reads them
+    // like `read`. `Err(Error::EOFF)` if receiving stops first.
+    pub async fn wait_read(&self, buf: &mut [u8]) -> Result<usize> {
+        let id = self.id;
+
+        wait_for(Source::ConsoleRead, move || unsafe {
+            CHANNELS[id].rx.len > 0 || !ChannelMux::new().is_receiving()
+        })
+        .await;
+
+        match self.read(buf) {
+            0 => Err(Error::EOFF),
+            n => Ok(n),
+        }
+    }
+}
+
+// For log output. A message that does not fit is cut short.
+impl fmt::Write for Channel {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if Channel::write(self, s.as_bytes()) == s.len() {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    for c in CHANNELS.iter_mut() {
+        *c = ChannelQueues::new();
+    }
+    CHANNEL_NEXT = 0;
+    CHANNEL_RECEIVING = false;
+    CHANNEL_PARSER = FrameParser::new();
+    CHANNEL_DROPPED = 0;
+    CHANNEL_CORRUPT = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::ops::Generator;
+    use core::pin::Pin;
+    use std::vec::Vec;
+
+    use crate::console_read::subscribe_num::READ;
+    use crate::console_read::ConsoleRead;
+    use crate::console_write::tests::complete_chunk;
+    use crate::console_write::{allow_num, DRIVER_NUM};
+    use crate::syscalls::fake::Kernel;
+    use crate::syscalls::yieldk;
+
+    fn frame(channel: u8, payload: &[u8]) -> Vec<u8> {
+        let mut f = Vec::new();
+        f.push(FRAME_SYNC);
+        f.push(channel);
+        f.push(payload.len() as u8);
+        f.extend_from_slice(payload);
+        f.push(f[1..].iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+        f
+    }
+
+    #[test]
+    fn channels_take_turns() {
+        let kernel = Kernel::new();
+        let mux = ConsoleWriteClient::new().channels();
+        let log = mux.channel(0).unwrap();
+        let data = mux.channel(2).unwrap();
+        assert!(mux.channel(MAX_CHANNELS).is_err());
+
+        // Written straight away
+        assert_eq!(log.write(b"boot"), 4);
+        assert_eq!(
+            kernel.allowed(DRIVER_NUM, allow_num::WRITE),
+            Some(frame(0, b"boot"))
+        );
+
+        // Queued while the first frame is written, then sent in turns
+        let long = [7; MAX_PAYLOAD + 1];
+        assert_eq!(log.write(&long), long.len());
+        assert_eq!(data.write(b"xy"), 2);
+        assert!(!mux.is_flushed());
+
+        let mut sent = Vec::new();
+        for _ in 0..3 {
+            let f = kernel.allowed(DRIVER_NUM, allow_num::WRITE).unwrap();
+            complete_chunk(&kernel, f.len());
+            sent.push(kernel.allowed(DRIVER_NUM, allow_num::WRITE).unwrap());
+        }
+
+        assert_eq!(sent[0], frame(2, b"xy"));
+        assert_eq!(sent[1], frame(0, &long[..MAX_PAYLOAD]));
+        assert_eq!(sent[2], frame(0, &long[MAX_PAYLOAD..]));
+        assert!(mux.is_flushed());
+    }
+
+    #[test]
+    fn frames_are_routed() {
+        let kernel = Kernel::new();
+        let mux = ConsoleWriteClient::new().channels();
+        let console_read = ConsoleRead::new();
+        let mut console_read_task = console_read.get_task();
+
+        mux.start_receive().unwrap();
+
+        let mut corrupt = frame(1, b"bad");
+        corrupt[5] ^= 1;
+
+        let mut input = b"noise".to_vec();
+        input.extend(frame(1, b"hi"));
+        input.extend(corrupt);
+        input.extend(frame(3, b""));
+        input.extend(frame(3, b"ok"));
+
+        for &b in input.iter() {
+            kernel.schedule_upcall_with_data(DRIVER_NUM, READ, &[b], 0, 1, 0);
+            yieldk();
+            Pin::new(&mut console_read_task).resume();
+        }
+
+        let mut buf = [0; 8];
+        let channel = mux.channel(1).unwrap();
+        assert_eq!(channel.read(&mut buf), 2);
+        assert_eq!(&buf[..2], b"hi");
+
+        let channel = mux.channel(3).unwrap();
+        assert_eq!(channel.available(), 2);
+        assert_eq!(channel.read(&mut buf), 2);
+        assert_eq!(&buf[..2], b"ok");
+
+        assert_eq!(mux.corrupt(), 1);
+        assert!(mux.is_receiving());
+
+        // Other reads do not take bytes from the channels
+        assert_eq!(ReceiveClient::new().start_intake(1), Err(Error::EBUSY));
+
+        // Receiving starts again once the aborted read has completed. The
+        // buffer of the library is not handed to the app in between.
+        mux.stop_receive().unwrap();
+        assert_eq!(mux.start_receive(), Err(Error::EBUSY));
+        kernel.schedule_upcall(DRIVER_NUM, READ, 0, 0, 0);
+        yieldk();
+        Pin::new(&mut console_read_task).resume();
+        assert!(!mux.is_receiving());
+        assert_eq!(ReceiveClient::new().release().err(), Some(Error::EINVAL));
+        assert_eq!(mux.start_receive(), Ok(()));
+        assert!(mux.is_receiving());
+    }
+}
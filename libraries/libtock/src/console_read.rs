@@ -1,38 +1,74 @@
+use core::cmp;
 use core::ops::Generator;
 
-use crate::result::{Error, Result, UsizeError};
+use crate::channel;
+use crate::executor::Source;
+use crate::futures::wait_for;
+use crate::queue::{OverflowPolicy, Queue};
+use crate::result::{library_error, Error, Result, UsizeError};
 use crate::syscalls::{allow, command, subscribe, CallbackMessage};
 use crate::task::{DriverTask, DriverTaskClient, DriverTaskWithState};
+use crate::timeout::Cancellable;
 
-const DRIVER_NUM: usize = 1;
+pub(crate) const DRIVER_NUM: usize = 1;
 
-mod allow_num {
+pub(crate) mod allow_num {
     pub const READ: usize = 2;
 }
 
-mod subscribe_num {
+pub(crate) mod subscribe_num {
     pub const READ: usize = 2;
 }
 
-mod command_num {
+pub(crate) mod command_num {
     pub const READ: usize = 2;
     pub const READ_ABORT: usize = 3;
 }
 
-static mut CONSOLE_READ_MESSAGE: Option<CallbackMessage> = None;
+static mut CONSOLE_READ_MESSAGE: Queue<CallbackMessage> = Queue::new();
 
 #[derive(Copy, Clone)]
 pub enum ConsoleReadClientMessage {
     BytesRead(Result<usize>),
 }
 
-static mut CONSOLE_READ_CLIENT_MESSAGE: Option<ConsoleReadClientMessage> = None;
+static mut CONSOLE_READ_CLIENT_MESSAGE: Queue<ConsoleReadClientMessage> = Queue::new();
+
+// Completion of a read that spans several kernel reads. A read that fails
+// after part of it has been read still reports the bytes read so far.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BytesRead {
+    count: usize,
+    error: Option<Error>,
+}
+
+impl BytesRead {
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn error(&self) -> Option<Error> {
+        self.error
+    }
+
+    // `Err` if nothing was read before `error`
+    fn failed(count: usize, error: Error) -> Result<BytesRead> {
+        if count == 0 {
+            Err(error)
+        } else {
+            Ok(BytesRead {
+                count,
+                error: Some(error),
+            })
+        }
+    }
+}
 
 extern "C" fn console_read_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
     let cb_message = CallbackMessage::new(arg0, arg1, arg2, userdata);
 
     unsafe {
-        CONSOLE_READ_MESSAGE = Some(cb_message);
+        let _ = CONSOLE_READ_MESSAGE.push(cb_message);
     }
 }
 
@@ -56,58 +92,257 @@ static mut CONSOLE_READ_STATE: Option<ConsoleReadState> = None;
 // Corresponds to kernel read buffer
 static mut CONSOLE_READ_BUF: [u8; 64] = [0; 64];
 
-pub struct ConsoleRead;
+// Continuous receive, see `ReceiveClient`
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Receive {
+    Off,
+    // A read of this many bytes is re-armed whenever one completes
+    On(usize),
+    // The read has been aborted, its bytes still go to the ring buffer
+    Stopping,
+}
+
+static mut CONSOLE_RECEIVE: Receive = Receive::Off;
+
+static mut RECEIVE_RING: Option<RingBuffer> = None;
+
+// Error that ended continuous receive, reported by the next wait
+static mut RECEIVE_ERROR: Option<Error> = None;
+
+// The ongoing read was given up by its client, see `ReadOp::discard`. It
+// completes without a client message.
+static mut CONSOLE_READ_DISCARD: bool = false;
+
+// Storage of the library's own continuous receive, see `read_until`. It is
+// only ever accessed through `RingBuffer`, and never handed out.
+static mut CONSOLE_INTAKE: [u8; 64] = [0; 64];
+
+enum Storage {
+    // Provided by the app, handed back by `ReceiveClient::release`
+    App(&'static mut [u8]),
+    Intake,
+}
+
+impl Storage {
+    fn is_app(&self) -> bool {
+        match *self {
+            Storage::App(_) => true,
+            Storage::Intake => false,
+        }
+    }
+}
+
+// Bytes received in continuous mode
+struct RingBuffer {
+    storage: Storage,
+    head: usize,
+    len: usize,
+    // Bytes lost because the buffer was full
+    dropped: usize,
+}
+
+impl RingBuffer {
+    fn new(storage: Storage) -> RingBuffer {
+        RingBuffer {
+            storage,
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn buf(&self) -> &[u8] {
+        match self.storage {
+            Storage::App(ref buf) => &buf[..],
+            Storage::Intake => unsafe { &CONSOLE_INTAKE[..] },
+        }
+    }
+
+    fn buf_mut(&mut self) -> &mut [u8] {
+        match self.storage {
+            Storage::App(ref mut buf) => &mut buf[..],
+            Storage::Intake => unsafe { &mut CONSOLE_INTAKE[..] },
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.buf().len()
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let capacity = self.capacity();
+
+        for &b in bytes {
+            if self.len == capacity {
+                self.dropped = self.dropped.wrapping_add(1);
+                continue;
+            }
+
+            let i = (self.head + self.len) % capacity;
+            self.buf_mut()[i] = b;
+            self.len += 1;
+        }
+    }
+
+    fn pop_into(&mut self, out: &mut [u8]) -> usize {
+        let capacity = self.capacity();
+        let n = cmp::min(out.len(), self.len);
+
+        for b in out[..n].iter_mut() {
+            *b = self.buf()[self.head];
+            self.head = (self.head + 1) % capacity;
+        }
+        self.len -= n;
+
+        n
+    }
+
+    fn position(&self, byte: u8) -> Option<usize> {
+        let buf = self.buf();
+
+        (0..self.len).find(|&i| buf[(self.head + i) % buf.len()] == byte)
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == self.capacity()
+    }
+}
+
+// Hands a finished read to the client, or in continuous mode to the ring
+// buffer, after which the next read is armed.
+unsafe fn read_done(res: Result<usize>) {
+    let mode = CONSOLE_RECEIVE;
+
+    if mode == Receive::Off {
+        if CONSOLE_READ_DISCARD {
+            CONSOLE_READ_DISCARD = false;
+            return;
+        }
+
+        let _ = CONSOLE_READ_CLIENT_MESSAGE.push(ConsoleReadClientMessage::BytesRead(res));
+        return;
+    }
+
+    CONSOLE_RECEIVE = Receive::Off;
+
+    let res = res.and_then(|n| {
+        if let Some(ring) = RECEIVE_RING.as_mut() {
+            ring.push(&CONSOLE_READ_BUF[..n]);
+        }
+
+        // Frames are taken out of the buffer as they arrive
+        channel::received();
+
+        match mode {
+            Receive::On(chunk) => arm_read(chunk).map(|_| CONSOLE_RECEIVE = mode),
+            _ => Ok(()),
+        }
+    });
+
+    if let Err(e) = res {
+        RECEIVE_ERROR = Some(e);
+    }
+}
+
+unsafe fn arm_read(len: usize) -> Result<()> {
+    allow(
+        DRIVER_NUM,
+        allow_num::READ,
+        &CONSOLE_READ_BUF as *const u8 as *mut u8,
+        len,
+    )
+    .and_then(|_| {
+        subscribe(
+            DRIVER_NUM,
+            subscribe_num::READ,
+            console_read_callback as *const _,
+            0,
+        )
+    })
+    .and_then(|_| command(DRIVER_NUM, command_num::READ, len, 0))
+    .map(|_| {
+        CONSOLE_READ_STATE = Some(ConsoleReadState::Ongoing(
+            ReadsPending(len),
+            ReadsComplete(0),
+        ));
+    })
+}
+
+// Starts continuous receive into `storage`. The bytes left in the intake by an
+// earlier receive are kept if `storage` is the intake again.
+unsafe fn start_receive(storage: Storage, chunk: usize) -> Result<()> {
+    if CONSOLE_READ_STATE.is_some() || !CONSOLE_READ_CLIENT_MESSAGE.is_empty() {
+        return Err(library_error(Error::EBUSY));
+    }
+
+    let keep = match RECEIVE_RING {
+        Some(ref ring) if ring.storage.is_app() => return Err(library_error(Error::EBUSY)),
+        Some(_) => !storage.is_app(),
+        None => false,
+    };
+
+    if !keep {
+        RECEIVE_RING = Some(RingBuffer::new(storage));
+    }
+
+    channel::detach();
+    RECEIVE_ERROR = None;
+
+    arm_read(chunk).map(|_| CONSOLE_RECEIVE = Receive::On(chunk))
+}
+
+pub struct ConsoleRead(());
 
 impl ConsoleRead {
-    pub fn new() -> ConsoleRead {
-        ConsoleRead
+    pub(crate) fn new() -> ConsoleRead {
+        ConsoleRead(())
     }
 
-    // Safety : This coroutine is called whenever there is an incoming callback
-    //          message. When called, it *must* consume the incoming callback
-    //          message before yielding.
-    pub unsafe fn get_task(&self) -> impl Generator<Yield = (), Return = ()> + '_ {
+    // This coroutine is resumed whenever there are incoming callback messages.
+    // When resumed, it consumes all incoming callback messages before yielding.
+    // `Drivers::take` hands out a single `ConsoleRead`, so there is only ever one
+    // such coroutine.
+    pub fn get_task(&self) -> impl Generator<Yield = (), Return = ()> + '_ {
         || loop {
-            if let Some(cb_message) = CONSOLE_READ_MESSAGE.take() {
-                let crs = CONSOLE_READ_STATE.clone();
-                if let Some(x) = crs {
-                    let y: UsizeError = cb_message.get_arg0().into();
-                    match y.0 {
-                        Some(e) => {
-                            // Callback error
-                            CONSOLE_READ_STATE = None;
-                            CONSOLE_READ_CLIENT_MESSAGE =
-                                Some(ConsoleReadClientMessage::BytesRead(Err(e)));
-                        }
-                        None => {
-                            // No callback error
-                            match x {
-                                ConsoleReadState::Ongoing(rp, rc) => {
-                                    let mut rp = rp.0;
-                                    let mut rc = rc.0;
+            unsafe {
+                while let Some(cb_message) = CONSOLE_READ_MESSAGE.pop() {
+                    let crs = CONSOLE_READ_STATE.clone();
+                    if let Some(x) = crs {
+                        let y: UsizeError = cb_message.get_arg0().into();
+                        match y.0 {
+                            Some(e) => {
+                                // Callback error
+                                CONSOLE_READ_STATE = None;
+                                read_done(Err(e));
+                            }
+                            None => {
+                                // No callback error
+                                match x {
+                                    ConsoleReadState::Ongoing(rp, rc) => {
+                                        let mut rp = rp.0;
+                                        let mut rc = rc.0;
 
-                                    rc += cb_message.get_arg1();
-                                    rp -= cb_message.get_arg1();
+                                        rc += cb_message.get_arg1();
+                                        rp -= cb_message.get_arg1();
 
-                                    if rp == 0 {
-                                        CONSOLE_READ_STATE = None;
-                                        CONSOLE_READ_CLIENT_MESSAGE =
-                                            Some(ConsoleReadClientMessage::BytesRead(Ok(rc)));
-                                    } else {
-                                        CONSOLE_READ_STATE = Some(ConsoleReadState::Ongoing(
-                                            ReadsPending(rp),
-                                            ReadsComplete(rc),
-                                        ));
+                                        if rp == 0 {
+                                            CONSOLE_READ_STATE = None;
+                                            read_done(Ok(rc));
+                                        } else {
+                                            CONSOLE_READ_STATE = Some(ConsoleReadState::Ongoing(
+                                                ReadsPending(rp),
+                                                ReadsComplete(rc),
+                                            ));
+                                        }
                                     }
-                                }
-                                ConsoleReadState::Aborting(_rp, rc) => {
-                                    let mut rc = rc.0;
+                                    ConsoleReadState::Aborting(_rp, rc) => {
+                                        let mut rc = rc.0;
 
-                                    rc += cb_message.get_arg1();
+                                        rc += cb_message.get_arg1();
 
-                                    CONSOLE_READ_STATE = None;
-                                    CONSOLE_READ_CLIENT_MESSAGE =
-                                        Some(ConsoleReadClientMessage::BytesRead(Ok(rc)));
+                                        CONSOLE_READ_STATE = None;
+                                        read_done(Ok(rc));
+                                    }
                                 }
                             }
                         }
@@ -118,110 +353,236 @@ impl ConsoleRead {
             yield;
         }
     }
+}
+
+impl DriverTask for ConsoleRead {
+    fn has_message(&self) -> bool {
+        unsafe { !CONSOLE_READ_MESSAGE.is_empty() }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { CONSOLE_READ_MESSAGE.dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { CONSOLE_READ_MESSAGE.configure(capacity, policy) }
+    }
+}
+
+impl DriverTaskWithState for ConsoleRead {
+    fn is_active(&self) -> bool {
+        unsafe { CONSOLE_READ_STATE.is_some() }
+    }
+}
+
+pub struct ConsoleReadClient(());
+
+impl ConsoleReadClient {
+    pub(crate) fn new() -> ConsoleReadClient {
+        ConsoleReadClient(())
+    }
+
+    // Is there an ongoing read
+    pub fn is_active(&self) -> bool {
+        unsafe { CONSOLE_READ_STATE.is_some() }
+    }
 
     pub fn initiate_read(&self, len: usize) -> Result<()> {
         unsafe {
             // is there an ongoing read
             if CONSOLE_READ_STATE.is_some() {
-                return Err(Error::EBUSY);
+                return Err(library_error(Error::EBUSY));
             }
 
             // previous console read client message has not been consumed
-            if ConsoleReadClient::new().has_message() {
-                return Err(Error::EBUSY);
+            if self.has_message() {
+                return Err(library_error(Error::EBUSY));
             }
 
             // invalid length
             if len > CONSOLE_READ_BUF.len() {
-                return Err(Error::EINVAL);
-            }
-
-            allow(
-                DRIVER_NUM,
-                allow_num::READ,
-                &CONSOLE_READ_BUF as *const u8 as *mut u8,
-                len,
-            )
-            .and_then(|_| {
-                subscribe(
-                    DRIVER_NUM,
-                    subscribe_num::READ,
-                    console_read_callback as *const _,
-                    0,
-                )
-            })
-            .and_then(|_| command(DRIVER_NUM, command_num::READ, len, 0))
-            .map(|_| {
-                CONSOLE_READ_STATE = Some(ConsoleReadState::Ongoing(
-                    ReadsPending(len),
-                    ReadsComplete(0),
-                ));
-                ()
-            })
+                return Err(library_error(Error::EINVAL));
+            }
+
+            arm_read(len)
         }
     }
 
-    // `CONSOLE_READ_STATE` goes from `Ongoing(...)` to `Aborting(...)`
+    // `CONSOLE_READ_STATE` goes from `Ongoing(...)` to `Aborting(...)`. Also
+    // stops continuous receive.
     pub fn abort(&self) -> Result<()> {
         unsafe {
             let c = CONSOLE_READ_STATE.clone();
 
-            c.ok_or(Error::EINVAL).and_then(|x| match x {
-                ConsoleReadState::Ongoing(rp, rc) => {
-                    command(DRIVER_NUM, command_num::READ_ABORT, 0, 0).map(|_| {
-                        CONSOLE_READ_STATE = Some(ConsoleReadState::Aborting(rp, rc));
-                        ()
-                    })
-                }
-                ConsoleReadState::Aborting(_, _) => Err(Error::EBUSY),
-            })
+            c.ok_or_else(|| library_error(Error::EINVAL))
+                .and_then(|x| match x {
+                    ConsoleReadState::Ongoing(rp, rc) => {
+                        command(DRIVER_NUM, command_num::READ_ABORT, 0, 0).map(|_| {
+                            CONSOLE_READ_STATE = Some(ConsoleReadState::Aborting(rp, rc));
+                            if CONSOLE_RECEIVE != Receive::Off {
+                                CONSOLE_RECEIVE = Receive::Stopping;
+                            }
+                        })
+                    }
+                    ConsoleReadState::Aborting(_, _) => Err(library_error(Error::EBUSY)),
+                })
         }
     }
-}
 
-impl DriverTask for ConsoleRead {
-    fn has_message(&self) -> bool {
-        unsafe { CONSOLE_READ_MESSAGE.is_some() }
+    // Continuous receive into a ring buffer, see `ReceiveClient`
+    pub fn receiver(&self) -> ReceiveClient {
+        ReceiveClient(())
     }
-}
 
-impl DriverTaskWithState for ConsoleRead {
-    fn is_active(&self) -> bool {
-        unsafe { CONSOLE_READ_STATE.is_some() }
+    // Read of `len` bytes that can be cancelled, see `timeout::with_timeout`
+    pub fn read_op(&self, len: usize) -> ReadOp {
+        ReadOp { len }
     }
-}
 
-pub struct ConsoleReadClient;
+    // Read up to `buf.len()` bytes into `buf`, at most the size of the kernel
+    // read buffer. Fewer bytes are read if the read is aborted. Returns the
+    // number of bytes read.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        self.initiate_read(buf.len())?;
 
-impl ConsoleReadClient {
-    pub fn new() -> ConsoleReadClient {
-        ConsoleReadClient
+        self.wait_read(buf).await
     }
 
-    pub fn reap_read_to_buffer(&self, buf: &mut [u8]) -> Result<()> {
-        unsafe {
-            let c = CONSOLE_READ_CLIENT_MESSAGE.clone();
-            let res = c.ok_or(Error::EINVAL).and_then(|c| {
-                match c {
-                    ConsoleReadClientMessage::BytesRead(len) => {
-                        len.and_then(|l| {
-                            // We need to have a slice that can accomodate the buffer.
-                            if l != buf.len() {
-                                Err(Error::EINVAL)
-                            } else {
-                                buf.copy_from_slice(&CONSOLE_READ_BUF[..buf.len()]);
-                                self.clear_console_read_buf();
-
-                                Ok(())
-                            }
-                        })
+    // Reads `buf.len()` bytes, of any length, in kernel buffer sized reads.
+    // If a read is aborted, the bytes read so far are returned.
+    pub async fn read_exact(&self, buf: &mut [u8]) -> Result<BytesRead> {
+        let mut count = 0;
+
+        for chunk in buf.chunks_mut(unsafe { CONSOLE_READ_BUF.len() }) {
+            match self.read(chunk).await {
+                Ok(n) => {
+                    count += n;
+
+                    if n < chunk.len() {
+                        break;
                     }
                 }
-            });
+                Err(e) => return BytesRead::failed(count, e),
+            }
+        }
 
-            CONSOLE_READ_CLIENT_MESSAGE = None;
+        Ok(BytesRead { count, error: None })
+    }
+
+    // Reads until `delimiter`, which is included, or until `buf` is full.
+    // Bytes are received continuously into the buffer of the library, see
+    // `ReceiveClient`, so that none are lost between kernel reads. Bytes
+    // received after the delimiter are kept for the next read from that
+    // buffer.
+    //
+    // `Err(Error::EBUSY)` while the app has a receive buffer of its own, or the
+    // channels receive, see `ChannelMux::start_receive`.
+    pub async fn read_until(&self, buf: &mut [u8], delimiter: u8) -> Result<BytesRead> {
+        self.receive(buf, |read| read.last() == Some(&delimiter))
+            .await
+    }
 
-            res
+    // Takes the bytes that the buffer of the library holds, up to
+    // `buf.len()`, without arming a kernel read. Only if it is empty, waits
+    // for the next byte to be received, see `read_until`.
+    pub async fn read_available(&self, buf: &mut [u8]) -> Result<BytesRead> {
+        self.receive(buf, |read| {
+            !read.is_empty() && ReceiveClient::new().available() == 0
+        })
+        .await
+    }
+
+    // Moves bytes from the buffer of the library to `buf` until `done` holds
+    // for the bytes moved so far, or `buf` is full, receiving while there are
+    // not enough. Receiving is stopped again if it was started here.
+    async fn receive<F>(&self, buf: &mut [u8], done: F) -> Result<BytesRead>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let receiver = ReceiveClient::new();
+        let mut count = 0;
+        let mut started = false;
+        let mut error = None;
+
+        // The bytes in a buffer of the app are not taken
+        unsafe {
+            if RECEIVE_RING.as_ref().map_or(false, |r| r.storage.is_app()) {
+                return Err(library_error(Error::EBUSY));
+            }
+        }
+
+        while count < buf.len() && !done(&buf[..count]) {
+            if receiver.read(&mut buf[count..count + 1]) == 1 {
+                count += 1;
+                continue;
+            }
+
+            // Bytes are received one at a time, as the kernel only completes
+            // a read once all of its bytes have arrived
+            match receiver.start_intake(1) {
+                Ok(s) => started |= s,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+
+            if let Err(e) = receiver.wait_for_bytes(1).await {
+                error = Some(e);
+                break;
+            }
+        }
+
+        if started && receiver.is_receiving() {
+            if let Err(e) = receiver.stop() {
+                // The armed read is not re-armed once it completes
+                unsafe {
+                    CONSOLE_RECEIVE = Receive::Stopping;
+                }
+                return BytesRead::failed(count, e);
+            }
+
+            // The bytes of the aborted read go to the buffer of the library
+            wait_for(Source::ConsoleRead, || !ReceiveClient::new().is_receiving()).await;
+        }
+
+        match error {
+            Some(e) => BytesRead::failed(count, e),
+            None => Ok(BytesRead { count, error: None }),
+        }
+    }
+
+    async fn wait_read(&self, buf: &mut [u8]) -> Result<usize> {
+        wait_for(Source::ConsoleRead, || {
+            ConsoleReadClient::new().has_message()
+        })
+        .await;
+
+        self.reap_read_to_buffer(buf)
+    }
+
+    // Copies the bytes of a completed read to the start of `buf`. Returns the
+    // number of bytes, `Err(Error::ESIZE)` if they do not fit.
+    pub fn reap_read_to_buffer(&self, buf: &mut [u8]) -> Result<usize> {
+        unsafe {
+            if CONSOLE_READ_CLIENT_MESSAGE.take_overflow() {
+                return Err(library_error(Error::ENOMEM));
+            }
+
+            let c = CONSOLE_READ_CLIENT_MESSAGE.pop();
+            c.ok_or_else(|| library_error(Error::EINVAL))
+                .and_then(|c| match c {
+                    ConsoleReadClientMessage::BytesRead(len) => len.and_then(|l| {
+                        if l > buf.len() {
+                            return Err(library_error(Error::ESIZE));
+                        }
+
+                        buf[..l].copy_from_slice(&CONSOLE_READ_BUF[..l]);
+                        self.clear_console_read_buf();
+
+                        Ok(l)
+                    }),
+                })
         }
     }
 
@@ -234,15 +595,652 @@ impl ConsoleReadClient {
 
 impl DriverTaskClient for ConsoleReadClient {
     fn has_message(&self) -> bool {
-        unsafe { CONSOLE_READ_CLIENT_MESSAGE.is_some() }
+        unsafe { !CONSOLE_READ_CLIENT_MESSAGE.is_empty() }
     }
 
     fn reap_message(&self) {
         unsafe {
-            let c = CONSOLE_READ_CLIENT_MESSAGE.clone();
-            c.map(|_| {
-                CONSOLE_READ_CLIENT_MESSAGE = None;
-            });
+            CONSOLE_READ_CLIENT_MESSAGE.pop();
+        }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { CONSOLE_READ_CLIENT_MESSAGE.dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { CONSOLE_READ_CLIENT_MESSAGE.configure(capacity, policy) }
+    }
+}
+
+// Re-arms the console read whenever one completes, and collects the bytes
+// received in a ring buffer provided by the app. While receiving, the console
+// is busy for one-shot reads.
+//
+// The next read is armed by the `ConsoleRead` task once it has handled the
+// completed one, not in the upcall. Input that arrives in between is lost,
+// unless the kernel buffers it, so tasks should not keep the executor from
+// running the driver tasks while receiving.
+//
+// Received bytes are not client messages: they stay in the buffer until read,
+// without keeping the executor awake.
+//
+// The kernel only completes a read once it has received all of its bytes, so
+// a `chunk` of 1 suits interactive input best.
+pub struct ReceiveClient(());
+
+impl ReceiveClient {
+    pub(crate) fn new() -> ReceiveClient {
+        ReceiveClient(())
+    }
+
+    // Starts receiving into `buf`. Bytes that arrive while `buf` is full are
+    // dropped, see `dropped`. `Err(Error::EBUSY)` while receiving, or until
+    // the buffer of the previous start has been handed back by `release`.
+    // Bytes the library left in its own buffer, see
+    // `ConsoleReadClient::read_until`, are discarded.
+    pub fn start(&self, buf: &'static mut [u8], chunk: usize) -> Result<()> {
+        unsafe {
+            if buf.is_empty() || chunk == 0 || chunk > CONSOLE_READ_BUF.len() {
+                return Err(library_error(Error::EINVAL));
+            }
+
+            start_receive(Storage::App(buf), chunk)
+        }
+    }
+
+    // Receives into the buffer of the library, which is never handed out,
+    // unless it already does. Bytes the buffer still holds are kept. Returns
+    // whether receiving was started. `Err(Error::EBUSY)` while the app has a
+    // receive buffer of its own, or the channels receive, see
+    // `ChannelMux::start_receive`.
+    pub(crate) fn start_intake(&self, chunk: usize) -> Result<bool> {
+        if self.is_receiving() {
+            unsafe {
+                return match RECEIVE_RING {
+                    Some(ref ring) if !ring.storage.is_app() && !channel::is_receiving() => {
+                        Ok(false)
+                    }
+                    _ => Err(library_error(Error::EBUSY)),
+                };
+            }
+        }
+
+        unsafe { start_receive(Storage::Intake, chunk).map(|_| true) }
+    }
+
+    // Aborts the armed read. Its bytes are still added to the buffer, which
+    // stays readable.
+    pub fn stop(&self) -> Result<()> {
+        if self.is_receiving() {
+            ConsoleReadClient::new().abort()
+        } else {
+            Err(library_error(Error::EINVAL))
+        }
+    }
+
+    // Hands back the buffer once receiving has ended, see `stop`. Bytes that
+    // have not been read are discarded. `Err(Error::EBUSY)` while receiving,
+    // `Err(Error::EINVAL)` if there is no buffer of the app.
+    pub fn release(&self) -> Result<&'static mut [u8]> {
+        if self.is_receiving() {
+            return Err(library_error(Error::EBUSY));
+        }
+
+        unsafe {
+            match RECEIVE_RING.take() {
+                Some(RingBuffer {
+                    storage: Storage::App(buf),
+                    ..
+                }) => Ok(buf),
+                ring => {
+                    RECEIVE_RING = ring;
+                    Err(library_error(Error::EINVAL))
+                }
+            }
+        }
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        unsafe { CONSOLE_RECEIVE != Receive::Off }
+    }
+
+    pub fn available(&self) -> usize {
+        unsafe { RECEIVE_RING.as_ref().map_or(0, |r| r.len) }
+    }
+
+    // Moves up to `buf.len()` received bytes to `buf`, without waiting.
+    // Returns the number of bytes moved.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        unsafe { RECEIVE_RING.as_mut().map_or(0, |r| r.pop_into(buf)) }
+    }
+
+    // Waits until at least `n` bytes have been received, `n` being capped at
+    // the size of the buffer. Returns the number of bytes available.
+    //
+    // `Err(Error::EOFF)` if receiving stops first, or the error that stopped
+    // it.
+    pub async fn wait_for_bytes(&self, n: usize) -> Result<usize> {
+        let capacity = unsafe { RECEIVE_RING.as_ref().map_or(0, |r| r.capacity()) };
+        let n = cmp::min(n, capacity);
+
+        wait_for(Source::ConsoleRead, move || {
+            let receiver = ReceiveClient::new();
+            receiver.available() >= n || !receiver.is_receiving()
+        })
+        .await;
+
+        if self.available() >= n {
+            Ok(self.available())
+        } else {
+            Err(self.take_error())
+        }
+    }
+
+    // Waits until `delimiter` has been received. Returns the number of bytes
+    // up to and including the delimiter.
+    //
+    // `Err(Error::ESIZE)` if the buffer fills up without a delimiter,
+    // `Err(Error::EOFF)` if receiving stops first, or the error that stopped
+    // it.
+    pub async fn wait_for_delimiter(&self, delimiter: u8) -> Result<usize> {
+        let done = move || unsafe {
+            match RECEIVE_RING.as_ref() {
+                Some(ring) => ring.position(delimiter).is_some() || ring.is_full(),
+                None => true,
+            }
+        };
+
+        wait_for(Source::ConsoleRead, move || {
+            done() || !ReceiveClient::new().is_receiving()
+        })
+        .await;
+
+        unsafe {
+            match RECEIVE_RING.as_ref() {
+                Some(ring) => match ring.position(delimiter) {
+                    Some(i) => Ok(i + 1),
+                    None if ring.is_full() => Err(library_error(Error::ESIZE)),
+                    None => Err(self.take_error()),
+                },
+                None => Err(library_error(Error::EOFF)),
+            }
+        }
+    }
+
+    // Bytes dropped because the buffer was full
+    pub fn dropped(&self) -> usize {
+        unsafe { RECEIVE_RING.as_ref().map_or(0, |r| r.dropped) }
+    }
+
+    fn take_error(&self) -> Error {
+        unsafe {
+            RECEIVE_ERROR
+                .take()
+                .unwrap_or_else(|| library_error(Error::EOFF))
+        }
+    }
+}
+
+// Bytes received by a `ReadOp`
+pub struct ReadBuffer {
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl ReadBuffer {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+pub struct ReadOp {
+    len: usize,
+}
+
+impl Cancellable for ReadOp {
+    type Output = ReadBuffer;
+
+    fn start(&mut self) -> Result<()> {
+        ConsoleReadClient::new().initiate_read(self.len)
+    }
+
+    fn is_complete(&self) -> bool {
+        ConsoleReadClient::new().has_message()
+    }
+
+    // A read that was aborted completes with the bytes received so far
+    fn complete(&mut self) -> Result<ReadBuffer> {
+        let mut read = ReadBuffer {
+            buf: [0; 64],
+            len: 0,
+        };
+
+        unsafe {
+            if CONSOLE_READ_CLIENT_MESSAGE.take_overflow() {
+                return Err(library_error(Error::ENOMEM));
+            }
+
+            match CONSOLE_READ_CLIENT_MESSAGE.pop() {
+                Some(ConsoleReadClientMessage::BytesRead(len)) => read.len = len?,
+                None => return Err(library_error(Error::EINVAL)),
+            }
+
+            read.buf[..read.len].copy_from_slice(&CONSOLE_READ_BUF[..read.len]);
+            ConsoleReadClient::new().clear_console_read_buf();
+        }
+
+        Ok(read)
+    }
+
+    fn abort(&mut self) -> Result<bool> {
+        let client = ConsoleReadClient::new();
+
+        if client.is_active() {
+            client.abort().map(|_| true)
+        } else {
+            Ok(client.has_message())
+        }
+    }
+
+    fn discard(&mut self) {
+        let client = ConsoleReadClient::new();
+
+        if client.is_active() {
+            unsafe {
+                CONSOLE_READ_DISCARD = true;
+            }
+        } else if client.has_message() {
+            client.reap_message();
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    CONSOLE_READ_MESSAGE = Queue::new();
+    CONSOLE_READ_CLIENT_MESSAGE = Queue::new();
+    CONSOLE_READ_STATE = None;
+    CONSOLE_READ_BUF = [0; 64];
+    CONSOLE_RECEIVE = Receive::Off;
+    RECEIVE_RING = None;
+    RECEIVE_ERROR = None;
+    CONSOLE_READ_DISCARD = false;
+    CONSOLE_INTAKE = [0; 64];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::pin::Pin;
+    use std::boxed::Box;
+    use std::vec;
+
+    use crate::drivers::Drivers;
+    use crate::executor::Executor;
+    use crate::syscalls::fake::{Kernel, SyscallClass, SyscallRecord};
+    use crate::syscalls::yieldk;
+
+    #[test]
+    fn read_completes() {
+        let kernel = Kernel::new();
+        let console_read = ConsoleRead::new();
+        let console_read_client = ConsoleReadClient::new();
+        let mut console_read_task = console_read.get_task();
+
+        console_read_client.initiate_read(5).unwrap();
+        assert!(console_read_client.is_active());
+
+        assert_eq!(
+            kernel.syscalls(),
+            vec![
+                SyscallRecord::Allow {
+                    major: DRIVER_NUM,
+                    minor: allow_num::READ,
+                    len: 5,
+                },
+                SyscallRecord::Subscribe {
+                    major: DRIVER_NUM,
+                    minor: subscribe_num::READ,
+                    userdata: 0,
+                },
+                SyscallRecord::Command {
+                    major: DRIVER_NUM,
+                    minor: command_num::READ,
+                    arg1: 5,
+                    arg2: 0,
+                },
+            ]
+        );
+
+        assert_eq!(
+            kernel.write_allowed(DRIVER_NUM, allow_num::READ, b"hello"),
+            5
+        );
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::READ, 0, 5, 0);
+        yieldk();
+        Pin::new(&mut console_read_task).resume();
+
+        assert!(!console_read_client.is_active());
+        assert!(console_read_client.has_message());
+
+        let mut buf = [0; 5];
+        assert_eq!(console_read_client.reap_read_to_buffer(&mut buf), Ok(5));
+        assert_eq!(&buf, b"hello");
+        assert!(!console_read_client.has_message());
+    }
+
+    #[test]
+    fn read_is_busy_while_ongoing() {
+        let _kernel = Kernel::new();
+        let console_read_client = ConsoleReadClient::new();
+
+        assert_eq!(console_read_client.initiate_read(65), Err(Error::EINVAL));
+        assert_eq!(console_read_client.initiate_read(5), Ok(()));
+        assert_eq!(console_read_client.initiate_read(5), Err(Error::EBUSY));
+    }
+
+    #[test]
+    fn failed_allow_does_not_start_read() {
+        let kernel = Kernel::new();
+        kernel.push_result(
+            SyscallClass::Allow,
+            DRIVER_NUM,
+            allow_num::READ,
+            Err(Error::ENOMEM),
+        );
+
+        let console_read_client = ConsoleReadClient::new();
+
+        assert_eq!(console_read_client.initiate_read(5), Err(Error::ENOMEM));
+        assert!(!console_read_client.is_active());
+    }
+
+    #[test]
+    fn abort_reports_partial_read() {
+        let kernel = Kernel::new();
+        let console_read = ConsoleRead::new();
+        let console_read_client = ConsoleReadClient::new();
+        let mut console_read_task = console_read.get_task();
+
+        console_read_client.initiate_read(5).unwrap();
+        console_read_client.abort().unwrap();
+        assert_eq!(console_read_client.abort(), Err(Error::EBUSY));
+
+        kernel.write_allowed(DRIVER_NUM, allow_num::READ, b"hi");
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::READ, 0, 2, 0);
+        yieldk();
+        Pin::new(&mut console_read_task).resume();
+
+        assert!(!console_read_client.is_active());
+
+        // The buffer only needs to fit the bytes read
+        let mut buf = [0; 8];
+        assert_eq!(console_read_client.reap_read_to_buffer(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"hi");
+    }
+
+    #[test]
+    fn callback_error_is_reported() {
+        let kernel = Kernel::new();
+        let console_read = ConsoleRead::new();
+        let console_read_client = ConsoleReadClient::new();
+        let mut console_read_task = console_read.get_task();
+
+        console_read_client.initiate_read(5).unwrap();
+
+        kernel.schedule_upcall(
+            DRIVER_NUM,
+            subscribe_num::READ,
+            Error::FAIL.code() as usize,
+            0,
+            0,
+        );
+        yieldk();
+        Pin::new(&mut console_read_task).resume();
+
+        let mut buf = [0; 5];
+        assert_eq!(
+            console_read_client.reap_read_to_buffer(&mut buf),
+            Err(Error::FAIL)
+        );
+    }
+
+    #[test]
+    fn read_exact_spans_kernel_reads() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let console_read = drivers.console_read;
+        let mut executor = Executor::new(drivers.tasks);
+
+        kernel.schedule_upcall_with_data(DRIVER_NUM, subscribe_num::READ, &[b'a'; 64], 0, 64, 0);
+        kernel.schedule_upcall_with_data(DRIVER_NUM, subscribe_num::READ, &[b'b'; 36], 0, 36, 0);
+
+        let mut buf = [0; 100];
+        let read = executor.block_on(console_read.read_exact(&mut buf));
+
+        assert_eq!(read.map(|b| (b.count(), b.error())), Ok((100, None)));
+        assert!(buf[..64].iter().all(|&b| b == b'a'));
+        assert!(buf[64..].iter().all(|&b| b == b'b'));
+
+        // A failed read after the first one reports the bytes read so far
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::READ, 0, 64, 0);
+        kernel.push_result(SyscallClass::Command, DRIVER_NUM, command_num::READ, Ok(0));
+        kernel.push_result(
+            SyscallClass::Command,
+            DRIVER_NUM,
+            command_num::READ,
+            Err(Error::FAIL),
+        );
+        let read = executor.block_on(console_read.read_exact(&mut buf));
+        assert_eq!(
+            read.map(|b| (b.count(), b.error())),
+            Ok((64, Some(Error::FAIL)))
+        );
+    }
+
+    #[test]
+    fn read_until_delimiter() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let console_read = drivers.console_read;
+        let mut executor = Executor::new(drivers.tasks);
+
+        for &b in b"ok\nno".iter() {
+            kernel.schedule_upcall_with_data(DRIVER_NUM, subscribe_num::READ, &[b], 0, 1, 0);
         }
+
+        let mut buf = [0; 8];
+        let read = executor.block_on(console_read.read_until(&mut buf, b'\n'));
+        assert_eq!(read.map(|b| b.count()), Ok(3));
+        assert_eq!(&buf[..3], b"ok\n");
+
+        // The read armed after the delimiter was aborted, its byte is kept.
+        // The buffer belongs to the library, not to the app.
+        let receiver = console_read.receiver();
+        assert!(!receiver.is_receiving());
+        assert_eq!(receiver.available(), 1);
+        assert_eq!(receiver.release().err(), Some(Error::EINVAL));
+
+        // Stops when the buffer is full, without receiving more
+        let read = executor.block_on(console_read.read_until(&mut buf[..1], b'\n'));
+        assert_eq!(read.map(|b| b.count()), Ok(1));
+        assert_eq!(buf[0], b'n');
+        assert_eq!(kernel.pending_upcalls(), 1);
+
+        // Busy while the app receives into a buffer of its own
+        receiver.start(ring(4), 1).unwrap();
+        let read = executor.block_on(console_read.read_until(&mut buf, b'\n'));
+        assert_eq!(read.map(|b| b.count()), Err(Error::EBUSY));
+    }
+
+    #[test]
+    fn read_available_takes_what_was_received() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let console_read = drivers.console_read;
+        let receiver = console_read.receiver();
+        let mut executor = Executor::new(drivers.tasks);
+        let read_armed = SyscallRecord::Allow {
+            major: DRIVER_NUM,
+            minor: allow_num::READ,
+            len: 1,
+        };
+
+        // Waits for the first byte. The byte of the read that was armed next
+        // arrives before its abort completes it.
+        kernel.schedule_upcall_with_data(DRIVER_NUM, subscribe_num::READ, b"a", 0, 1, 0);
+        kernel.schedule_upcall_with_data(DRIVER_NUM, subscribe_num::READ, b"b", 0, 1, 0);
+
+        let mut buf = [0; 16];
+        let read = executor.block_on(console_read.read_available(&mut buf));
+        assert_eq!(read.map(|b| b.count()), Ok(1));
+        assert_eq!(buf[0], b'a');
+        assert!(!receiver.is_receiving());
+
+        // Bytes that have been received are taken at once, without arming a
+        // kernel read
+        receiver.start_intake(1).unwrap();
+        kernel.schedule_upcall_with_data(DRIVER_NUM, subscribe_num::READ, b"c", 0, 1, 0);
+        kernel.schedule_upcall_with_data(DRIVER_NUM, subscribe_num::READ, b"d", 0, 1, 0);
+        assert_eq!(executor.block_on(receiver.wait_for_bytes(3)), Ok(3));
+
+        kernel.clear_syscalls();
+        let read = executor.block_on(console_read.read_available(&mut buf));
+        assert_eq!(read.map(|b| b.count()), Ok(3));
+        assert_eq!(&buf[..3], b"bcd");
+        assert!(!kernel.syscalls().contains(&read_armed));
+
+        // Receiving that was not started by `read_available` goes on
+        assert!(receiver.is_receiving());
+    }
+
+    fn ring(len: usize) -> &'static mut [u8] {
+        Box::leak(vec![0; len].into_boxed_slice())
+    }
+
+    #[test]
+    fn receive_rearms_after_each_chunk() {
+        let kernel = Kernel::new();
+        let console_read = ConsoleRead::new();
+        let receiver = ConsoleReadClient::new().receiver();
+        let mut console_read_task = console_read.get_task();
+
+        assert_eq!(receiver.start(ring(4), 0), Err(Error::EINVAL));
+        receiver.start(ring(4), 2).unwrap();
+        assert!(receiver.is_receiving());
+        assert_eq!(ConsoleReadClient::new().initiate_read(1), Err(Error::EBUSY));
+
+        for chunk in [&b"ab"[..], b"cd", b"ef"].iter() {
+            kernel.clear_syscalls();
+            kernel.write_allowed(DRIVER_NUM, allow_num::READ, chunk);
+            kernel.schedule_upcall(DRIVER_NUM, subscribe_num::READ, 0, 2, 0);
+            yieldk();
+            Pin::new(&mut console_read_task).resume();
+
+            // The next read is armed straight away
+            assert_eq!(
+                kernel.syscalls()[1..],
+                [
+                    SyscallRecord::Allow {
+                        major: DRIVER_NUM,
+                        minor: allow_num::READ,
+                        len: 2,
+                    },
+                    SyscallRecord::Subscribe {
+                        major: DRIVER_NUM,
+                        minor: subscribe_num::READ,
+                        userdata: 0,
+                    },
+                    SyscallRecord::Command {
+                        major: DRIVER_NUM,
+                        minor: command_num::READ,
+                        arg1: 2,
+                        arg2: 0,
+                    },
+                ]
+            );
+        }
+
+        // The buffer holds 4 bytes, the last chunk was dropped
+        assert!(!ConsoleReadClient::new().has_message());
+        assert_eq!(receiver.available(), 4);
+        assert_eq!(receiver.dropped(), 2);
+
+        let mut buf = [0; 3];
+        assert_eq!(receiver.read(&mut buf), 3);
+        assert_eq!(&buf, b"abc");
+        assert_eq!(receiver.read(&mut buf), 1);
+        assert_eq!(buf[0], b'd');
+    }
+
+    #[test]
+    fn receive_stops_with_partial_chunk() {
+        let kernel = Kernel::new();
+        let console_read = ConsoleRead::new();
+        let receiver = ConsoleReadClient::new().receiver();
+        let mut console_read_task = console_read.get_task();
+
+        assert_eq!(receiver.stop(), Err(Error::EINVAL));
+        receiver.start(ring(8), 4).unwrap();
+        receiver.stop().unwrap();
+
+        kernel.clear_syscalls();
+        kernel.write_allowed(DRIVER_NUM, allow_num::READ, b"x");
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::READ, 0, 1, 0);
+        yieldk();
+        Pin::new(&mut console_read_task).resume();
+
+        assert!(!receiver.is_receiving());
+        assert_eq!(kernel.syscalls(), vec![SyscallRecord::Yield]);
+        assert_eq!(receiver.available(), 1);
+
+        // Stopped receivers report `EOFF` for what they cannot deliver
+        let mut executor = Executor::new(Drivers::take().unwrap().tasks);
+        assert_eq!(
+            executor.block_on(receiver.wait_for_bytes(2)),
+            Err(Error::EOFF)
+        );
+        assert_eq!(executor.block_on(receiver.wait_for_bytes(1)), Ok(1));
+
+        // The buffer is handed back once receiving has ended, and can be
+        // started with again
+        assert_eq!(receiver.start(ring(8), 4), Err(Error::EBUSY));
+        let buf = receiver.release().unwrap();
+        assert_eq!(buf.len(), 8);
+        assert_eq!(receiver.release().err(), Some(Error::EINVAL));
+        assert_eq!(receiver.start(buf, 4), Ok(()));
+        assert_eq!(receiver.release().err(), Some(Error::EBUSY));
+    }
+
+    #[test]
+    fn receive_waits_for_delimiter() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let receiver = drivers.console_read.receiver();
+        let mut executor = Executor::new(drivers.tasks);
+
+        receiver.start(ring(8), 3).unwrap();
+        kernel.write_allowed(DRIVER_NUM, allow_num::READ, b"hi\nyo");
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::READ, 0, 3, 0);
+
+        assert_eq!(executor.block_on(receiver.wait_for_delimiter(b'\n')), Ok(3));
+        assert!(receiver.is_receiving());
+
+        // A callback error ends receiving and is reported once
+        kernel.schedule_upcall(
+            DRIVER_NUM,
+            subscribe_num::READ,
+            Error::FAIL.code() as usize,
+            0,
+            0,
+        );
+        assert_eq!(
+            executor.block_on(receiver.wait_for_delimiter(b'!')),
+            Err(Error::FAIL)
+        );
+        assert!(!receiver.is_receiving());
+        assert_eq!(receiver.available(), 3);
     }
 }
@@ -1,40 +1,63 @@
+use core::cmp;
 use core::fmt;
 use core::ops::Generator;
 
-use crate::result::{Error, Result, UsizeError};
+use crate::channel::{self, ChannelMux};
+use crate::executor::Source;
+use crate::futures::wait_for;
+use crate::print;
+use crate::queue::{OverflowPolicy, Queue};
+use crate::result::{library_error, Error, Result, UsizeError};
 use crate::syscalls::{allow, command, subscribe, CallbackMessage};
 use crate::task::{DriverTask, DriverTaskClient, DriverTaskWithState};
+use crate::timeout::Cancellable;
 
-const DRIVER_NUM: usize = 1;
+pub(crate) const DRIVER_NUM: usize = 1;
 
-mod allow_num {
+pub(crate) mod allow_num {
     pub const WRITE: usize = 1;
 }
 
-mod subscribe_num {
+pub(crate) mod subscribe_num {
     pub const WRITE: usize = 1;
 }
 
-mod command_num {
+pub(crate) mod command_num {
     pub const WRITE: usize = 1;
 }
 
-static mut CONSOLE_WRITE_MESSAGE: Option<CallbackMessage> = None;
+static mut CONSOLE_WRITE_MESSAGE: Queue<CallbackMessage> = Queue::new();
 
-pub struct BytesWritten(usize);
+// Completion of a write. A write that fails after part of it has been written
+// still reports the bytes written so far.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BytesWritten {
+    count: usize,
+    error: Option<Error>,
+}
+
+impl BytesWritten {
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn error(&self) -> Option<Error> {
+        self.error
+    }
+}
 
 #[derive(Copy, Clone)]
 pub enum ConsoleWriteClientMessage {
-    BytesWritten(Result<usize>),
+    BytesWritten(BytesWritten),
 }
 
-static mut CONSOLE_WRITE_CLIENT_MESSAGE: Option<ConsoleWriteClientMessage> = None;
+static mut CONSOLE_WRITE_CLIENT_MESSAGE: Queue<ConsoleWriteClientMessage> = Queue::new();
 
 extern "C" fn console_write_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
     let cb_message = CallbackMessage::new(arg0, arg1, arg2, userdata);
 
     unsafe {
-        CONSOLE_WRITE_MESSAGE = Some(cb_message);
+        let _ = CONSOLE_WRITE_MESSAGE.push(cb_message);
     }
 }
 
@@ -56,117 +79,188 @@ static mut CONSOLE_WRITE_STATE: Option<ConsoleWriteState> = None;
 // Corresponds to kernel write buffer
 static mut CONSOLE_WRITE_BUF: [u8; 64] = [0; 64];
 
-pub struct ConsoleWrite;
+// Part of an `initiate_write_static` write that has not been copied to
+// `CONSOLE_WRITE_BUF` yet
+static mut CONSOLE_WRITE_REMAINING: &[u8] = &[];
+
+// Who the ongoing write is for. Writes that drain the `print` buffer or write
+// a `channel` frame complete without a client message, and so do client writes
+// that were given up, see `WriteOp::discard`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Writer {
+    Client,
+    Discarded,
+    Print,
+    Channel,
+}
 
-impl ConsoleWrite {
-    pub fn new() -> ConsoleWrite {
-        ConsoleWrite
-    }
+static mut CONSOLE_WRITER: Writer = Writer::Client;
 
-    // Safety : This coroutine is called whenever there is an incoming callback
-    //          message. When called, it *must* consume the incoming callback
-    //          message before yielding.
-    pub unsafe fn get_task(&self) -> impl Generator<Yield = (), Return = ()> + '_ {
-        || loop {
-            if let Some(cb_message) = CONSOLE_WRITE_MESSAGE.take() {
-                let c = CONSOLE_WRITE_STATE.clone();
-
-                if let Some(ConsoleWriteState::Ongoing(wp, wc)) = c {
-                    let x: UsizeError = cb_message.get_arg0().into();
-                    match x.0 {
-                        Some(e) => {
-                            // Callback error
-                            CONSOLE_WRITE_STATE = None;
-                            CONSOLE_WRITE_CLIENT_MESSAGE =
-                                Some(ConsoleWriteClientMessage::BytesWritten(Err(e)));
-                        }
-                        None => {
-                            // No callback error
-                            let mut wp = wp.0;
-                            let mut wc = wc.0;
-
-                            wc += cb_message.get_arg0();
-                            wp -= cb_message.get_arg0();
-
-                            if wp == 0 {
-                                CONSOLE_WRITE_STATE = None;
-                                CONSOLE_WRITE_CLIENT_MESSAGE =
-                                    Some(ConsoleWriteClientMessage::BytesWritten(Ok(wc)));
-                            } else {
-                                CONSOLE_WRITE_STATE = Some(ConsoleWriteState::Ongoing(
-                                    WritesPending(wp),
-                                    WritesComplete(wc),
-                                ));
-                            }
-                        }
-                    };
-                }
-            }
+unsafe fn complete_write(count: usize, error: Option<Error>) {
+    CONSOLE_WRITE_STATE = None;
+    CONSOLE_WRITE_REMAINING = &[];
 
-            yield;
+    let writer = CONSOLE_WRITER;
+    CONSOLE_WRITER = Writer::Client;
+
+    match writer {
+        Writer::Client => {
+            let _ = CONSOLE_WRITE_CLIENT_MESSAGE.push(ConsoleWriteClientMessage::BytesWritten(
+                BytesWritten { count, error },
+            ));
         }
+        Writer::Discarded => {}
+        Writer::Print => print::chunk_written(error),
+        Writer::Channel => channel::frame_written(error),
     }
 
-    pub fn initiate_write(&self, s: &[u8]) -> Result<()> {
-        unsafe {
-            // is there an ongoing write
-            if CONSOLE_WRITE_STATE.is_some() {
-                return Err(Error::EBUSY);
-            }
+    // Output that was held back by the write. Printed output and channel
+    // frames take turns.
+    if writer == Writer::Print {
+        channel::drain();
+        print::drain();
+    } else {
+        print::drain();
+        channel::drain();
+    }
+}
 
-            // previous console write client message has not been consumed
-            if ConsoleWriteClient::new().has_message() {
-                return Err(Error::EBUSY);
-            }
+// Hands `chunk` to the kernel. `complete` is the number of bytes of the
+// ongoing write that have already been written.
+unsafe fn write_chunk(chunk: &[u8], complete: usize) -> Result<()> {
+    let client = ConsoleWriteClient::new();
+
+    client.clear_console_write_buf();
+
+    CONSOLE_WRITE_BUF[..chunk.len()].copy_from_slice(chunk);
+
+    allow(
+        DRIVER_NUM,
+        allow_num::WRITE,
+        &CONSOLE_WRITE_BUF as *const u8 as *mut u8,
+        chunk.len(),
+    )
+    .and_then(|_| {
+        subscribe(
+            DRIVER_NUM,
+            subscribe_num::WRITE,
+            console_write_callback as *const _,
+            0,
+        )
+    })
+    .and_then(|_| command(DRIVER_NUM, command_num::WRITE, chunk.len(), 0))
+    .map(|_| {
+        CONSOLE_WRITE_STATE = Some(ConsoleWriteState::Ongoing(
+            WritesPending(chunk.len()),
+            WritesComplete(complete),
+        ));
+    })
+    .map_err(|e| {
+        client.clear_console_write_buf();
+        e
+    })
+}
 
-            if s.len() > CONSOLE_WRITE_BUF.len() {
-                return Err(Error::EINVAL);
-            }
+// Hands the next buffer sized chunk of `CONSOLE_WRITE_REMAINING` to the kernel
+unsafe fn write_next_chunk(complete: usize) -> Result<()> {
+    let len = cmp::min(CONSOLE_WRITE_REMAINING.len(), CONSOLE_WRITE_BUF.len());
+    let (chunk, remaining) = CONSOLE_WRITE_REMAINING.split_at(len);
 
-            self.clear_console_write_buf();
-
-            &CONSOLE_WRITE_BUF[..s.len()].copy_from_slice(s);
-
-            let res = allow(
-                DRIVER_NUM,
-                allow_num::WRITE,
-                &CONSOLE_WRITE_BUF as *const u8 as *mut u8,
-                s.len(),
-            )
-            .and_then(|_| {
-                subscribe(
-                    DRIVER_NUM,
-                    subscribe_num::WRITE,
-                    console_write_callback as *const _,
-                    0,
-                )
-            })
-            .and_then(|_| command(DRIVER_NUM, command_num::WRITE, s.len(), 0))
-            .map(|_| ())
-            .map_err(|e| {
-                self.clear_console_write_buf();
-                e
-            });
+    write_chunk(chunk, complete)?;
+    CONSOLE_WRITE_REMAINING = remaining;
 
-            CONSOLE_WRITE_STATE = Some(ConsoleWriteState::Ongoing(
-                WritesPending(s.len()),
-                WritesComplete(0),
-            ));
+    Ok(())
+}
+
+// Hands the start of `bytes` to the kernel on behalf of `print`. Returns the
+// number of bytes handed out.
+pub(crate) unsafe fn write_print_chunk(bytes: &[u8]) -> Result<usize> {
+    let len = cmp::min(bytes.len(), CONSOLE_WRITE_BUF.len());
+
+    write_chunk(&bytes[..len], 0)?;
+    CONSOLE_WRITER = Writer::Print;
+
+    Ok(len)
+}
+
+// Hands a whole frame to the kernel on behalf of `channel`
+pub(crate) unsafe fn write_channel_frame(frame: &[u8]) -> Result<()> {
+    write_chunk(frame, 0)?;
+    CONSOLE_WRITER = Writer::Channel;
 
-            res
+    Ok(())
+}
+
+// Consumes all incoming callback messages. Normally run by the `ConsoleWrite`
+// task, and in place by `print` while it waits for output to drain.
+pub(crate) unsafe fn handle_callback_messages() {
+    while let Some(cb_message) = CONSOLE_WRITE_MESSAGE.pop() {
+        let c = CONSOLE_WRITE_STATE.clone();
+
+        if let Some(ConsoleWriteState::Ongoing(wp, wc)) = c {
+            let x: UsizeError = cb_message.get_arg0().into();
+            match x.0 {
+                Some(e) => {
+                    // Callback error
+                    complete_write(wc.0, Some(e));
+                }
+                None => {
+                    // No callback error
+                    let mut wp = wp.0;
+                    let mut wc = wc.0;
+
+                    wc += cb_message.get_arg0();
+                    wp -= cb_message.get_arg0();
+
+                    if wp != 0 {
+                        CONSOLE_WRITE_STATE = Some(ConsoleWriteState::Ongoing(
+                            WritesPending(wp),
+                            WritesComplete(wc),
+                        ));
+                    } else if CONSOLE_WRITE_REMAINING.is_empty() {
+                        complete_write(wc, None);
+                    } else if let Err(e) = write_next_chunk(wc) {
+                        complete_write(wc, Some(e));
+                    }
+                }
+            };
         }
     }
+}
 
-    fn clear_console_write_buf(&self) {
-        unsafe {
-            &CONSOLE_WRITE_BUF.iter_mut().for_each(|x| *x = 0);
+pub struct ConsoleWrite(());
+
+impl ConsoleWrite {
+    pub(crate) fn new() -> ConsoleWrite {
+        ConsoleWrite(())
+    }
+
+    // This coroutine is resumed whenever there are incoming callback messages.
+    // When resumed, it consumes all incoming callback messages before yielding.
+    // `Drivers::take` hands out a single `ConsoleWrite`, so there is only ever one
+    // such coroutine.
+    pub fn get_task(&self) -> impl Generator<Yield = (), Return = ()> + '_ {
+        || loop {
+            unsafe {
+                handle_callback_messages();
+            }
+
+            yield;
         }
     }
 }
 
 impl DriverTask for ConsoleWrite {
     fn has_message(&self) -> bool {
-        unsafe { CONSOLE_WRITE_MESSAGE.is_some() }
+        unsafe { !CONSOLE_WRITE_MESSAGE.is_empty() }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { CONSOLE_WRITE_MESSAGE.dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { CONSOLE_WRITE_MESSAGE.configure(capacity, policy) }
     }
 }
 
@@ -176,40 +270,194 @@ impl DriverTaskWithState for ConsoleWrite {
     }
 }
 
-pub struct ConsoleWriteClient;
+pub struct ConsoleWriteClient(());
 
 impl ConsoleWriteClient {
-    pub fn new() -> ConsoleWriteClient {
-        ConsoleWriteClient
+    pub(crate) fn new() -> ConsoleWriteClient {
+        ConsoleWriteClient(())
     }
 
-    pub fn reap_bytes_written_message(&self) -> Result<BytesWritten> {
+    // Is there an ongoing write. This includes writes that drain the `print`
+    // buffer and that write `channel` frames.
+    pub fn is_active(&self) -> bool {
+        unsafe { CONSOLE_WRITE_STATE.is_some() }
+    }
+
+    // `s` has to fit in the kernel write buffer, see `initiate_write_static`
+    // and `write` for longer writes.
+    pub fn initiate_write(&self, s: &[u8]) -> Result<()> {
         unsafe {
-            let c = CONSOLE_WRITE_CLIENT_MESSAGE.clone();
-            let res = c.ok_or(Error::EINVAL).and_then(|c| match c {
-                ConsoleWriteClientMessage::BytesWritten(len) => {
-                    len.and_then(|l| Ok(BytesWritten(l)))
+            self.check_idle()?;
+
+            if s.len() > CONSOLE_WRITE_BUF.len() {
+                return Err(library_error(Error::EINVAL));
+            }
+
+            write_chunk(s, 0)
+        }
+    }
+
+    // `s` can be of any length. It is handed to the kernel in buffer sized
+    // chunks, and a single client message reports the total number of bytes
+    // written.
+    pub fn initiate_write_static(&self, s: &'static [u8]) -> Result<()> {
+        unsafe {
+            self.check_idle()?;
+
+            CONSOLE_WRITE_REMAINING = s;
+
+            write_next_chunk(0).map_err(|e| {
+                CONSOLE_WRITE_REMAINING = &[];
+                e
+            })
+        }
+    }
+
+    fn check_idle(&self) -> Result<()> {
+        // is there an ongoing write
+        if self.is_active() {
+            return Err(library_error(Error::EBUSY));
+        }
+
+        // previous console write client message has not been consumed
+        if self.has_message() {
+            return Err(library_error(Error::EBUSY));
+        }
+
+        Ok(())
+    }
+
+    // Logical channels over the console, see `channel`
+    pub fn channels(&self) -> ChannelMux {
+        ChannelMux::new()
+    }
+
+    // Write that can be cancelled, see `timeout::with_timeout`
+    pub fn write_op<'a>(&self, s: &'a [u8]) -> WriteOp<'a> {
+        WriteOp { s }
+    }
+
+    // `s` can be of any length. It is written in buffer sized chunks, each
+    // one copied to the kernel write buffer once the previous one has been
+    // written. `Err` if the write could not be started at all.
+    //
+    // Each chunk waits until the console is idle, so printed output and
+    // channel frames that are being written take turns with the chunks.
+    pub async fn write(&self, s: &[u8]) -> Result<BytesWritten> {
+        let mut count = 0;
+
+        for chunk in s.chunks(unsafe { CONSOLE_WRITE_BUF.len() }) {
+            wait_for(Source::ConsoleWrite, || {
+                !ConsoleWriteClient::new().is_active()
+            })
+            .await;
+
+            if let Err(e) = self.initiate_write(chunk) {
+                if count == 0 {
+                    return Err(e);
                 }
-            });
 
-            CONSOLE_WRITE_CLIENT_MESSAGE = None;
+                return Ok(BytesWritten {
+                    count,
+                    error: Some(e),
+                });
+            }
+
+            wait_for(Source::ConsoleWrite, || {
+                ConsoleWriteClient::new().has_message()
+            })
+            .await;
+
+            let written = self.reap_bytes_written_message()?;
+            count += written.count;
+
+            if written.error.is_some() {
+                return Ok(BytesWritten {
+                    count,
+                    error: written.error,
+                });
+            }
+        }
+
+        Ok(BytesWritten { count, error: None })
+    }
+
+    fn clear_console_write_buf(&self) {
+        unsafe {
+            &CONSOLE_WRITE_BUF.iter_mut().for_each(|x| *x = 0);
+        }
+    }
 
-            res
+    pub fn reap_bytes_written_message(&self) -> Result<BytesWritten> {
+        unsafe {
+            if CONSOLE_WRITE_CLIENT_MESSAGE.take_overflow() {
+                return Err(library_error(Error::ENOMEM));
+            }
+
+            let c = CONSOLE_WRITE_CLIENT_MESSAGE.pop();
+            c.ok_or_else(|| library_error(Error::EINVAL))
+                .map(|c| match c {
+                    ConsoleWriteClientMessage::BytesWritten(written) => written,
+                })
         }
     }
 }
 
 impl DriverTaskClient for ConsoleWriteClient {
     fn has_message(&self) -> bool {
-        unsafe { CONSOLE_WRITE_CLIENT_MESSAGE.is_some() }
+        unsafe { !CONSOLE_WRITE_CLIENT_MESSAGE.is_empty() }
     }
 
     fn reap_message(&self) {
         unsafe {
-            let c = CONSOLE_WRITE_CLIENT_MESSAGE.clone();
-            c.map(|_| {
-                CONSOLE_WRITE_CLIENT_MESSAGE = None;
-            });
+            CONSOLE_WRITE_CLIENT_MESSAGE.pop();
+        }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { CONSOLE_WRITE_CLIENT_MESSAGE.dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { CONSOLE_WRITE_CLIENT_MESSAGE.configure(capacity, policy) }
+    }
+}
+
+pub struct WriteOp<'a> {
+    s: &'a [u8],
+}
+
+impl<'a> Cancellable for WriteOp<'a> {
+    type Output = BytesWritten;
+
+    fn start(&mut self) -> Result<()> {
+        ConsoleWriteClient::new().initiate_write(self.s)
+    }
+
+    fn is_complete(&self) -> bool {
+        ConsoleWriteClient::new().has_message()
+    }
+
+    fn complete(&mut self) -> Result<BytesWritten> {
+        ConsoleWriteClient::new().reap_bytes_written_message()
+    }
+
+    // The console driver can not abort a write, so we wait for it to complete
+    fn abort(&mut self) -> Result<bool> {
+        let client = ConsoleWriteClient::new();
+
+        Ok(client.is_active() || client.has_message())
+    }
+
+    fn discard(&mut self) {
+        let client = ConsoleWriteClient::new();
+
+        unsafe {
+            if client.is_active() && CONSOLE_WRITER == Writer::Client {
+                CONSOLE_WRITER = Writer::Discarded;
+            } else if client.has_message() {
+                client.reap_message();
+            }
         }
     }
 }
@@ -255,3 +503,231 @@ impl<'a> fmt::Write for ConsoleWriteStr<'a> {
         Ok(())
     }
 }
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    CONSOLE_WRITE_MESSAGE = Queue::new();
+    CONSOLE_WRITE_CLIENT_MESSAGE = Queue::new();
+    CONSOLE_WRITE_STATE = None;
+    CONSOLE_WRITE_BUF = [0; 64];
+    CONSOLE_WRITE_REMAINING = &[];
+    CONSOLE_WRITER = Writer::Client;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use core::fmt::Write;
+    use core::pin::Pin;
+    use std::vec;
+
+    use crate::drivers::Drivers;
+    use crate::executor::Executor;
+    use crate::syscalls::fake::{Kernel, SyscallClass, SyscallRecord};
+    use crate::syscalls::yieldk;
+
+    #[test]
+    fn write_completes() {
+        let kernel = Kernel::new();
+        let console_write = ConsoleWrite::new();
+        let console_write_client = ConsoleWriteClient::new();
+        let mut console_write_task = console_write.get_task();
+
+        console_write_client.initiate_write(b"hello").unwrap();
+        assert!(console_write_client.is_active());
+        assert_eq!(
+            kernel.allowed(DRIVER_NUM, allow_num::WRITE),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(
+            kernel.syscalls().last(),
+            Some(&SyscallRecord::Command {
+                major: DRIVER_NUM,
+                minor: command_num::WRITE,
+                arg1: 5,
+                arg2: 0,
+            })
+        );
+
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::WRITE, 5, 0, 0);
+        yieldk();
+        Pin::new(&mut console_write_task).resume();
+
+        assert!(!console_write_client.is_active());
+        assert!(console_write_client.has_message());
+        assert_eq!(
+            console_write_client
+                .reap_bytes_written_message()
+                .map(|b| b.count()),
+            Ok(5)
+        );
+    }
+
+    #[test]
+    fn write_is_busy_until_reaped() {
+        let kernel = Kernel::new();
+        let console_write = ConsoleWrite::new();
+        let console_write_client = ConsoleWriteClient::new();
+        let mut console_write_task = console_write.get_task();
+
+        assert_eq!(
+            console_write_client.initiate_write(&[0; 65]),
+            Err(Error::EINVAL)
+        );
+
+        console_write_client.initiate_write(b"a").unwrap();
+        assert_eq!(console_write_client.initiate_write(b"b"), Err(Error::EBUSY));
+
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::WRITE, 1, 0, 0);
+        yieldk();
+        Pin::new(&mut console_write_task).resume();
+
+        // Client message has not been reaped yet
+        assert_eq!(console_write_client.initiate_write(b"b"), Err(Error::EBUSY));
+
+        console_write_client.reap_message();
+        assert_eq!(console_write_client.initiate_write(b"b"), Ok(()));
+    }
+
+    static LONG: [u8; 150] = [b'x'; 150];
+
+    // Completes the chunk handed to the kernel with `written` bytes, or with
+    // an error code. Also used by the tests of the modules that write to the
+    // console.
+    pub(crate) fn complete_chunk(kernel: &Kernel, written: usize) {
+        let console_write = ConsoleWrite::new();
+        let mut console_write_task = console_write.get_task();
+
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::WRITE, written, 0, 0);
+        yieldk();
+        Pin::new(&mut console_write_task).resume();
+    }
+
+    #[test]
+    fn static_write_is_chunked() {
+        let kernel = Kernel::new();
+        let console_write_client = ConsoleWriteClient::new();
+
+        console_write_client.initiate_write_static(&LONG).unwrap();
+
+        for &len in [64, 64, 22].iter() {
+            assert!(console_write_client.is_active());
+            assert!(!console_write_client.has_message());
+            assert_eq!(
+                kernel.allowed(DRIVER_NUM, allow_num::WRITE),
+                Some(vec![b'x'; len])
+            );
+
+            complete_chunk(&kernel, len);
+        }
+
+        assert!(!console_write_client.is_active());
+        assert_eq!(
+            console_write_client.reap_bytes_written_message(),
+            Ok(BytesWritten {
+                count: 150,
+                error: None,
+            })
+        );
+    }
+
+    #[test]
+    fn failed_chunk_reports_partial_write() {
+        let kernel = Kernel::new();
+        let console_write_client = ConsoleWriteClient::new();
+
+        console_write_client.initiate_write_static(&LONG).unwrap();
+        complete_chunk(&kernel, 64);
+        complete_chunk(&kernel, Error::FAIL.code() as usize);
+
+        assert!(!console_write_client.is_active());
+        assert_eq!(
+            console_write_client.reap_bytes_written_message(),
+            Ok(BytesWritten {
+                count: 64,
+                error: Some(Error::FAIL),
+            })
+        );
+    }
+
+    #[test]
+    fn failed_next_chunk_reports_partial_write() {
+        let kernel = Kernel::new();
+        let console_write_client = ConsoleWriteClient::new();
+
+        console_write_client.initiate_write_static(&LONG).unwrap();
+
+        kernel.push_result(
+            SyscallClass::Command,
+            DRIVER_NUM,
+            command_num::WRITE,
+            Err(Error::EBUSY),
+        );
+        complete_chunk(&kernel, 64);
+
+        assert_eq!(
+            console_write_client
+                .reap_bytes_written_message()
+                .map(|b| (b.count(), b.error())),
+            Ok((64, Some(Error::EBUSY)))
+        );
+
+        // The driver is ready for the next write
+        assert_eq!(console_write_client.initiate_write(b"a"), Ok(()));
+    }
+
+    #[test]
+    fn async_write_of_any_length() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let console_write = drivers.console_write;
+        let mut executor = Executor::new(drivers.tasks);
+
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::WRITE, 64, 0, 0);
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::WRITE, 36, 0, 0);
+
+        let buf = [b'y'; 100];
+        let written = executor.block_on(console_write.write(&buf));
+
+        assert_eq!(written.map(|b| b.count()), Ok(100));
+        assert_eq!(
+            kernel.allowed(DRIVER_NUM, allow_num::WRITE),
+            Some(vec![b'y'; 36])
+        );
+    }
+
+    #[test]
+    fn async_write_waits_for_print() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let console_write = drivers.console_write;
+        let mut executor = Executor::new(drivers.tasks);
+
+        println!("printed");
+        assert!(console_write.is_active());
+
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::WRITE, 8, 0, 0);
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::WRITE, 6, 0, 0);
+
+        let written = executor.block_on(console_write.write(b"client"));
+
+        assert_eq!(written.map(|b| b.count()), Ok(6));
+        assert_eq!(
+            kernel.allowed(DRIVER_NUM, allow_num::WRITE),
+            Some(b"client".to_vec())
+        );
+        assert!(print::is_drained());
+    }
+
+    #[test]
+    fn console_write_str() {
+        let mut buf = [0; 8];
+        let mut w = ConsoleWriteStr::new(&mut buf);
+
+        write!(w, "ab{}", 12).unwrap();
+        assert_eq!(w.get_offset(), 4);
+        assert!(write!(w, "too long").is_err());
+        assert_eq!(&buf[..4], b"ab12");
+    }
+}
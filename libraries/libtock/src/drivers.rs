@@ -0,0 +1,75 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::alarm::{Alarm, AlarmClient};
+use crate::button::{Button, ButtonClient};
+use crate::console_read::{ConsoleRead, ConsoleReadClient};
+use crate::console_write::{ConsoleWrite, ConsoleWriteClient};
+use crate::led::Led;
+
+static DRIVERS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+// Driver (task) halves. They are handed to `Executor::new`, which runs the
+// driver tasks.
+pub struct DriverTasks {
+    pub(crate) alarm: Alarm,
+    pub(crate) button: Button,
+    pub(crate) console_read: ConsoleRead,
+    pub(crate) console_write: ConsoleWrite,
+}
+
+// Every driver handle of the process. Client halves are used by user tasks to
+// start operations and to reap the resulting client messages.
+pub struct Drivers {
+    pub tasks: DriverTasks,
+    pub alarm: AlarmClient,
+    pub button: ButtonClient,
+    pub console_read: ConsoleReadClient,
+    pub console_write: ConsoleWriteClient,
+    pub led: Led,
+}
+
+impl Drivers {
+    // Returns `Some` on the first call only. Driver state lives in `static
+    // mut`s, so having exactly one handle per driver is what makes the handles
+    // safe to use. Handles have private fields, so this is the only way for an
+    // app to get one.
+    pub fn take() -> Option<Drivers> {
+        if DRIVERS_TAKEN.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+
+        Some(Drivers {
+            tasks: DriverTasks {
+                alarm: Alarm::new(),
+                button: Button::new(),
+                console_read: ConsoleRead::new(),
+                console_write: ConsoleWrite::new(),
+            },
+            alarm: AlarmClient::new(),
+            button: ButtonClient::new(),
+            console_read: ConsoleReadClient::new(),
+            console_write: ConsoleWriteClient::new(),
+            led: Led::new(),
+        })
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    DRIVERS_TAKEN.store(false, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::syscalls::fake::Kernel;
+
+    #[test]
+    fn drivers_are_taken_once() {
+        let _kernel = Kernel::new();
+
+        assert!(Drivers::take().is_some());
+        assert!(Drivers::take().is_none());
+    }
+}
@@ -1,6 +1,8 @@
 use core::intrinsics;
 use core::ptr;
 
+#[cfg(feature = "heap")]
+use crate::heap;
 use crate::syscalls;
 
 // _start and rust_start are the first two procedures executed when a Tock
@@ -233,6 +235,10 @@ pub unsafe extern "C" fn rust_start(app_start: usize, stacktop: usize, app_heap_
     // tell the kernel the new app heap break
     syscalls::memop(0, app_heap_end).unwrap();
 
+    // `heap::TockAllocator` moves the break further up when it needs to
+    #[cfg(feature = "heap")]
+    heap::init(_app_heap_start, heap_size);
+
     main(0, ptr::null());
 
     loop {}
@@ -0,0 +1,398 @@
+use core::future::Future;
+use core::ops::{Generator, GeneratorState};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use crate::drivers::DriverTasks;
+use crate::futures;
+use crate::result::{Error, Result};
+use crate::syscalls;
+use crate::task::DriverTask;
+use crate::{has_callback_messages, has_client_messages};
+
+pub const MAX_TASKS: usize = 4;
+
+pub const NUM_SOURCES: usize = 4;
+
+// Driver whose task consumed a callback message
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Source {
+    Alarm = 0,
+    Button = 1,
+    ConsoleRead = 2,
+    ConsoleWrite = 3,
+}
+
+pub(crate) const SOURCES: [Source; NUM_SOURCES] = [
+    Source::Alarm,
+    Source::Button,
+    Source::ConsoleRead,
+    Source::ConsoleWrite,
+];
+
+// Runs the driver tasks. `pass` is called once per pass, after the driver
+// tasks, with the drivers that consumed a callback message during the pass.
+// Returns once `pass` returns `true`.
+fn run_drivers<F>(drivers: &DriverTasks, mut pass: F)
+where
+    F: FnMut(&[bool; NUM_SOURCES]) -> bool,
+{
+    let mut alarm_task = drivers.alarm.get_task();
+    let mut button_task = drivers.button.get_task();
+    let mut console_read_task = drivers.console_read.get_task();
+    let mut console_write_task = drivers.console_write.get_task();
+
+    loop {
+        let mut fired = [false; NUM_SOURCES];
+
+        if drivers.alarm.has_message() {
+            Pin::new(&mut alarm_task).resume();
+            fired[Source::Alarm as usize] = true;
+        }
+
+        if drivers.button.has_message() {
+            Pin::new(&mut button_task).resume();
+            fired[Source::Button as usize] = true;
+        }
+
+        if drivers.console_read.has_message() {
+            Pin::new(&mut console_read_task).resume();
+            fired[Source::ConsoleRead as usize] = true;
+        }
+
+        if drivers.console_write.has_message() {
+            Pin::new(&mut console_write_task).resume();
+            fired[Source::ConsoleWrite as usize] = true;
+        }
+
+        if pass(&fired) {
+            return;
+        }
+
+        if !has_callback_messages() {
+            // Before calling yield, we need to *ensure* that there are *no*
+            // incoming callback messages.
+            //
+            // There could however be incoming client messages which the
+            // user tasks might not be interested in at the moment.
+            syscalls::yieldk();
+        }
+    }
+}
+
+pub type Task<'a> = Pin<&'a mut dyn Generator<Yield = (), Return = ()>>;
+
+struct UserTask<'a> {
+    task: Task<'a>,
+    wake: fn() -> bool,
+    started: bool,
+    complete: bool,
+}
+
+impl<'a> UserTask<'a> {
+    fn is_runnable(&self) -> bool {
+        !self.complete && (!self.started || (self.wake)())
+    }
+}
+
+// Cooperative executor. It owns the driver tasks (`Alarm`, `Button`,
+// `ConsoleRead` and `ConsoleWrite`, see `Drivers::take`) and up to `MAX_TASKS`
+// user tasks.
+//
+// A user task is resumed once when the executor starts, and after that only
+// when its wake condition holds. For tasks added with `spawn` the wake
+// condition is `has_client_messages`.
+//
+// `yieldk` is only called when no driver task has an incoming callback message
+// to consume. A user task that is woken, but leaves the message it was woken
+// for unreaped, will be resumed on every pass until the message is reaped.
+pub struct Executor<'a> {
+    drivers: DriverTasks,
+    tasks: [Option<UserTask<'a>>; MAX_TASKS],
+}
+
+impl<'a> Executor<'a> {
+    pub fn new(drivers: DriverTasks) -> Executor<'a> {
+        Executor {
+            drivers,
+            tasks: [None, None, None, None],
+        }
+    }
+
+    pub fn spawn<G>(&mut self, task: Pin<&'a mut G>) -> Result<()>
+    where
+        G: Generator<Yield = (), Return = ()> + 'a,
+    {
+        self.spawn_with_wake(task, has_client_messages)
+    }
+
+    pub fn spawn_with_wake<G>(&mut self, task: Pin<&'a mut G>, wake: fn() -> bool) -> Result<()>
+    where
+        G: Generator<Yield = (), Return = ()> + 'a,
+    {
+        let slot = self
+            .tasks
+            .iter_mut()
+            .find(|t| t.is_none())
+            .ok_or(Error::ENOMEM)?;
+
+        *slot = Some(UserTask {
+            task: task as Task<'a>,
+            wake,
+            started: false,
+            complete: false,
+        });
+
+        Ok(())
+    }
+
+    // Run until every user task has completed.
+    pub fn run(&mut self) {
+        if !self.has_pending_tasks() {
+            return;
+        }
+
+        let Executor { drivers, tasks } = self;
+
+        run_drivers(drivers, |_| {
+            for t in tasks.iter_mut().filter_map(|t| t.as_mut()) {
+                if t.is_runnable() {
+                    t.started = true;
+
+                    if let GeneratorState::Complete(()) = t.task.as_mut().resume() {
+                        t.complete = true;
+                    }
+                }
+            }
+
+            !has_pending_tasks(tasks)
+        });
+    }
+
+    fn has_pending_tasks(&self) -> bool {
+        has_pending_tasks(&self.tasks)
+    }
+
+    // Drive `future` to completion alongside the driver tasks. The future is
+    // polled once at the start, and after that only when a driver whose
+    // callback message it is waiting on (see `futures::wait_for`) has fired.
+    // Spawned user tasks are not resumed.
+    pub fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        let mut future = future;
+        // `future` is shadowed and never moved again
+        let mut future = unsafe { Pin::new_unchecked(&mut future) };
+
+        let waker = unsafe { Waker::from_raw(waker_clone(core::ptr::null())) };
+        let mut cx = Context::from_waker(&waker);
+
+        let mut output = None;
+
+        WOKEN.store(true, Ordering::Relaxed);
+
+        run_drivers(&self.drivers, |fired| {
+            for (i, f) in fired.iter().enumerate() {
+                if *f {
+                    futures::wake(SOURCES[i]);
+                }
+            }
+
+            if WOKEN.swap(false, Ordering::Relaxed) {
+                if let Poll::Ready(o) = future.as_mut().poll(&mut cx) {
+                    output = Some(o);
+                }
+            }
+
+            output.is_some()
+        });
+
+        output.unwrap()
+    }
+}
+
+fn has_pending_tasks(tasks: &[Option<UserTask>]) -> bool {
+    tasks.iter().filter_map(|t| t.as_ref()).any(|t| !t.complete)
+}
+
+// Set by the `block_on` waker. Checked and cleared before every poll.
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake, waker_drop);
+
+fn waker_clone(_: *const ()) -> RawWaker {
+    RawWaker::new(core::ptr::null(), &WAKER_VTABLE)
+}
+
+fn waker_wake(_: *const ()) {
+    WOKEN.store(true, Ordering::Relaxed);
+}
+
+fn waker_drop(_: *const ()) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::drivers::Drivers;
+    use crate::syscalls::fake::{Kernel, SyscallClass, SyscallRecord};
+    use crate::task::DriverTaskClient;
+    use crate::time::Duration;
+
+    #[test]
+    fn task_is_woken_by_client_message() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let alarm = drivers.alarm;
+        let mut resumed = 0;
+
+        {
+            let mut task = || {
+                resumed += 1;
+
+                let _timeout = alarm.oneshot(Duration::from_ticks(100)).unwrap();
+
+                loop {
+                    if alarm.has_message() {
+                        break;
+                    } else {
+                        yield;
+                    }
+                }
+
+                resumed += 1;
+                alarm.reap_message();
+            };
+
+            // Delivered by the `yieldk` made after the task's first resume
+            kernel.schedule_upcall(0, 0, 100, 100, 0);
+
+            let mut executor = Executor::new(drivers.tasks);
+            executor.spawn(Pin::new(&mut task)).unwrap();
+            executor.run();
+        }
+
+        assert_eq!(resumed, 2);
+        assert!(!has_client_messages());
+    }
+
+    fn idle_task() -> impl Generator<Yield = (), Return = ()> + Unpin {
+        || {
+            yield;
+        }
+    }
+
+    #[test]
+    fn spawn_is_bounded() {
+        let _kernel = Kernel::new();
+
+        let mut tasks = [
+            idle_task(),
+            idle_task(),
+            idle_task(),
+            idle_task(),
+            idle_task(),
+        ];
+        let (first, rest) = tasks.split_at_mut(MAX_TASKS);
+
+        let mut executor = Executor::new(Drivers::take().unwrap().tasks);
+        for t in first.iter_mut() {
+            assert_eq!(executor.spawn(Pin::new(t)), Ok(()));
+        }
+        assert_eq!(executor.spawn(Pin::new(&mut rest[0])), Err(Error::ENOMEM));
+    }
+
+    #[test]
+    fn block_on_driver_futures() {
+        let kernel = Kernel::new();
+        kernel.set_result(SyscallClass::Command, 0, 1, Ok(1000));
+        kernel.set_result(SyscallClass::Command, 0, 2, Ok(50));
+
+        kernel.schedule_upcall(1, 1, 3, 0, 0);
+        kernel.schedule_upcall(0, 0, 60, 60, 0);
+
+        let drivers = Drivers::take().unwrap();
+        let alarm = drivers.alarm;
+        let console_write = drivers.console_write;
+        let mut executor = Executor::new(drivers.tasks);
+
+        let now = executor.block_on(async {
+            console_write.write(b"abc").await.unwrap();
+            alarm
+                .sleep(alarm.duration_from_ms(10).unwrap())
+                .await
+                .unwrap()
+                .get_now()
+        });
+
+        assert_eq!(now, 60);
+        assert!(kernel.syscalls().contains(&SyscallRecord::Command {
+            major: 0,
+            minor: 4,
+            arg1: 60,
+            arg2: 0,
+        }));
+    }
+
+    #[test]
+    fn block_on_ready_future_does_not_yield() {
+        let kernel = Kernel::new();
+        let mut executor = Executor::new(Drivers::take().unwrap().tasks);
+
+        assert_eq!(executor.block_on(async { 42 }), 42);
+        assert!(!kernel.syscalls().contains(&SyscallRecord::Yield));
+    }
+
+    #[test]
+    fn select_keeps_messages_of_other_clients() {
+        let kernel = Kernel::new();
+        kernel.schedule_upcall(1, 1, 1, 0, 0);
+        kernel.schedule_upcall(0, 0, 5, 5, 0);
+
+        let drivers = Drivers::take().unwrap();
+        let alarm = drivers.alarm;
+        let console_write = drivers.console_write;
+        let _timeout = alarm.oneshot(Duration::from_ticks(5)).unwrap();
+        let mut fired = [0; 2];
+
+        {
+            let mut task = || {
+                console_write.initiate_write(b"a").unwrap();
+
+                fired[0] = select!(alarm, console_write);
+                join!(alarm, console_write);
+                fired[1] = select!(alarm, console_write);
+            };
+
+            let mut executor = Executor::new(drivers.tasks);
+            executor.spawn(Pin::new(&mut task)).unwrap();
+            executor.run();
+        }
+
+        assert_eq!(fired, [1, 0]);
+        assert!(alarm.has_message());
+        assert!(console_write.has_message());
+    }
+
+    #[test]
+    fn select_future() {
+        let kernel = Kernel::new();
+        kernel.schedule_upcall(1, 1, 1, 0, 0);
+
+        let drivers = Drivers::take().unwrap();
+        let alarm = drivers.alarm;
+        let console_write = drivers.console_write;
+        let mut executor = Executor::new(drivers.tasks);
+
+        let fired = executor.block_on(async {
+            console_write.initiate_write(b"a").unwrap();
+            alarm.initiate().unwrap();
+
+            let clients: [&dyn DriverTaskClient; 2] = [&alarm, &console_write];
+            futures::select(&clients).await
+        });
+
+        assert_eq!(fired, 1);
+        assert!(console_write.has_message());
+    }
+}
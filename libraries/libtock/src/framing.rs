@@ -0,0 +1,598 @@
+use crate::console_read::ReceiveClient;
+use crate::console_write::{BytesWritten, ConsoleWriteClient};
+use crate::result::{Error, Result};
+
+// Framing of binary packets for transport over the console, with COBS or
+// SLIP. Frames start and end with the delimiter of the codec, so a receiver
+// that missed the start of a frame, or got corrupted bytes, picks up again at
+// the next delimiter.
+//
+// With a CRC, `crc16` of the packet is appended, little endian, before
+// encoding, and checked and removed again by the decoder.
+//
+// Decoding is a byte at a time, so frames can arrive split over any number of
+// console reads, see `Decoder::read_packet`.
+pub const MAX_PACKET_LEN: usize = 128;
+
+const CRC_LEN: usize = 2;
+
+// Largest encoded frame, SLIP with every byte escaped and a CRC
+pub const MAX_FRAME_LEN: usize = 2 * (MAX_PACKET_LEN + CRC_LEN) + 2;
+
+mod slip {
+    pub const END: u8 = 0xc0;
+    pub const ESC: u8 = 0xdb;
+    pub const ESC_END: u8 = 0xdc;
+    pub const ESC_ESC: u8 = 0xdd;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Codec {
+    // Consistent overhead byte stuffing, frames are delimited by 0
+    Cobs,
+    // RFC 1055, frames are delimited by 0xc0
+    Slip,
+}
+
+impl Codec {
+    fn delimiter(self) -> u8 {
+        match self {
+            Codec::Cobs => 0,
+            Codec::Slip => slip::END,
+        }
+    }
+}
+
+// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xffff
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, &b| {
+        (0..8).fold(crc ^ (u16::from(b) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+// Output of an encoder, with room checks
+struct FrameWriter<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> FrameWriter<'a> {
+    fn push(&mut self, byte: u8) -> Result<()> {
+        if self.len == self.out.len() {
+            return Err(Error::ESIZE);
+        }
+
+        self.out[self.len] = byte;
+        self.len += 1;
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Encoder {
+    codec: Codec,
+    crc: bool,
+}
+
+impl Encoder {
+    pub fn new(codec: Codec) -> Encoder {
+        Encoder { codec, crc: false }
+    }
+
+    pub fn with_crc(codec: Codec) -> Encoder {
+        Encoder { codec, crc: true }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    // Encodes `packet` as a frame in `out`. Returns the length of the frame.
+    //
+    // `Err(Error::EINVAL)` if `packet` is longer than `MAX_PACKET_LEN`,
+    // `Err(Error::ESIZE)` if the frame does not fit in `out`.
+    pub fn encode(&self, packet: &[u8], out: &mut [u8]) -> Result<usize> {
+        if packet.len() > MAX_PACKET_LEN {
+            return Err(Error::EINVAL);
+        }
+
+        let crc = crc16(packet).to_le_bytes();
+        let crc: &[u8] = if self.crc { &crc } else { &[] };
+
+        let mut w = FrameWriter { out, len: 0 };
+        let bytes = packet.iter().chain(crc.iter()).cloned();
+
+        match self.codec {
+            Codec::Cobs => encode_cobs(bytes, &mut w)?,
+            Codec::Slip => encode_slip(bytes, &mut w)?,
+        }
+
+        Ok(w.len)
+    }
+
+    // Encodes `packet` and writes the frame to the console
+    pub async fn write_packet(
+        &self,
+        console_write: &ConsoleWriteClient,
+        packet: &[u8],
+    ) -> Result<BytesWritten> {
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = self.encode(packet, &mut frame)?;
+
+        console_write.write(&frame[..len]).await
+    }
+}
+
+// Each block is a code byte, the offset of the next zero, followed by the
+// bytes before it. A code of 0xff is a block of 254 bytes without a zero.
+fn encode_cobs<I>(bytes: I, w: &mut FrameWriter<'_>) -> Result<()>
+where
+    I: Iterator<Item = u8>,
+{
+    w.push(0)?;
+
+    let mut code_at = w.len;
+    let mut code = 1;
+    w.push(0)?;
+
+    for b in bytes {
+        if b != 0 {
+            w.push(b)?;
+            code += 1;
+        }
+
+        if b == 0 || code == 0xff {
+            w.out[code_at] = code;
+            code_at = w.len;
+            code = 1;
+            w.push(0)?;
+        }
+    }
+
+    w.out[code_at] = code;
+    w.push(0)
+}
+
+fn encode_slip<I>(bytes: I, w: &mut FrameWriter<'_>) -> Result<()>
+where
+    I: Iterator<Item = u8>,
+{
+    w.push(slip::END)?;
+
+    for b in bytes {
+        match b {
+            slip::END => {
+                w.push(slip::ESC)?;
+                w.push(slip::ESC_END)?;
+            }
+            slip::ESC => {
+                w.push(slip::ESC)?;
+                w.push(slip::ESC_ESC)?;
+            }
+            _ => w.push(b)?,
+        }
+    }
+
+    w.push(slip::END)
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    // Between frames, or at the start of a frame
+    Idle,
+    // COBS: bytes left in the current block, and its code
+    Block(u8, u8),
+    // SLIP: inside a frame, after ESC or not
+    Data,
+    Escape,
+    // After an error, until the next delimiter
+    Discard,
+}
+
+pub struct Decoder {
+    codec: Codec,
+    crc: bool,
+    state: State,
+    buf: [u8; MAX_PACKET_LEN + CRC_LEN],
+    len: usize,
+    // Length of the last packet
+    packet_len: usize,
+    errors: usize,
+}
+
+impl Decoder {
+    pub fn new(codec: Codec) -> Decoder {
+        Decoder {
+            codec,
+            crc: false,
+            state: State::Idle,
+            buf: [0; MAX_PACKET_LEN + CRC_LEN],
+            len: 0,
+            packet_len: 0,
+            errors: 0,
+        }
+    }
+
+    pub fn with_crc(codec: Codec) -> Decoder {
+        Decoder {
+            crc: true,
+            ..Decoder::new(codec)
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    // Last packet decoded by `feed`
+    pub fn packet(&self) -> &[u8] {
+        &self.buf[..self.packet_len]
+    }
+
+    // Frames dropped because they were corrupt, too long, or failed the CRC
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    // Drops the frame being decoded
+    pub fn reset(&mut self) {
+        self.state = State::Idle;
+        self.len = 0;
+    }
+
+    // Decodes one byte. `Some(Ok(len))` once a packet of `len` bytes is
+    // complete, see `packet`. `Some(Err(..))` when a frame is dropped. Unless
+    // it was dropped at its delimiter, bytes up to the next delimiter are then
+    // skipped.
+    //
+    // `Error::ESIZE` if the frame is too long, `Error::FAIL` if it is corrupt
+    // or fails the CRC.
+    pub fn feed(&mut self, byte: u8) -> Option<Result<usize>> {
+        if byte == self.codec.delimiter() {
+            return self.end_of_frame();
+        }
+
+        let res = match (self.codec, self.state) {
+            (_, State::Discard) => return None,
+            (Codec::Cobs, State::Idle) => {
+                self.state = State::Block(byte - 1, byte);
+                Ok(())
+            }
+            (Codec::Cobs, State::Block(0, code)) => {
+                // The previous block ended in a zero, unless it was a full one
+                let res = if code == 0xff { Ok(()) } else { self.push(0) };
+
+                self.state = State::Block(byte - 1, byte);
+                res
+            }
+            (Codec::Cobs, State::Block(left, code)) => {
+                self.state = State::Block(left - 1, code);
+                self.push(byte)
+            }
+            (Codec::Slip, State::Escape) => {
+                self.state = State::Data;
+
+                match byte {
+                    slip::ESC_END => self.push(slip::END),
+                    slip::ESC_ESC => self.push(slip::ESC),
+                    _ => Err(Error::FAIL),
+                }
+            }
+            (Codec::Slip, _) => {
+                if byte == slip::ESC {
+                    self.state = State::Escape;
+                    Ok(())
+                } else {
+                    self.state = State::Data;
+                    self.push(byte)
+                }
+            }
+            (Codec::Cobs, _) => Err(Error::FAIL),
+        };
+
+        res.err().map(|e| self.drop_frame(e))
+    }
+
+    fn push(&mut self, byte: u8) -> Result<()> {
+        if self.len == self.buf.len() {
+            return Err(Error::ESIZE);
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn drop_frame(&mut self, e: Error) -> Result<usize> {
+        self.state = State::Discard;
+        self.len = 0;
+        self.errors = self.errors.wrapping_add(1);
+
+        Err(e)
+    }
+
+    // Like `drop_frame`, for a frame that has already ended at a delimiter.
+    // The next byte starts a new frame.
+    fn reject_frame(&mut self, e: Error) -> Result<usize> {
+        self.errors = self.errors.wrapping_add(1);
+
+        Err(e)
+    }
+
+    fn end_of_frame(&mut self) -> Option<Result<usize>> {
+        let state = self.state;
+        let len = self.len;
+        self.reset();
+
+        match state {
+            // Empty frames are only delimiters, like the one at the start of
+            // every frame
+            State::Idle | State::Discard => return None,
+            // Truncated block
+            State::Block(left, _) if left > 0 => return Some(self.reject_frame(Error::FAIL)),
+            State::Escape => return Some(self.reject_frame(Error::FAIL)),
+            _ => {}
+        }
+
+        let len = if self.crc {
+            if len < CRC_LEN {
+                return Some(self.reject_frame(Error::FAIL));
+            }
+
+            let len = len - CRC_LEN;
+            let crc = u16::from_le_bytes([self.buf[len], self.buf[len + 1]]);
+            if crc16(&self.buf[..len]) != crc {
+                return Some(self.reject_frame(Error::FAIL));
+            }
+
+            len
+        } else {
+            len
+        };
+
+        if len > MAX_PACKET_LEN {
+            return Some(self.reject_frame(Error::ESIZE));
+        }
+
+        self.packet_len = len;
+
+        Some(Ok(len))
+    }
+
+    // Decodes bytes received by `receiver` until a packet is complete. Bytes
+    // after the packet stay with `receiver`. Dropped frames are skipped, see
+    // `errors`.
+    //
+    // `Err(Error::EOFF)` if receiving stops first, or the error that stopped
+    // it.
+    pub async fn read_packet(&mut self, receiver: &ReceiveClient) -> Result<&[u8]> {
+        loop {
+            let mut byte = [0];
+
+            while receiver.read(&mut byte) > 0 {
+                if let Some(Ok(_)) = self.feed(byte[0]) {
+                    return Ok(self.packet());
+                }
+            }
+
+            receiver.wait_for_bytes(1).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    use crate::drivers::Drivers;
+    use crate::executor::Executor;
+    use crate::syscalls::fake::Kernel;
+
+    fn encode(encoder: Encoder, packet: &[u8]) -> Vec<u8> {
+        let mut out = [0; MAX_FRAME_LEN];
+        let len = encoder.encode(packet, &mut out).unwrap();
+        out[..len].to_vec()
+    }
+
+    // Packets decoded from `input`, and errors
+    fn decode(decoder: &mut Decoder, input: &[u8]) -> Vec<Result<Vec<u8>>> {
+        let mut packets = Vec::new();
+
+        for &b in input {
+            if let Some(res) = decoder.feed(b) {
+                packets.push(res.map(|_| decoder.packet().to_vec()));
+            }
+        }
+
+        packets
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc16(b""), 0xffff);
+    }
+
+    #[test]
+    fn cobs() {
+        let encoder = Encoder::new(Codec::Cobs);
+
+        assert_eq!(encode(encoder, b""), vec![0, 1, 0]);
+        assert_eq!(
+            encode(encoder, &[0x11, 0x00, 0x00, 0x22]),
+            vec![0, 2, 0x11, 1, 2, 0x22, 0]
+        );
+
+        // One block for a packet without zeros
+        let long = [0x55; MAX_PACKET_LEN];
+        let frame = encode(encoder, &long);
+        assert_eq!(frame[1] as usize, MAX_PACKET_LEN + 1);
+        assert_eq!(frame.len(), MAX_PACKET_LEN + 3);
+
+        let mut decoder = Decoder::new(Codec::Cobs);
+        for packet in [&b""[..], b"\x00", b"a\x00\x00b", &[0xff; 100]].iter() {
+            assert_eq!(
+                decode(&mut decoder, &encode(encoder, packet)),
+                vec![Ok(packet.to_vec())]
+            );
+        }
+    }
+
+    #[test]
+    fn slip() {
+        let encoder = Encoder::new(Codec::Slip);
+        let packet = [1, slip::END, slip::ESC, 2];
+
+        let frame = encode(encoder, &packet);
+        assert_eq!(
+            frame,
+            vec![
+                slip::END,
+                1,
+                slip::ESC,
+                slip::ESC_END,
+                slip::ESC,
+                slip::ESC_ESC,
+                2,
+                slip::END
+            ]
+        );
+
+        let mut decoder = Decoder::new(Codec::Slip);
+        assert_eq!(decode(&mut decoder, &frame), vec![Ok(packet.to_vec())]);
+
+        // A bad escape drops the frame, the next one is decoded
+        let mut input = vec![slip::END, 1, slip::ESC, 3, 4, slip::END];
+        input.extend(encode(encoder, b"ok"));
+        assert_eq!(
+            decode(&mut decoder, &input),
+            vec![Err(Error::FAIL), Ok(b"ok".to_vec())]
+        );
+        assert_eq!(decoder.errors(), 1);
+    }
+
+    #[test]
+    fn crc_and_resync() {
+        for &codec in [Codec::Cobs, Codec::Slip].iter() {
+            let encoder = Encoder::with_crc(codec);
+            let mut decoder = Decoder::with_crc(codec);
+
+            let mut corrupt = encode(encoder, b"hello");
+            corrupt[3] ^= 0x20;
+
+            // Garbage, a corrupted frame, and a good one split over two reads
+            let mut input = vec![0x42, 0x17];
+            input.extend(corrupt);
+            input.extend(encode(encoder, b"world"));
+            let (first, second) = input.split_at(input.len() - 3);
+
+            let mut packets = decode(&mut decoder, first);
+            packets.extend(decode(&mut decoder, second));
+
+            assert_eq!(packets.last(), Some(&Ok(b"world".to_vec())));
+            assert!(packets[..packets.len() - 1].iter().all(|p| p.is_err()));
+        }
+
+        let mut out = [0; 8];
+        assert_eq!(
+            Encoder::new(Codec::Slip).encode(b"too long", &mut out),
+            Err(Error::ESIZE)
+        );
+        assert_eq!(
+            Encoder::new(Codec::Cobs).encode(&[0; MAX_PACKET_LEN + 1], &mut [0; MAX_FRAME_LEN]),
+            Err(Error::EINVAL)
+        );
+
+        // Frames longer than a packet are dropped
+        let mut decoder = Decoder::new(Codec::Slip);
+        let mut input = vec![1; MAX_PACKET_LEN + CRC_LEN + 1];
+        input.push(slip::END);
+        assert_eq!(decode(&mut decoder, &input), vec![Err(Error::ESIZE)]);
+    }
+
+    #[test]
+    fn resync_without_leading_delimiters() {
+        for &codec in [Codec::Cobs, Codec::Slip].iter() {
+            let encoder = Encoder::with_crc(codec);
+            let mut decoder = Decoder::with_crc(codec);
+
+            // Frames that are only terminated by a delimiter
+            let trailing = |packet: &[u8]| encode(encoder, packet)[1..].to_vec();
+
+            let mut input = trailing(b"hello");
+            input[2] ^= 0x20;
+            input.extend(trailing(b"world"));
+
+            assert_eq!(
+                decode(&mut decoder, &input),
+                vec![Err(Error::FAIL), Ok(b"world".to_vec())]
+            );
+        }
+
+        // A truncated block and a truncated escape
+        let mut decoder = Decoder::new(Codec::Cobs);
+        assert_eq!(
+            decode(&mut decoder, &[5, 1, 0, 3, b'o', b'k', 0]),
+            vec![Err(Error::FAIL), Ok(b"ok".to_vec())]
+        );
+
+        let mut decoder = Decoder::new(Codec::Slip);
+        assert_eq!(
+            decode(
+                &mut decoder,
+                &[1, slip::ESC, slip::END, b'o', b'k', slip::END]
+            ),
+            vec![Err(Error::FAIL), Ok(b"ok".to_vec())]
+        );
+        assert_eq!(decoder.errors(), 1);
+    }
+
+    #[test]
+    fn read_packet_across_reads() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let receiver = drivers.console_read.receiver();
+        let mut executor = Executor::new(drivers.tasks);
+
+        let frame = encode(Encoder::with_crc(Codec::Cobs), b"ping");
+        let mut input = frame.clone();
+        input.extend(&frame);
+
+        // Reads only complete once full, extra delimiters are skipped
+        while input.len() % 4 != 0 {
+            input.push(0);
+        }
+
+        receiver
+            .start(Box::leak(vec![0; 32].into_boxed_slice()), 4)
+            .unwrap();
+        for chunk in input.chunks(4) {
+            kernel.schedule_upcall_with_data(1, 2, chunk, 0, chunk.len(), 0);
+        }
+
+        let mut decoder = Decoder::with_crc(Codec::Cobs);
+        let packet = executor
+            .block_on(decoder.read_packet(&receiver))
+            .map(|p| p.to_vec());
+        assert_eq!(packet, Ok(b"ping".to_vec()));
+
+        // The rest of the input stays with the receiver
+        let packet = executor
+            .block_on(decoder.read_packet(&receiver))
+            .map(|p| p.to_vec());
+        assert_eq!(packet, Ok(b"ping".to_vec()));
+        assert_eq!(decoder.errors(), 0);
+    }
+}
@@ -0,0 +1,129 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use crate::executor::{Source, NUM_SOURCES, SOURCES};
+use crate::task::DriverTaskClient;
+
+// Waker registered by a pending future, per driver. When the driver task
+// consumes a callback message, `executor::block_on` wakes the waker registered
+// for that driver.
+static mut WAKERS: [Option<Waker>; NUM_SOURCES] = [None, None, None, None];
+
+pub(crate) fn register(source: Source, waker: &Waker) {
+    unsafe {
+        WAKERS[source as usize] = Some(waker.clone());
+    }
+}
+
+pub(crate) fn wake(source: Source) {
+    unsafe {
+        if let Some(w) = WAKERS[source as usize].take() {
+            w.wake();
+        }
+    }
+}
+
+// Future that is ready once `cond` holds. It is only polled again after the
+// driver task for `source` has consumed a callback message, or after any driver
+// task has for `wait_for_any`.
+pub struct WaitFor<F> {
+    source: Option<Source>,
+    cond: F,
+}
+
+impl<F> Future for WaitFor<F>
+where
+    F: Fn() -> bool + Unpin,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if (self.cond)() {
+            Poll::Ready(())
+        } else {
+            match self.source {
+                Some(source) => register(source, cx.waker()),
+                None => register_all(cx.waker()),
+            }
+            Poll::Pending
+        }
+    }
+}
+
+pub fn wait_for<F>(source: Source, cond: F) -> WaitFor<F>
+where
+    F: Fn() -> bool + Unpin,
+{
+    WaitFor {
+        source: Some(source),
+        cond,
+    }
+}
+
+// For conditions that depend on more than one driver
+pub fn wait_for_any<F>(cond: F) -> WaitFor<F>
+where
+    F: Fn() -> bool + Unpin,
+{
+    WaitFor { source: None, cond }
+}
+
+// Future that is ready once at least one of the clients has a message. Its
+// output is the index of the first client, in slice order, that has a message.
+// Messages of the other clients are left intact.
+pub struct Select<'a> {
+    clients: &'a [&'a dyn DriverTaskClient],
+}
+
+impl<'a> Future for Select<'a> {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<usize> {
+        match self.clients.iter().position(|c| c.has_message()) {
+            Some(i) => Poll::Ready(i),
+            None => {
+                register_all(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub fn select<'a>(clients: &'a [&'a dyn DriverTaskClient]) -> Select<'a> {
+    Select { clients }
+}
+
+// Future that is ready once every client has a message.
+pub struct Join<'a> {
+    clients: &'a [&'a dyn DriverTaskClient],
+}
+
+impl<'a> Future for Join<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.clients.iter().all(|c| c.has_message()) {
+            Poll::Ready(())
+        } else {
+            register_all(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+pub fn join<'a>(clients: &'a [&'a dyn DriverTaskClient]) -> Join<'a> {
+    Join { clients }
+}
+
+// We do not know which drivers a `DriverTaskClient` gets its messages from.
+pub(crate) fn register_all(waker: &Waker) {
+    for s in SOURCES.iter() {
+        register(*s, waker);
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    WAKERS = [None, None, None, None];
+}
@@ -0,0 +1,658 @@
+use core::ops::Generator;
+
+use crate::alarm::DeadlineAlarm;
+use crate::button::{self, ButtonClient, ButtonEventData, ButtonState};
+use crate::futures::wait_for_any;
+use crate::queue::{OverflowPolicy, Queue};
+use crate::result::{library_error, Error, Result};
+use crate::task::DriverTaskClient;
+use crate::time::{Duration, Frequency, Instant};
+
+// Gestures are recognized on top of the pressed and not pressed events of the
+// button driver, see `ButtonClient::gestures`.
+//
+// An edge is only accepted once the button has kept its new state for
+// `GestureConfig::debounce`, and is then taken to have happened at the time of
+// the edge. A press that is held for `long_press` is a `LongPress`, followed by
+// a `HoldRepeat` every `hold_repeat` until the button is released. Otherwise a
+// release is a `Click`, unless the button is pressed again within
+// `double_click`, in which case the second release is a `DoubleClick`.
+//
+// The `Button` task queues timestamped button events for the recognizer while
+// gestures are enabled. The recognizer itself runs in `GestureClient::poll`,
+// which also handles its timers, driven by an internal virtual alarm. Either
+// `wait_gesture` or the task returned by `GestureClient::task` has to poll it.
+
+// Buttons with a higher number are ignored
+pub const MAX_GESTURE_BUTTONS: usize = 8;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Gesture {
+    Click,
+    DoubleClick,
+    LongPress,
+    HoldRepeat,
+}
+
+// `timestamp` is the time the gesture was recognized: the second release of a
+// `DoubleClick`, or the expiry of the timer for the other gestures.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GestureEvent {
+    pub button: usize,
+    pub gesture: Gesture,
+    pub timestamp: Instant,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GestureConfig {
+    pub debounce: Duration,
+    // Longest time from a release to the next press of a double click. A
+    // click is only reported once this has passed. 0 disables double clicks.
+    pub double_click: Duration,
+    pub long_press: Duration,
+    // 0 disables hold repeats
+    pub hold_repeat: Duration,
+}
+
+impl GestureConfig {
+    // 20 ms debounce, 250 ms double click, 600 ms long press and 200 ms hold
+    // repeat. `None` if these do not fit in the tick counter.
+    pub fn new(frequency: Frequency) -> Option<GestureConfig> {
+        Some(GestureConfig {
+            debounce: Duration::from_ms(20, frequency)?,
+            double_click: Duration::from_ms(250, frequency)?,
+            long_press: Duration::from_ms(600, frequency)?,
+            hold_repeat: Duration::from_ms(200, frequency)?,
+        })
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Idle,
+    // `second` is the second press of a double click, `long` a press that
+    // has been reported as a `LongPress`
+    Pressed { second: bool, long: bool },
+    // Waiting for the second press of a double click
+    Released,
+}
+
+#[derive(Copy, Clone)]
+struct Recognizer {
+    // Debounced state of the button
+    pressed: bool,
+    // Time of an edge that has not been accepted yet
+    edge: Option<Instant>,
+    state: State,
+    timer: Option<Instant>,
+}
+
+enum Next {
+    Edge(Instant),
+    Timer(Instant),
+}
+
+impl Recognizer {
+    const fn new() -> Recognizer {
+        Recognizer {
+            pressed: false,
+            edge: None,
+            state: State::Idle,
+            timer: None,
+        }
+    }
+
+    fn on_edge(&mut self, pressed: bool, now: Instant) {
+        if pressed == self.pressed {
+            // The button bounced back before the edge was accepted
+            self.edge = None;
+        } else if self.edge.is_none() {
+            self.edge = Some(now);
+        }
+    }
+
+    // The timer is only handled before a pending edge that happened after it.
+    // An edge that happened first has to be accepted or dropped before the
+    // timer can be handled.
+    fn next(&self) -> Option<Next> {
+        match (self.timer, self.edge) {
+            (Some(timer), Some(edge)) if timer.is_before(edge) => Some(Next::Timer(timer)),
+            (_, Some(edge)) => Some(Next::Edge(edge)),
+            (Some(timer), None) => Some(Next::Timer(timer)),
+            (None, None) => None,
+        }
+    }
+
+    fn next_deadline(&self, config: &GestureConfig) -> Option<Instant> {
+        self.next().map(|next| match next {
+            Next::Edge(edge) => edge.wrapping_add(config.debounce),
+            Next::Timer(timer) => timer,
+        })
+    }
+
+    fn expire<F>(&mut self, config: &GestureConfig, now: Instant, emit: &mut F)
+    where
+        F: FnMut(Gesture, Instant),
+    {
+        while let Some(deadline) = self.next_deadline(config) {
+            if deadline.is_after(now) {
+                return;
+            }
+
+            match self.next() {
+                Some(Next::Edge(edge)) => {
+                    self.edge = None;
+                    self.pressed = !self.pressed;
+                    self.accept_edge(config, edge, emit);
+                }
+                Some(Next::Timer(timer)) => {
+                    self.timer = None;
+                    self.timer_expired(config, timer, emit);
+                }
+                None => return,
+            }
+        }
+    }
+
+    fn accept_edge<F>(&mut self, config: &GestureConfig, at: Instant, emit: &mut F)
+    where
+        F: FnMut(Gesture, Instant),
+    {
+        let long_press = Some(at.wrapping_add(config.long_press));
+
+        match (self.state, self.pressed) {
+            (State::Idle, true) => {
+                self.state = State::Pressed {
+                    second: false,
+                    long: false,
+                };
+                self.timer = long_press;
+            }
+            (State::Released, true) => {
+                self.state = State::Pressed {
+                    second: true,
+                    long: false,
+                };
+                self.timer = long_press;
+            }
+            (State::Pressed { second, long }, false) => {
+                self.state = State::Idle;
+                self.timer = None;
+
+                if long {
+                    // Already reported
+                } else if second {
+                    emit(Gesture::DoubleClick, at);
+                } else if config.double_click.ticks() == 0 {
+                    emit(Gesture::Click, at);
+                } else {
+                    self.state = State::Released;
+                    self.timer = Some(at.wrapping_add(config.double_click));
+                }
+            }
+            // A release without a press, for example of a button that was
+            // held when gestures were enabled
+            _ => {}
+        }
+    }
+
+    fn timer_expired<F>(&mut self, config: &GestureConfig, at: Instant, emit: &mut F)
+    where
+        F: FnMut(Gesture, Instant),
+    {
+        let repeat = if config.hold_repeat.ticks() == 0 {
+            None
+        } else {
+            Some(at.wrapping_add(config.hold_repeat))
+        };
+
+        match self.state {
+            State::Pressed { second, long } => {
+                emit(
+                    if long {
+                        Gesture::HoldRepeat
+                    } else {
+                        Gesture::LongPress
+                    },
+                    at,
+                );
+                self.state = State::Pressed { second, long: true };
+                self.timer = repeat;
+            }
+            State::Released => {
+                emit(Gesture::Click, at);
+                self.state = State::Idle;
+            }
+            State::Idle => {}
+        }
+    }
+}
+
+// Gesture recognition for up to `MAX_GESTURE_BUTTONS` buttons. The recognizer
+// does not read the clock itself: button events are passed in with the time
+// they were received, and `expire` has to be called by `next_deadline`.
+pub struct GestureRecognizer {
+    config: GestureConfig,
+    buttons: [Recognizer; MAX_GESTURE_BUTTONS],
+}
+
+impl GestureRecognizer {
+    pub fn new(config: GestureConfig) -> GestureRecognizer {
+        GestureRecognizer {
+            config,
+            buttons: [Recognizer::new(); MAX_GESTURE_BUTTONS],
+        }
+    }
+
+    pub fn config(&self) -> GestureConfig {
+        self.config
+    }
+
+    // Handles everything that is due at `now` before the event
+    pub fn on_button_event<F>(&mut self, event: ButtonEventData, now: Instant, mut emit: F)
+    where
+        F: FnMut(GestureEvent),
+    {
+        self.expire(now, &mut emit);
+
+        if let Some(button) = self.buttons.get_mut(event.get_num()) {
+            button.on_edge(event.get_state() == ButtonState::Pressed, now);
+        }
+    }
+
+    pub fn expire<F>(&mut self, now: Instant, mut emit: F)
+    where
+        F: FnMut(GestureEvent),
+    {
+        let config = self.config;
+
+        for (num, button) in self.buttons.iter_mut().enumerate() {
+            button.expire(&config, now, &mut |gesture, timestamp| {
+                emit(GestureEvent {
+                    button: num,
+                    gesture,
+                    timestamp,
+                })
+            });
+        }
+    }
+
+    // Earliest time `expire` has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.buttons
+            .iter()
+            .filter_map(|b| b.next_deadline(&self.config))
+            .fold(None, |earliest, deadline| match earliest {
+                Some(earliest) if earliest.is_before(deadline) => Some(earliest),
+                _ => Some(deadline),
+            })
+    }
+}
+
+static mut GESTURES: Option<GestureRecognizer> = None;
+
+// Expires at the next deadline of `GESTURES`
+static mut GESTURE_ALARM: DeadlineAlarm = DeadlineAlarm::new();
+
+static mut GESTURE_MESSAGE: Queue<GestureEvent> = Queue::new();
+
+fn push_gesture(event: GestureEvent) {
+    unsafe {
+        let _ = GESTURE_MESSAGE.push(event);
+    }
+}
+
+// There are button events or an expired timer for `GestureClient::poll`. The
+// wake condition for `GestureClient::task`.
+pub fn is_due() -> bool {
+    unsafe { button::has_gesture_input() || GESTURE_ALARM.is_due() }
+}
+
+// Feeds the queued button events to the recognizer, handles the expired timers
+// and arms the alarm for the next one.
+unsafe fn update() {
+    let gestures = match GESTURES.as_mut() {
+        Some(gestures) => gestures,
+        None => return,
+    };
+
+    // Only events with a time are queued for the recognizer
+    while let Some(event) = button::pop_gesture_input() {
+        if let Some(timestamp) = event.timestamp {
+            let data = ButtonEventData::new(event.button, event.state);
+            gestures.on_button_event(data, timestamp, push_gesture);
+        }
+    }
+
+    // On failure the timers are handled with the next button event
+    let _ = GESTURE_ALARM.update(|now| {
+        gestures.expire(now, push_gesture);
+        gestures.next_deadline()
+    });
+}
+
+fn poll_for_message() -> bool {
+    let gestures = GestureClient::new();
+
+    if is_due() {
+        gestures.poll();
+    }
+
+    gestures.has_message()
+}
+
+pub struct GestureClient(());
+
+impl GestureClient {
+    pub(crate) fn new() -> GestureClient {
+        GestureClient(())
+    }
+
+    // Starts recognizing gestures, with interrupts enabled for every button
+    // reported by `ButtonClient::get_num_buttons`. Enabling gestures again
+    // restarts recognition with the new `config`.
+    pub fn enable(&self, config: GestureConfig) -> Result<()> {
+        let button_client = ButtonClient::new();
+
+        button_client.initiate()?;
+
+        unsafe {
+            GESTURES = Some(GestureRecognizer::new(config));
+            button::set_gesture_input(true);
+        }
+
+        for button in button_client.indices()?.take(MAX_GESTURE_BUTTONS) {
+            button_client.enable_button_interrupt(button)?;
+        }
+
+        Ok(())
+    }
+
+    // Stops recognizing gestures and discards the queued gestures. Button
+    // interrupts are left enabled, as other clients may rely on them.
+    pub fn disable(&self) {
+        unsafe {
+            GESTURES = None;
+            GESTURE_ALARM.release();
+            GESTURE_MESSAGE.clear();
+            button::set_gesture_input(false);
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        unsafe { GESTURES.is_some() }
+    }
+
+    // Recognizes gestures from the queued button events and the expired
+    // timers. Does nothing while gestures are disabled.
+    pub fn poll(&self) {
+        unsafe { update() }
+    }
+
+    // Polls the recognizer whenever `is_due`. It never completes. Spawn it with
+    // `Executor::spawn_with_wake` and `gesture::is_due` to recognize gestures
+    // while no one waits for them.
+    pub fn task(&self) -> impl Generator<Yield = (), Return = ()> {
+        || loop {
+            if is_due() {
+                GestureClient::new().poll();
+            }
+
+            yield;
+        }
+    }
+
+    // Polls the recognizer itself, so it also works without `task`
+    pub async fn wait_gesture(&self) -> Result<GestureEvent> {
+        wait_for_any(poll_for_message).await;
+
+        self.reap_get_gesture()
+    }
+
+    // `Err(Error::ENOMEM)` reports gestures that were dropped under the
+    // `OverflowPolicy::Error` policy. The queued gestures are still available.
+    pub fn reap_get_gesture(&self) -> Result<GestureEvent> {
+        unsafe {
+            if GESTURE_MESSAGE.take_overflow() {
+                return Err(library_error(Error::ENOMEM));
+            }
+
+            GESTURE_MESSAGE
+                .pop()
+                .ok_or_else(|| library_error(Error::EINVAL))
+        }
+    }
+}
+
+impl DriverTaskClient for GestureClient {
+    fn has_message(&self) -> bool {
+        unsafe { !GESTURE_MESSAGE.is_empty() }
+    }
+
+    fn reap_message(&self) {
+        unsafe {
+            GESTURE_MESSAGE.pop();
+        }
+    }
+
+    fn dropped_messages(&self) -> usize {
+        unsafe { GESTURE_MESSAGE.dropped() }
+    }
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        unsafe { GESTURE_MESSAGE.configure(capacity, policy) }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    GESTURES = None;
+    GESTURE_ALARM.forget();
+    GESTURE_MESSAGE = Queue::new();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::ops::Generator;
+    use core::pin::Pin;
+    use std::vec::Vec;
+
+    use crate::alarm::{Alarm, AlarmClient};
+    use crate::button::Button;
+    use crate::syscalls::fake::{Kernel, SyscallClass};
+    use crate::syscalls::yieldk;
+    use crate::task::DriverTask;
+
+    const DEBOUNCE: u32 = 10;
+    const DOUBLE_CLICK: u32 = 50;
+    const LONG_PRESS: u32 = 100;
+    const HOLD_REPEAT: u32 = 30;
+
+    fn config() -> GestureConfig {
+        GestureConfig {
+            debounce: Duration::from_ticks(DEBOUNCE),
+            double_click: Duration::from_ticks(DOUBLE_CLICK),
+            long_press: Duration::from_ticks(LONG_PRESS),
+            hold_repeat: Duration::from_ticks(HOLD_REPEAT),
+        }
+    }
+
+    fn at(ticks: u32) -> Instant {
+        Instant::from_ticks(ticks)
+    }
+
+    struct Harness {
+        recognizer: GestureRecognizer,
+        events: Vec<(usize, Gesture, u32)>,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            Harness {
+                recognizer: GestureRecognizer::new(config()),
+                events: Vec::new(),
+            }
+        }
+
+        fn edge(&mut self, num: usize, pressed: bool, now: u32) {
+            let state = if pressed {
+                ButtonState::Pressed
+            } else {
+                ButtonState::NotPressed
+            };
+            let events = &mut self.events;
+
+            self.recognizer
+                .on_button_event(ButtonEventData::new(num, state), at(now), |e| {
+                    events.push((e.button, e.gesture, e.timestamp.ticks()))
+                });
+        }
+
+        fn expire(&mut self, now: u32) {
+            let events = &mut self.events;
+
+            self.recognizer.expire(at(now), |e| {
+                events.push((e.button, e.gesture, e.timestamp.ticks()))
+            });
+        }
+    }
+
+    #[test]
+    fn click_and_double_click() {
+        let mut h = Harness::new();
+
+        h.edge(0, true, 0);
+        h.edge(0, false, 40);
+        assert_eq!(h.recognizer.next_deadline(), Some(at(40 + DEBOUNCE)));
+
+        // The click is only reported once no second press can follow
+        h.expire(89);
+        assert!(h.events.is_empty());
+        h.expire(90);
+        assert_eq!(h.events, [(0, Gesture::Click, 90)]);
+
+        h.events.clear();
+        h.edge(1, true, 200);
+        h.edge(1, false, 220);
+        h.edge(1, true, 260);
+        h.edge(1, false, 280);
+        h.expire(1000);
+        assert_eq!(h.events, [(1, Gesture::DoubleClick, 280)]);
+        assert_eq!(h.recognizer.next_deadline(), None);
+    }
+
+    #[test]
+    fn long_press_and_hold_repeat() {
+        let mut h = Harness::new();
+
+        h.edge(2, true, 0);
+        h.expire(170);
+        h.edge(2, false, 175);
+        h.expire(1000);
+
+        assert_eq!(
+            h.events,
+            [
+                (2, Gesture::LongPress, 100),
+                (2, Gesture::HoldRepeat, 130),
+                (2, Gesture::HoldRepeat, 160),
+            ]
+        );
+    }
+
+    #[test]
+    fn bounces_are_filtered() {
+        let mut h = Harness::new();
+
+        // Shorter than the debounce time
+        h.edge(0, true, 0);
+        h.edge(0, false, 5);
+        h.expire(500);
+        assert!(h.events.is_empty());
+
+        // A bounce on release. The release is taken to happen at the last
+        // bounce.
+        h.edge(0, true, 1000);
+        h.edge(0, false, 1040);
+        h.edge(0, true, 1042);
+        h.edge(0, false, 1045);
+        h.expire(2000);
+        assert_eq!(h.events, [(0, Gesture::Click, 1045 + DOUBLE_CLICK)]);
+    }
+
+    #[test]
+    fn press_late_in_double_click_window() {
+        let mut h = Harness::new();
+
+        // The second press is only accepted after the double click window has
+        // closed, but it happened within it
+        h.edge(0, true, 0);
+        h.edge(0, false, 20);
+        h.edge(0, true, 65);
+        h.expire(72);
+        h.edge(0, false, 80);
+        h.expire(1000);
+
+        assert_eq!(h.events, [(0, Gesture::DoubleClick, 80)]);
+    }
+
+    #[test]
+    fn gestures_are_timed_by_the_alarm() {
+        const ALARM: usize = 0;
+        const TICK: usize = 2;
+        const BUTTON: usize = 3;
+        const NUM_BUTTONS: usize = 0;
+
+        let kernel = Kernel::new();
+        let alarm = Alarm::new();
+        let button = Button::new();
+        let gestures = GestureClient::new();
+        let mut alarm_task = alarm.get_task();
+        let mut button_task = button.get_task();
+
+        kernel.set_result(SyscallClass::Command, ALARM, 1, Ok(1000));
+        kernel.set_result(SyscallClass::Command, BUTTON, NUM_BUTTONS, Ok(2));
+        kernel.set_result(SyscallClass::Command, ALARM, TICK, Ok(0));
+        gestures.enable(config()).unwrap();
+
+        kernel.schedule_upcall(BUTTON, 0, 1, 1, 0);
+        yieldk();
+        Pin::new(&mut button_task).resume();
+        assert!(is_due());
+        gestures.poll();
+
+        kernel.set_result(SyscallClass::Command, ALARM, TICK, Ok(40));
+        kernel.schedule_upcall(BUTTON, 0, 1, 0, 0);
+        yieldk();
+        Pin::new(&mut button_task).resume();
+        gestures.poll();
+        assert!(!is_due());
+        assert!(!gestures.has_message());
+
+        // The double click window has closed. The gesture alarm is not seen
+        // by other alarm clients.
+        kernel.set_result(SyscallClass::Command, ALARM, TICK, Ok(100));
+        kernel.schedule_upcall(ALARM, 0, 100, 0, 0);
+        yieldk();
+        Pin::new(&mut alarm_task).resume();
+        assert!(!button.has_message());
+        assert!(!AlarmClient::new().has_message());
+        assert!(is_due());
+        gestures.poll();
+
+        assert_eq!(
+            gestures.reap_get_gesture(),
+            Ok(GestureEvent {
+                button: 1,
+                gesture: Gesture::Click,
+                timestamp: at(90),
+            })
+        );
+        assert!(!is_due());
+
+        gestures.disable();
+        assert!(!gestures.is_enabled());
+    }
+}
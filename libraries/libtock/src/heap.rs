@@ -0,0 +1,342 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
+use core::mem;
+use core::ptr;
+
+use crate::memory;
+
+// Heap between the end of .bss and the app break, see `entry_point`. With the
+// `heap` feature `TockAllocator` is the global allocator, so that apps can use
+// the `alloc` crate. When the heap runs out of memory, the break is moved up
+// with `memory::sbrk` to make room for the allocation.
+
+// The break is moved by at least this many bytes at a time
+const MIN_GROW: usize = 512;
+
+const ALIGN: usize = mem::align_of::<FreeBlock>();
+
+// Smallest block that can be put on the free list
+const MIN_BLOCK: usize = mem::size_of::<FreeBlock>();
+
+#[repr(C)]
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct HeapStats {
+    // Bytes in allocated blocks
+    pub used: usize,
+    // Bytes on the free list. Not all of it is usable for a single allocation
+    // if the heap is fragmented.
+    pub free: usize,
+    // Highest `used` so far
+    pub high_water_mark: usize,
+}
+
+fn round_up(n: usize, align: usize) -> usize {
+    (n + align - 1) & !(align - 1)
+}
+
+// Size of the block that holds an allocation of `layout`
+fn block_size(layout: &Layout) -> usize {
+    cmp::max(round_up(layout.size(), ALIGN), MIN_BLOCK)
+}
+
+// First fit allocator over a linked list of free blocks, sorted by address.
+// Adjacent free blocks are merged when memory is freed.
+pub struct Heap {
+    head: *mut FreeBlock,
+    size: usize,
+    used: usize,
+    high_water_mark: usize,
+}
+
+impl Heap {
+    pub const fn new() -> Heap {
+        Heap {
+            head: ptr::null_mut(),
+            size: 0,
+            used: 0,
+            high_water_mark: 0,
+        }
+    }
+
+    // Adds the memory in `start..(start + size)` to the heap. The memory must
+    // not be in use by anything else.
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        let aligned = round_up(start, ALIGN);
+        let end = (start + size) & !(ALIGN - 1);
+
+        if end < aligned + MIN_BLOCK {
+            return;
+        }
+
+        self.size += end - aligned;
+        self.insert(aligned, end - aligned);
+    }
+
+    // Null if there is no free block that fits `layout`
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = block_size(&layout);
+        let align = cmp::max(layout.align(), ALIGN);
+
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut cur = self.head;
+
+        unsafe {
+            while !cur.is_null() {
+                let block_start = cur as usize;
+                let block_end = block_start + (*cur).size;
+
+                // Padding in front of the allocation goes back on the free
+                // list, so it has to fit a block of its own.
+                let mut start = round_up(block_start, align);
+                if start != block_start && start - block_start < MIN_BLOCK {
+                    start = round_up(block_start + MIN_BLOCK, align);
+                }
+                let end = start + size;
+
+                let fits = end <= block_end && {
+                    let rest = block_end - end;
+                    rest == 0 || rest >= MIN_BLOCK
+                };
+
+                if fits {
+                    let next = (*cur).next;
+                    if prev.is_null() {
+                        self.head = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+
+                    if start != block_start {
+                        self.insert(block_start, start - block_start);
+                    }
+                    if end != block_end {
+                        self.insert(end, block_end - end);
+                    }
+
+                    self.used += size;
+                    self.high_water_mark = cmp::max(self.high_water_mark, self.used);
+
+                    return start as *mut u8;
+                }
+
+                prev = cur;
+                cur = (*cur).next;
+            }
+        }
+
+        ptr::null_mut()
+    }
+
+    // `ptr` must have been returned by `allocate` for the same `layout`
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let size = block_size(&layout);
+
+        self.used -= size;
+        self.insert(ptr as usize, size);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            used: self.used,
+            free: self.size - self.used,
+            high_water_mark: self.high_water_mark,
+        }
+    }
+
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut cur = self.head;
+
+        while !cur.is_null() && (cur as usize) < addr {
+            prev = cur;
+            cur = (*cur).next;
+        }
+
+        let mut size = size;
+        let mut next = cur;
+
+        if !cur.is_null() && addr + size == cur as usize {
+            size += (*cur).size;
+            next = (*cur).next;
+        }
+
+        if !prev.is_null() && prev as usize + (*prev).size == addr {
+            (*prev).size += size;
+            (*prev).next = next;
+            return;
+        }
+
+        let block = addr as *mut FreeBlock;
+        ptr::write(block, FreeBlock { size, next });
+
+        if prev.is_null() {
+            self.head = block;
+        } else {
+            (*prev).next = block;
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+static mut HEAP: Heap = Heap::new();
+
+// Called by `entry_point` with the initial heap
+#[cfg(feature = "heap")]
+pub(crate) unsafe fn init(start: usize, size: usize) {
+    HEAP.add_region(start, size);
+}
+
+pub fn stats() -> HeapStats {
+    unsafe { HEAP.stats() }
+}
+
+pub struct TockAllocator;
+
+unsafe impl GlobalAlloc for TockAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = HEAP.allocate(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        // Enough for the allocation, whatever the alignment of the new memory
+        let needed = block_size(&layout) + cmp::max(layout.align(), ALIGN) + MIN_BLOCK;
+        let increment = round_up(cmp::max(needed, MIN_GROW), ALIGN);
+
+        match memory::sbrk(increment as isize) {
+            Ok(old_break) => {
+                HEAP.add_region(old_break.as_usize(), increment);
+                HEAP.allocate(layout)
+            }
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        HEAP.deallocate(ptr, layout);
+    }
+}
+
+#[cfg(all(target_arch = "arm", feature = "heap"))]
+#[global_allocator]
+static ALLOCATOR: TockAllocator = TockAllocator;
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    HEAP = Heap::new();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use crate::syscalls::fake::{Kernel, SyscallClass, SyscallRecord};
+
+    const SBRK: u32 = 1;
+
+    fn heap(mem: &mut Vec<usize>) -> Heap {
+        let mut heap = Heap::new();
+        unsafe {
+            heap.add_region(
+                mem.as_mut_ptr() as usize,
+                mem.len() * mem::size_of::<usize>(),
+            );
+        }
+        heap
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn allocate_and_free() {
+        let mut mem = vec![0; 64];
+        let mut heap = heap(&mut mem);
+        let total = heap.stats().free;
+
+        let a = heap.allocate(layout(10, 1));
+        let b = heap.allocate(layout(3 * ALIGN, ALIGN));
+        assert!(!a.is_null() && !b.is_null());
+        assert_eq!(b as usize, a as usize + round_up(10, ALIGN));
+
+        let used = round_up(10, ALIGN) + 3 * ALIGN;
+        assert_eq!(
+            heap.stats(),
+            HeapStats {
+                used,
+                free: total - used,
+                high_water_mark: used,
+            }
+        );
+
+        unsafe {
+            heap.deallocate(a, layout(10, 1));
+            heap.deallocate(b, layout(3 * ALIGN, ALIGN));
+        }
+
+        // Freed blocks were merged back into a single one
+        assert_eq!(heap.stats().free, total);
+        assert_eq!(heap.stats().high_water_mark, used);
+        assert_eq!(heap.allocate(layout(total, ALIGN)) as usize, a as usize);
+        assert!(heap.allocate(layout(1, 1)).is_null());
+    }
+
+    #[test]
+    fn alignment() {
+        let mem = vec![0usize; 128];
+        let start = round_up(mem.as_ptr() as usize, 64) + MIN_BLOCK;
+        let mut heap = Heap::new();
+        unsafe {
+            heap.add_region(start, 512);
+        }
+
+        let a = heap.allocate(layout(32, 64));
+        assert_eq!(a as usize, start - MIN_BLOCK + 64);
+
+        // The padding in front of `a` can still be allocated
+        let b = heap.allocate(layout(1, 1));
+        assert!((b as usize) < a as usize);
+    }
+
+    #[test]
+    fn grows_with_sbrk() {
+        let kernel = Kernel::new();
+        let mut mem = vec![0usize; 1024];
+        let start = mem.as_mut_ptr() as usize;
+
+        unsafe {
+            HEAP.add_region(start, 128);
+        }
+        kernel.set_result(SyscallClass::Memop, SBRK as usize, 0, Ok(start + 128));
+
+        let ptr = unsafe { TockAllocator.alloc(layout(200, 4)) };
+        assert_eq!(ptr as usize, start);
+        assert!(kernel.syscalls().contains(&SyscallRecord::Memop {
+            major: SBRK,
+            arg1: MIN_GROW,
+        }));
+        assert_eq!(stats().used + stats().free, 128 + MIN_GROW);
+
+        kernel.set_result(
+            SyscallClass::Memop,
+            SBRK as usize,
+            0,
+            Err(crate::result::Error::ENOMEM),
+        );
+        assert!(unsafe { TockAllocator.alloc(layout(4096, 4)) }.is_null());
+    }
+}
@@ -1,13 +1,157 @@
+#[cfg(feature = "heap")]
+use core::alloc::Layout;
 use core::intrinsics;
 use core::panic::PanicInfo;
 
 // Panic handler. Adapted from `panic-abort` crate
+#[cfg(not(feature = "panic_console"))]
 #[no_mangle]
 #[panic_handler]
 pub unsafe extern "C" fn panic_fmt(_info: &PanicInfo) -> ! {
     intrinsics::abort();
 }
 
+// Panic handler that reports the panic message and location over the console
+// before faulting, see `panic_report`.
+#[cfg(feature = "panic_console")]
+#[no_mangle]
+#[panic_handler]
+pub unsafe extern "C" fn panic_fmt(info: &PanicInfo) -> ! {
+    panic_report::write(info);
+
+    intrinsics::abort();
+}
+
+// Out of heap memory, see `heap::TockAllocator`. With the `panic_console`
+// feature, this is reported over the console by the panic handler.
+#[cfg(feature = "heap")]
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!("memory allocation of {} bytes failed", layout.size());
+}
+
+// The driver tasks will not run again once we have panicked, so the report is
+// written with the console syscalls directly, waiting for the write to
+// complete with `yieldk`.
+#[cfg(feature = "panic_console")]
+mod panic_report {
+    use core::cmp;
+    use core::fmt::{self, Write};
+    use core::panic::PanicInfo;
+
+    use crate::console_write::{allow_num, command_num, subscribe_num, DRIVER_NUM};
+    use crate::console_write::{ConsoleWrite, ConsoleWriteClient};
+    use crate::result::{Error, Result};
+    use crate::syscalls;
+    use crate::task::DriverTask;
+
+    static mut PANIC_BUF: [u8; 256] = [0; 256];
+
+    static mut PANIC_WRITE_DONE: bool = false;
+
+    // Set by the first panic. A panic while the report is written, in `write!`
+    // or a syscall, would otherwise start another report.
+    static mut PANICKING: bool = false;
+
+    // Formats into `PANIC_BUF`. A report that does not fit is cut short.
+    struct PanicWriter {
+        len: usize,
+    }
+
+    impl fmt::Write for PanicWriter {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            unsafe {
+                let remainder = &mut PANIC_BUF[self.len..];
+                let n = cmp::min(remainder.len(), s.len());
+
+                remainder[..n].copy_from_slice(&s.as_bytes()[..n]);
+                self.len += n;
+            }
+
+            Ok(())
+        }
+    }
+
+    extern "C" fn panic_write_callback(_: usize, _: usize, _: usize, _: usize) {
+        unsafe {
+            PANIC_WRITE_DONE = true;
+        }
+    }
+
+    unsafe fn wait_for_write() {
+        while !PANIC_WRITE_DONE {
+            syscalls::yieldk();
+        }
+        PANIC_WRITE_DONE = false;
+    }
+
+    // A write that was ongoing when we panicked reads its buffer until it
+    // completes. Its completion is either reported to `panic_write_callback`,
+    // or was already queued for the `ConsoleWrite` task.
+    unsafe fn wait_for_ongoing_write() {
+        if !ConsoleWriteClient::new().is_active() {
+            return;
+        }
+
+        while !PANIC_WRITE_DONE && !ConsoleWrite::new().has_message() {
+            syscalls::yieldk();
+        }
+        PANIC_WRITE_DONE = false;
+    }
+
+    unsafe fn start_write(len: usize) -> Result<usize> {
+        syscalls::allow(
+            DRIVER_NUM,
+            allow_num::WRITE,
+            &PANIC_BUF as *const u8 as *mut u8,
+            len,
+        )
+        .and_then(|_| syscalls::command(DRIVER_NUM, command_num::WRITE, len, 0))
+    }
+
+    pub unsafe fn write(info: &PanicInfo) {
+        if PANICKING {
+            return;
+        }
+        PANICKING = true;
+
+        let mut w = PanicWriter { len: 0 };
+
+        // "panicked at '<message>', <file>:<line>:<column>"
+        let _ = write!(w, "\n{}\n", info);
+
+        if syscalls::subscribe(
+            DRIVER_NUM,
+            subscribe_num::WRITE,
+            panic_write_callback as *const _,
+            0,
+        )
+        .is_err()
+        {
+            return;
+        }
+
+        PANIC_WRITE_DONE = false;
+        wait_for_ongoing_write();
+
+        match start_write(w.len) {
+            Ok(_) => wait_for_write(),
+            Err(Error::EBUSY) => {
+                // A write we did not know of was ongoing. Its completion is now
+                // reported to `panic_write_callback`. The kernel may still be
+                // reading its buffer until then, so our buffer is only allowed
+                // again once it has completed.
+                wait_for_write();
+
+                if start_write(w.len).is_ok() {
+                    wait_for_write();
+                }
+            }
+            Err(_) => (),
+        }
+    }
+}
+
 #[lang = "start"]
 extern "C" fn start<T>(main: fn() -> T, _argc: isize, _argv: *const *const u8) -> i32
 where
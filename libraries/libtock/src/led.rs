@@ -1,3 +1,4 @@
+use crate::led_animation::LedAnimationClient;
 use crate::result::Result;
 use crate::syscalls::command;
 
@@ -10,11 +11,11 @@ mod command_num {
     pub const TOGGLE: usize = 3;
 }
 
-pub struct Led;
+pub struct Led(());
 
 impl Led {
-    pub fn new() -> Led {
-        Led
+    pub(crate) fn new() -> Led {
+        Led(())
     }
 
     pub fn get_num_leds(&self) -> Result<usize> {
@@ -32,4 +33,69 @@ impl Led {
     pub fn toggle(&self, led_num: usize) -> Result<()> {
         unsafe { command(DRIVER_NUM, command_num::TOGGLE, led_num, 0).map(|_| ()) }
     }
+
+    // Patterns that play in the background, see `led_animation`
+    pub fn animations(&self) -> LedAnimationClient {
+        LedAnimationClient::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec;
+
+    use crate::result::Error;
+    use crate::syscalls::fake::{Kernel, SyscallClass, SyscallRecord};
+
+    fn led_command(minor: usize, led_num: usize) -> SyscallRecord {
+        SyscallRecord::Command {
+            major: DRIVER_NUM,
+            minor,
+            arg1: led_num,
+            arg2: 0,
+        }
+    }
+
+    #[test]
+    fn on_off_toggle() {
+        let kernel = Kernel::new();
+        kernel.set_result(
+            SyscallClass::Command,
+            DRIVER_NUM,
+            command_num::NUM_LEDS,
+            Ok(2),
+        );
+
+        let led = Led::new();
+
+        assert_eq!(led.get_num_leds(), Ok(2));
+        assert_eq!(led.on(0), Ok(()));
+        assert_eq!(led.off(1), Ok(()));
+        assert_eq!(led.toggle(0), Ok(()));
+
+        assert_eq!(
+            kernel.syscalls(),
+            vec![
+                led_command(command_num::NUM_LEDS, 0),
+                led_command(command_num::ON, 0),
+                led_command(command_num::OFF, 1),
+                led_command(command_num::TOGGLE, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_led() {
+        let kernel = Kernel::new();
+        kernel.push_result(
+            SyscallClass::Command,
+            DRIVER_NUM,
+            command_num::ON,
+            Err(Error::EINVAL),
+        );
+
+        assert_eq!(Led::new().on(7), Err(Error::EINVAL));
+    }
 }
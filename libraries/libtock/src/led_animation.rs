@@ -0,0 +1,806 @@
+use core::cmp;
+use core::ops::Generator;
+
+use crate::alarm::{AlarmClient, DeadlineAlarm};
+use crate::led::Led;
+use crate::result::{Error, Result};
+use crate::time::{Duration, Frequency, Instant};
+
+// LED patterns that play in the background, see `Led::animations`.
+//
+// Every pattern is a sequence of segments, each of which keeps the LED on or
+// off for some time. The segments are stepped through by an internal virtual
+// alarm. Once it expires, `LedAnimationClient::poll` has to be called to drive
+// the LEDs, usually by the task returned by `LedAnimationClient::task`.
+// Starting a pattern on an LED replaces the pattern that was playing on it.
+
+// LEDs with a higher number can not be animated
+pub const MAX_ANIMATED_LEDS: usize = 8;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Pattern {
+    // Repeats forever
+    Blink {
+        on: Duration,
+        off: Duration,
+    },
+    // Plays `text` once in morse code, with a dot taking `unit`. Characters
+    // other than letters, digits and spaces are skipped.
+    Morse {
+        text: &'static str,
+        unit: Duration,
+    },
+    // Two short beats every `period`
+    Heartbeat {
+        period: Duration,
+    },
+    // Software PWM, on for `duty` percent of every `period`
+    Pwm {
+        duty: u8,
+        period: Duration,
+    },
+    // Software PWM that fades in and out once every `period`
+    Breathe {
+        period: Duration,
+        pwm_period: Duration,
+    },
+}
+
+impl Pattern {
+    // Blinks `hz` times a second. `None` if `hz` is 0 or the frequency of the
+    // tick counter is too low.
+    pub fn blink_hz(hz: u32, frequency: Frequency) -> Option<Pattern> {
+        if hz == 0 {
+            return None;
+        }
+
+        let half_period = Duration::from_us(1_000_000 / (2 * hz), frequency)?;
+        if half_period.ticks() == 0 {
+            return None;
+        }
+
+        Some(Pattern::Blink {
+            on: half_period,
+            off: half_period,
+        })
+    }
+
+    // Whether to turn the LED on, and for how many ticks, in segment `index`.
+    // `None` once the pattern has ended.
+    fn segment(&self, index: usize) -> Option<(bool, u32)> {
+        match *self {
+            Pattern::Blink { on, off } => Some(if index % 2 == 0 {
+                (true, on.ticks())
+            } else {
+                (false, off.ticks())
+            }),
+            Pattern::Morse { text, unit } => morse_segment(text, index)
+                .map(|(on, units)| (on, unit.ticks().saturating_mul(units))),
+            Pattern::Heartbeat { period } => {
+                let beat = period.ticks() / 10;
+
+                Some(match index % 4 {
+                    0 | 2 => (true, beat),
+                    1 => (false, beat),
+                    _ => (false, period.ticks() - 3 * beat),
+                })
+            }
+            Pattern::Pwm { duty, period } => {
+                let duty = u64::from(cmp::min(duty, 100));
+                let on = (u64::from(period.ticks()) * duty / 100) as u32;
+
+                Some(pwm_segment(index, on, period.ticks()))
+            }
+            Pattern::Breathe { period, pwm_period } => {
+                let pwm_period = u64::from(pwm_period.ticks());
+                let period = u64::from(period.ticks());
+                let half = period / 2;
+
+                let on = if half == 0 {
+                    pwm_period / 2
+                } else {
+                    let t = (index / 2) as u64 * pwm_period % period;
+                    let ramp = if t < half { t } else { period - t };
+
+                    pwm_period * cmp::min(ramp, half) / half
+                };
+
+                Some(pwm_segment(index, on as u32, pwm_period as u32))
+            }
+        }
+    }
+
+    // Number of segments and ticks after which the pattern repeats, counted
+    // from a segment whose index is a multiple of the number. Breathe repeats
+    // its PWM period with a shifted phase, which `segment` derives from the
+    // index.
+    fn period(&self) -> Option<(usize, u32)> {
+        let (segments, ticks) = match *self {
+            Pattern::Blink { on, off } => (2, on.ticks().checked_add(off.ticks())?),
+            Pattern::Morse { .. } => return None,
+            Pattern::Heartbeat { period } => (4, period.ticks()),
+            Pattern::Pwm { period, .. } => (2, period.ticks()),
+            Pattern::Breathe { pwm_period, .. } => (2, pwm_period.ticks()),
+        };
+
+        if ticks == 0 {
+            None
+        } else {
+            Some((segments, ticks))
+        }
+    }
+}
+
+fn pwm_segment(index: usize, on: u32, period: u32) -> (bool, u32) {
+    if index % 2 == 0 {
+        (true, on)
+    } else {
+        (false, period - on)
+    }
+}
+
+fn morse_code(c: u8) -> Option<&'static str> {
+    const LETTERS: [&str; 26] = [
+        ".-", "-...", "-.-.", "-..", ".", "..-.", "--.", "....", "..", ".---", "-.-", ".-..", "--",
+        "-.", "---", ".--.", "--.-", ".-.", "...", "-", "..-", "...-", ".--", "-..-", "-.--",
+        "--..",
+    ];
+    const DIGITS: [&str; 10] = [
+        "-----", ".----", "..---", "...--", "....-", ".....", "-....", "--...", "---..", "----.",
+    ];
+
+    match c {
+        b'a'..=b'z' => Some(LETTERS[(c - b'a') as usize]),
+        b'A'..=b'Z' => Some(LETTERS[(c - b'A') as usize]),
+        b'0'..=b'9' => Some(DIGITS[(c - b'0') as usize]),
+        _ => None,
+    }
+}
+
+// Segment `index` of `text`, in units: a dot is 1 unit on and a dash 3. The
+// gap is 1 unit within a letter, 3 between letters and 7 between words.
+fn morse_segment(text: &str, index: usize) -> Option<(bool, u32)> {
+    let mut n = 0;
+    // Gap before the next element, 0 before the first one
+    let mut gap = 0;
+
+    for &c in text.as_bytes() {
+        if c == b' ' {
+            if gap > 0 {
+                gap = 7;
+            }
+            continue;
+        }
+
+        let code = match morse_code(c) {
+            Some(code) => code,
+            None => continue,
+        };
+
+        for &element in code.as_bytes() {
+            if gap > 0 {
+                if n == index {
+                    return Some((false, gap));
+                }
+                n += 1;
+            }
+
+            if n == index {
+                return Some((true, if element == b'-' { 3 } else { 1 }));
+            }
+            n += 1;
+            gap = 1;
+        }
+
+        gap = cmp::max(gap, 3);
+    }
+
+    None
+}
+
+#[derive(Copy, Clone)]
+struct Slot {
+    pattern: Option<Pattern>,
+    // Time to wait, with the LED off, before the first segment
+    delay: Option<Duration>,
+    index: usize,
+    // When the current segment started and when it ends
+    start: Instant,
+    next: Instant,
+    // Level of the current segment, and the level last set on the LED
+    level: bool,
+    applied: Option<bool>,
+}
+
+impl Slot {
+    const fn new() -> Slot {
+        Slot {
+            pattern: None,
+            delay: None,
+            index: 0,
+            start: Instant::from_ticks(0),
+            next: Instant::from_ticks(0),
+            level: false,
+            applied: None,
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        // Segments without a duration are skipped. Patterns never have two of
+        // them in a row, unless they are broken.
+        let mut empty = 0;
+
+        while let Some(pattern) = self.pattern {
+            // Measured from the start of the segment, so that a stall of more
+            // than half the counter range does not look like a segment that
+            // ends in the future
+            let elapsed = now.duration_since(self.start).ticks();
+            let length = self.next.duration_since(self.start).ticks();
+
+            if elapsed < length {
+                return;
+            }
+
+            if let Some(delay) = self.delay.take() {
+                self.level = false;
+                self.advance(delay);
+                continue;
+            }
+
+            // After a stall, the periods that were missed entirely are skipped
+            // at once instead of segment by segment
+            if let Some((segments, period)) = pattern.period() {
+                let missed = (elapsed - length) / period;
+
+                if missed > 0 && self.index % segments == 0 {
+                    let skipped = (missed as usize).wrapping_mul(segments);
+
+                    self.index = self.index.wrapping_add(skipped);
+                    self.next = self
+                        .next
+                        .wrapping_add(Duration::from_ticks(missed * period));
+                }
+            }
+
+            match pattern.segment(self.index) {
+                Some((level, ticks)) => {
+                    self.index = self.index.wrapping_add(1);
+
+                    if ticks == 0 {
+                        empty += 1;
+                        if empty > 1 {
+                            self.stop();
+                        }
+                        self.advance(Duration::from_ticks(0));
+                        continue;
+                    }
+
+                    empty = 0;
+                    self.level = level;
+                    self.advance(Duration::from_ticks(ticks));
+                }
+                None => self.stop(),
+            }
+        }
+    }
+
+    // The next segment starts where the current one ends
+    fn advance(&mut self, length: Duration) {
+        self.start = self.next;
+        self.next = self.next.wrapping_add(length);
+    }
+
+    // The LED is turned off
+    fn stop(&mut self) {
+        self.pattern = None;
+        self.delay = None;
+        self.level = false;
+    }
+}
+
+// Plays patterns on up to `MAX_ANIMATED_LEDS` LEDs. The animator does not
+// drive the LEDs or read the clock itself: `expire` has to be called at
+// `next_deadline`, and reports the LEDs to turn on or off.
+pub struct LedAnimator {
+    slots: [Slot; MAX_ANIMATED_LEDS],
+}
+
+impl LedAnimator {
+    pub const fn new() -> LedAnimator {
+        LedAnimator {
+            slots: [Slot::new(); MAX_ANIMATED_LEDS],
+        }
+    }
+
+    // Starts `pattern` on `led` at `now`, replacing the pattern that was
+    // playing. `Err(Error::EINVAL)` if `led` can not be animated.
+    pub fn start(&mut self, led: usize, pattern: Pattern, now: Instant) -> Result<()> {
+        self.start_after(led, pattern, now, Duration::from_ticks(0))
+    }
+
+    // Like `start`, but the LED is kept off for `delay` first
+    pub fn start_after(
+        &mut self,
+        led: usize,
+        pattern: Pattern,
+        now: Instant,
+        delay: Duration,
+    ) -> Result<()> {
+        let slot = self.slots.get_mut(led).ok_or(Error::EINVAL)?;
+
+        slot.pattern = Some(pattern);
+        slot.delay = Some(delay);
+        slot.index = 0;
+        slot.start = now;
+        slot.next = now;
+
+        Ok(())
+    }
+
+    // The LED is turned off by the next `expire`
+    pub fn stop(&mut self, led: usize) {
+        if let Some(slot) = self.slots.get_mut(led) {
+            slot.stop();
+        }
+    }
+
+    pub fn is_running(&self, led: usize) -> bool {
+        self.slots.get(led).map_or(false, |s| s.pattern.is_some())
+    }
+
+    // Steps the patterns up to `now`. `set` is called for every LED that has
+    // to be turned on or off. An LED that `set` fails for is set again by the
+    // next `expire`.
+    pub fn expire<F>(&mut self, now: Instant, mut set: F)
+    where
+        F: FnMut(usize, bool) -> Result<()>,
+    {
+        for (led, slot) in self.slots.iter_mut().enumerate() {
+            slot.expire(now);
+
+            // Segments that were missed are not played
+            if slot.applied != Some(slot.level)
+                && (slot.pattern.is_some() || slot.applied.is_some())
+                && set(led, slot.level).is_ok()
+            {
+                slot.applied = Some(slot.level);
+            }
+        }
+    }
+
+    // When the next segment of a running pattern starts
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.slots
+            .iter()
+            .filter(|s| s.pattern.is_some())
+            .map(|s| s.next)
+            .fold(None, |earliest, next| match earliest {
+                Some(earliest) if earliest.is_before(next) => Some(earliest),
+                _ => Some(next),
+            })
+    }
+}
+
+impl Default for LedAnimator {
+    fn default() -> LedAnimator {
+        LedAnimator::new()
+    }
+}
+
+static mut LED_ANIMATOR: LedAnimator = LedAnimator::new();
+
+// Expires at the next deadline of `LED_ANIMATOR`
+static mut LED_ANIMATION_ALARM: DeadlineAlarm = DeadlineAlarm::new();
+
+// The animation alarm has expired. The wake condition for
+// `LedAnimationClient::task`.
+pub fn is_due() -> bool {
+    unsafe { LED_ANIMATION_ALARM.is_due() }
+}
+
+// Called when the animation alarm has expired, and after patterns have been
+// started or stopped. Drives the LEDs and arms the alarm for the next segment.
+unsafe fn update() -> Result<()> {
+    let led = Led::new();
+
+    LED_ANIMATION_ALARM.update(|now| {
+        LED_ANIMATOR.expire(now, |num, on| if on { led.on(num) } else { led.off(num) });
+        LED_ANIMATOR.next_deadline()
+    })
+}
+
+pub struct LedAnimationClient(());
+
+impl LedAnimationClient {
+    pub(crate) fn new() -> LedAnimationClient {
+        LedAnimationClient(())
+    }
+
+    // Starts `pattern` on `led`, replacing the pattern that was playing on it.
+    // The first segment is applied right away.
+    pub fn start(&self, led: usize, pattern: Pattern) -> Result<()> {
+        self.start_after(led, pattern, Duration::from_ticks(0))
+    }
+
+    // Like `start`, but the LED is kept off for `delay` first
+    pub fn start_after(&self, led: usize, pattern: Pattern, delay: Duration) -> Result<()> {
+        let now = AlarmClient::new().now()?;
+
+        unsafe {
+            LED_ANIMATOR.start_after(led, pattern, now, delay)?;
+            update()
+        }
+    }
+
+    // Stops the pattern on `led` and turns it off
+    pub fn stop(&self, led: usize) -> Result<()> {
+        unsafe {
+            LED_ANIMATOR.stop(led);
+            update()
+        }
+    }
+
+    pub fn stop_all(&self) -> Result<()> {
+        unsafe {
+            for led in 0..MAX_ANIMATED_LEDS {
+                LED_ANIMATOR.stop(led);
+            }
+            update()
+        }
+    }
+
+    pub fn is_running(&self, led: usize) -> bool {
+        unsafe { LED_ANIMATOR.is_running(led) }
+    }
+
+    // Steps the patterns if the animation alarm has expired
+    pub fn poll(&self) -> Result<()> {
+        if is_due() {
+            unsafe { update() }
+        } else {
+            Ok(())
+        }
+    }
+
+    // Polls the animations whenever the alarm expires. It never completes.
+    // Spawn it with `Executor::spawn_with_wake` and `led_animation::is_due`.
+    pub fn task(&self) -> impl Generator<Yield = (), Return = ()> {
+        || loop {
+            // On failure the LEDs keep their state until the animations are
+            // changed
+            let _ = LedAnimationClient::new().poll();
+
+            yield;
+        }
+    }
+
+    // Lights the LEDs reported by `Led::get_num_leds` one after the other,
+    // each for `step`, and starts over after the last one.
+    // `Err(Error::EINVAL)` if a round does not fit in the tick counter.
+    pub fn chase(&self, step: Duration) -> Result<()> {
+        let num_leds = cmp::min(Led::new().get_num_leds()?, MAX_ANIMATED_LEDS);
+        let steps = |n: usize| {
+            step.ticks()
+                .checked_mul(n as u32)
+                .map(Duration::from_ticks)
+                .ok_or(Error::EINVAL)
+        };
+
+        let pattern = Pattern::Blink {
+            on: step,
+            off: steps(num_leds.saturating_sub(1))?,
+        };
+        let now = AlarmClient::new().now()?;
+
+        unsafe {
+            for led in 0..num_leds {
+                LED_ANIMATOR.start_after(led, pattern, now, steps(led)?)?;
+            }
+            update()
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    LED_ANIMATOR = LedAnimator::new();
+    LED_ANIMATION_ALARM.forget();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::ops::Generator;
+    use core::pin::Pin;
+    use std::vec;
+    use std::vec::Vec;
+
+    use crate::alarm::Alarm;
+    use crate::syscalls::fake::{Kernel, SyscallClass, SyscallRecord};
+    use crate::syscalls::yieldk;
+    use crate::task::DriverTaskClient;
+
+    fn at(ticks: u32) -> Instant {
+        Instant::from_ticks(ticks)
+    }
+
+    fn ticks(ticks: u32) -> Duration {
+        Duration::from_ticks(ticks)
+    }
+
+    // LED changes as `(time, led, on)` for every tick up to `end`
+    fn run(animator: &mut LedAnimator, end: u32) -> Vec<(u32, usize, bool)> {
+        let mut changes = Vec::new();
+
+        for now in 0..=end {
+            animator.expire(at(now), |led, on| {
+                changes.push((now, led, on));
+                Ok(())
+            });
+        }
+
+        changes
+    }
+
+    #[test]
+    fn blink() {
+        let mut animator = LedAnimator::new();
+
+        animator
+            .start(
+                1,
+                Pattern::blink_hz(5, Frequency::from_hz(1000).unwrap()).unwrap(),
+                at(0),
+            )
+            .unwrap();
+
+        assert_eq!(
+            run(&mut animator, 250),
+            [(0, 1, true), (100, 1, false), (200, 1, true)]
+        );
+        assert_eq!(animator.next_deadline(), Some(at(300)));
+    }
+
+    #[test]
+    fn morse() {
+        let mut animator = LedAnimator::new();
+
+        // "E T": dot, word gap, dash
+        animator
+            .start(
+                0,
+                Pattern::Morse {
+                    text: "e t",
+                    unit: ticks(10),
+                },
+                at(0),
+            )
+            .unwrap();
+
+        assert_eq!(
+            run(&mut animator, 200),
+            [(0, 0, true), (10, 0, false), (80, 0, true), (110, 0, false)]
+        );
+        assert!(!animator.is_running(0));
+        assert_eq!(animator.next_deadline(), None);
+
+        assert_eq!(morse_segment("ai", 1), Some((false, 1)));
+        assert_eq!(morse_segment("ai", 3), Some((false, 3)));
+    }
+
+    #[test]
+    fn pwm_and_heartbeat() {
+        let mut animator = LedAnimator::new();
+
+        animator
+            .start(
+                0,
+                Pattern::Pwm {
+                    duty: 25,
+                    period: ticks(20),
+                },
+                at(0),
+            )
+            .unwrap();
+        animator
+            .start(
+                1,
+                Pattern::Pwm {
+                    duty: 0,
+                    period: ticks(20),
+                },
+                at(0),
+            )
+            .unwrap();
+        animator
+            .start(2, Pattern::Heartbeat { period: ticks(100) }, at(0))
+            .unwrap();
+
+        assert_eq!(
+            run(&mut animator, 45),
+            [
+                (0, 0, true),
+                (0, 1, false),
+                (0, 2, true),
+                (5, 0, false),
+                (10, 2, false),
+                (20, 0, true),
+                (20, 2, true),
+                (25, 0, false),
+                (30, 2, false),
+                (40, 0, true),
+                (45, 0, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn breathe() {
+        let pattern = Pattern::Breathe {
+            period: ticks(100),
+            pwm_period: ticks(10),
+        };
+
+        let on = |cycle: usize| pattern.segment(2 * cycle).unwrap().1;
+
+        assert_eq!(on(0), 0);
+        assert_eq!(on(2), 4);
+        assert_eq!(on(5), 10);
+        assert_eq!(on(8), 4);
+        assert_eq!(pattern.segment(11), Some((false, 0)));
+    }
+
+    #[test]
+    fn stall() {
+        let mut animator = LedAnimator::new();
+        let mut changes = Vec::new();
+
+        animator
+            .start(
+                0,
+                Pattern::Blink {
+                    on: ticks(10),
+                    off: ticks(10),
+                },
+                at(0),
+            )
+            .unwrap();
+
+        // More than half the counter range later, the missed periods are
+        // skipped and the pattern keeps its phase
+        animator.expire(at(3_000_000_005), |led, on| {
+            changes.push((led, on));
+            Ok(())
+        });
+
+        assert_eq!(changes, [(0, true)]);
+        assert_eq!(animator.next_deadline(), Some(at(3_000_000_010)));
+    }
+
+    #[test]
+    fn patterns_are_interruptible() {
+        let mut animator = LedAnimator::new();
+        let blink = Pattern::Blink {
+            on: ticks(10),
+            off: ticks(10),
+        };
+
+        animator.start(0, blink, at(0)).unwrap();
+        animator.start_after(1, blink, at(0), ticks(5)).unwrap();
+        assert_eq!(
+            run(&mut animator, 12),
+            [(0, 0, true), (0, 1, false), (5, 1, true), (10, 0, false)]
+        );
+
+        animator.start(0, blink, at(13)).unwrap();
+        animator.stop(1);
+        let mut changes = Vec::new();
+        animator.expire(at(13), |led, on| {
+            changes.push((led, on));
+            Ok(())
+        });
+        assert_eq!(changes, [(0, true), (1, false)]);
+
+        assert_eq!(
+            animator.start(MAX_ANIMATED_LEDS, blink, at(0)),
+            Err(Error::EINVAL)
+        );
+    }
+
+    #[test]
+    fn failed_set_is_retried() {
+        let mut animator = LedAnimator::new();
+        let mut changes = Vec::new();
+
+        animator
+            .start(
+                0,
+                Pattern::Blink {
+                    on: ticks(10),
+                    off: ticks(10),
+                },
+                at(0),
+            )
+            .unwrap();
+
+        animator.expire(at(0), |led, on| {
+            changes.push((led, on));
+            Err(Error::FAIL)
+        });
+        for now in 1..3 {
+            animator.expire(at(now), |led, on| {
+                changes.push((led, on));
+                Ok(())
+            });
+        }
+
+        assert_eq!(changes, [(0, true), (0, true)]);
+    }
+
+    #[test]
+    fn chase_is_timed_by_the_alarm() {
+        const ALARM: usize = 0;
+        const TICK: usize = 2;
+        const LED: usize = 2;
+        const NUM_LEDS: usize = 0;
+
+        let kernel = Kernel::new();
+        let alarm = Alarm::new();
+        let mut alarm_task = alarm.get_task();
+        let animations = Led::new().animations();
+
+        kernel.set_result(SyscallClass::Command, LED, NUM_LEDS, Ok(2));
+        kernel.set_result(SyscallClass::Command, ALARM, TICK, Ok(0));
+        animations.chase(ticks(10)).unwrap();
+
+        kernel.set_result(SyscallClass::Command, ALARM, TICK, Ok(10));
+        kernel.schedule_upcall(ALARM, 0, 10, 0, 0);
+        yieldk();
+        Pin::new(&mut alarm_task).resume();
+
+        // The animation alarm is left to the animation task
+        assert!(is_due());
+        assert!(!AlarmClient::new().has_message());
+        let mut animation_task = animations.task();
+        Pin::new(&mut animation_task).resume();
+        assert!(!is_due());
+
+        let leds: Vec<SyscallRecord> = kernel
+            .syscalls()
+            .into_iter()
+            .filter(|s| match s {
+                SyscallRecord::Command { major, minor, .. } => *major == LED && *minor != NUM_LEDS,
+                _ => false,
+            })
+            .collect();
+        let led_command = |minor, arg1| SyscallRecord::Command {
+            major: LED,
+            minor,
+            arg1,
+            arg2: 0,
+        };
+
+        // On is 1, off is 2
+        assert_eq!(
+            leds,
+            vec![
+                led_command(1, 0),
+                led_command(2, 1),
+                led_command(2, 0),
+                led_command(1, 1),
+            ]
+        );
+
+        animations.stop_all().unwrap();
+        assert!(!animations.is_running(0));
+
+        // A round of the chase would overflow the tick counter
+        kernel.set_result(SyscallClass::Command, LED, NUM_LEDS, Ok(3));
+        assert_eq!(
+            animations.chase(ticks(u32::max_value())),
+            Err(Error::EINVAL)
+        );
+        assert!(!animations.is_running(0));
+    }
+}
@@ -6,37 +6,76 @@
     lang_items,
     naked_functions
 )]
+#![cfg_attr(
+    all(target_arch = "arm", feature = "heap"),
+    feature(alloc_error_handler)
+)]
 #![no_std]
 
+// The fake syscall backend, used for host builds, needs collections.
+#[cfg(not(target_arch = "arm"))]
+extern crate std;
+
+#[macro_use]
+mod macros;
+
 pub mod alarm;
 pub mod button;
+pub mod channel;
 pub mod console_read;
 pub mod console_write;
+pub mod drivers;
+#[cfg(target_arch = "arm")]
 pub mod entry_point;
+pub mod executor;
+pub mod framing;
+pub mod futures;
+pub mod gesture;
+pub mod heap;
+#[cfg(target_arch = "arm")]
 pub mod lang_items;
 pub mod led;
+pub mod led_animation;
+pub mod memory;
+pub mod print;
+pub mod queue;
+pub mod readline;
+pub mod result;
+pub mod shell;
 pub mod syscalls;
 pub mod task;
+pub mod time;
+pub mod timeout;
+#[cfg(target_arch = "arm")]
 pub mod unwind_symbols;
-
-mod result;
+pub mod virtual_alarm;
 
 use alarm::{Alarm, AlarmClient};
-use button::{Button, ButtonClient};
+use button::{AnySingleButtonClient, Button, ButtonClient, ButtonEventClient};
 use console_read::{ConsoleRead, ConsoleReadClient};
 use console_write::{ConsoleWrite, ConsoleWriteClient};
+use gesture::GestureClient;
 use task::{DriverTask, DriverTaskClient};
 
+// Reap every queued client message
 pub fn reap_client_messages() {
-    AlarmClient::new().reap_message();
-    ButtonClient::new().reap_message();
-    ConsoleReadClient::new().reap_message();
-    ConsoleWriteClient::new().reap_message();
+    while has_client_messages() {
+        AlarmClient::new().reap_message();
+        ButtonClient::new().reap_message();
+        ButtonEventClient::new().reap_message();
+        AnySingleButtonClient.reap_message();
+        GestureClient::new().reap_message();
+        ConsoleReadClient::new().reap_message();
+        ConsoleWriteClient::new().reap_message();
+    }
 }
 
 pub fn has_client_messages() -> bool {
     AlarmClient::new().has_message()
         || ButtonClient::new().has_message()
+        || ButtonEventClient::new().has_message()
+        || AnySingleButtonClient.has_message()
+        || GestureClient::new().has_message()
         || ConsoleReadClient::new().has_message()
         || ConsoleWriteClient::new().has_message()
 }
@@ -47,3 +86,20 @@ pub fn has_callback_messages() -> bool {
         || ConsoleRead::new().has_message()
         || ConsoleWrite::new().has_message()
 }
+
+// Used by `syscalls::fake::Kernel` to give every host test a clean slate.
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_driver_state() {
+    alarm::reset_state();
+    button::reset_state();
+    channel::reset_state();
+    console_read::reset_state();
+    console_write::reset_state();
+    drivers::reset_state();
+    futures::reset_state();
+    gesture::reset_state();
+    heap::reset_state();
+    led_animation::reset_state();
+    print::reset_state();
+    result::reset_state();
+}
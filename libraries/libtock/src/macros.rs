@@ -0,0 +1,126 @@
+// Combinators for generator based tasks. They expand to `yield`, so they can
+// only be used from within a generator. Sources are `DriverTaskClient`s, and
+// messages are *never* reaped by the combinators, that is left to the caller.
+// `await_timeout!` is the exception, see `timeout::WithTimeout`.
+
+// Wait until `$client` has a message.
+#[macro_export]
+macro_rules! await_message {
+    ($client:expr) => {
+        loop {
+            if $crate::task::DriverTaskClient::has_message(&$client) {
+                break;
+            } else {
+                yield;
+            }
+        }
+    };
+}
+
+// Wait until at least one of the clients has a message. Evaluates to the index
+// of the first client, in argument order, that has a message. Messages of the
+// other clients are left intact.
+//
+//     match select!(console_read_client, button_client.pressed()) {
+//         0 => { /* console read complete */ }
+//         _ => { /* button pressed */ }
+//     }
+#[macro_export]
+macro_rules! select {
+    ($($client:expr),+ $(,)?) => {
+        loop {
+            let mut i = 0;
+            let mut fired = None;
+            $(
+                if fired.is_none() && $crate::task::DriverTaskClient::has_message(&$client) {
+                    fired = Some(i);
+                }
+                i += 1;
+            )+
+            let _ = i;
+
+            match fired {
+                Some(i) => break i,
+                None => yield,
+            }
+        }
+    };
+}
+
+// Wait until every client has a message.
+#[macro_export]
+macro_rules! join {
+    ($($client:expr),+ $(,)?) => {
+        loop {
+            if true $(&& $crate::task::DriverTaskClient::has_message(&$client))+ {
+                break;
+            } else {
+                yield;
+            }
+        }
+    };
+}
+
+// Wait until the `WithTimeout` returned by `timeout::with_timeout` has
+// finished. Evaluates to its result.
+//
+//     let mut read = with_timeout(duration, console_read_client.read_op(5))?;
+//     match await_timeout!(read) {
+//         Ok(Timeout::Completed(buf)) => { /* buf.as_bytes() */ }
+//         Ok(Timeout::TimedOut) => { /* read was aborted */ }
+//         Err(e) => { /* ... */ }
+//     }
+#[macro_export]
+macro_rules! await_timeout {
+    ($with_timeout:expr) => {
+        loop {
+            match $crate::timeout::WithTimeout::check(&mut $with_timeout) {
+                Some(res) => break res,
+                None => yield,
+            }
+        }
+    };
+}
+
+// Formatted output to the console, see `print` for how it is buffered. These
+// never yield, and can be used from anywhere.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::print::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($fmt:expr) => {
+        $crate::print!(concat!($fmt, "\n"))
+    };
+    ($fmt:expr, $($arg:tt)*) => {
+        $crate::print!(concat!($fmt, "\n"), $($arg)*)
+    };
+}
+
+// Like `print!`, but never drops or truncates the message
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => {
+        $crate::print::_eprint(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => {
+        $crate::eprint!("\n")
+    };
+    ($fmt:expr) => {
+        $crate::eprint!(concat!($fmt, "\n"))
+    };
+    ($fmt:expr, $($arg:tt)*) => {
+        $crate::eprint!(concat!($fmt, "\n"), $($arg)*)
+    };
+}
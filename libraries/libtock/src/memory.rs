@@ -0,0 +1,214 @@
+use crate::result::{library_error, Error, Result};
+use crate::syscalls;
+
+// Where the process lives, as reported by the kernel through `memop`.
+//
+//     +--------------+ <- memory().end
+//     | Grant        |
+//     +--------------+ <- grant_start()
+//     | Unused       |
+//     +--------------+ <- app break, see `app_break`
+//     | Heap         |
+//     | ...          |
+//     | Stack        |
+//     +--------------+ <- memory().start
+//
+// `flash()` covers the whole app in flash, including the writeable flash
+// regions, see `writeable_flash_region`.
+
+mod memop_num {
+    pub const BRK: u32 = 0;
+    pub const SBRK: u32 = 1;
+    pub const MEMORY_START: u32 = 2;
+    pub const MEMORY_END: u32 = 3;
+    pub const FLASH_START: u32 = 4;
+    pub const FLASH_END: u32 = 5;
+    pub const GRANT_START: u32 = 6;
+    pub const WRITEABLE_FLASH_REGIONS: u32 = 7;
+    pub const WRITEABLE_FLASH_REGION_START: u32 = 8;
+    pub const WRITEABLE_FLASH_REGION_END: u32 = 9;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Address(usize);
+
+impl Address {
+    pub const fn new(addr: usize) -> Address {
+        Address(addr)
+    }
+
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.0 as *const u8
+    }
+}
+
+// Addresses in `start..end`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Region {
+    pub start: Address,
+    pub end: Address,
+}
+
+impl Region {
+    pub fn len(&self) -> usize {
+        self.end.0.saturating_sub(self.start.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, addr: Address) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+fn address(major: u32, arg1: usize) -> Result<Address> {
+    unsafe { syscalls::memop_address(major, arg1).map(Address) }
+}
+
+// RAM of the process, including the grant region
+pub fn memory() -> Result<Region> {
+    Ok(Region {
+        start: address(memop_num::MEMORY_START, 0)?,
+        end: address(memop_num::MEMORY_END, 0)?,
+    })
+}
+
+pub fn flash() -> Result<Region> {
+    Ok(Region {
+        start: address(memop_num::FLASH_START, 0)?,
+        end: address(memop_num::FLASH_END, 0)?,
+    })
+}
+
+// Lowest address of the kernel owned grant region, the break can not be moved
+// past it
+pub fn grant_start() -> Result<Address> {
+    address(memop_num::GRANT_START, 0)
+}
+
+pub fn writeable_flash_regions() -> Result<usize> {
+    unsafe { syscalls::memop(memop_num::WRITEABLE_FLASH_REGIONS, 0) }
+}
+
+// `Err(Error::EINVAL)` if there is no region `index`
+pub fn writeable_flash_region(index: usize) -> Result<Region> {
+    if index >= writeable_flash_regions()? {
+        return Err(library_error(Error::EINVAL));
+    }
+
+    Ok(Region {
+        start: address(memop_num::WRITEABLE_FLASH_REGION_START, index)?,
+        end: address(memop_num::WRITEABLE_FLASH_REGION_END, index)?,
+    })
+}
+
+// Current app break
+pub fn app_break() -> Result<Address> {
+    address(memop_num::SBRK, 0)
+}
+
+// Moves the app break to `addr`.
+//
+// Unsafe because moving the break down takes memory away from whatever is
+// using it, such as the stack or the heap of `heap::TockAllocator`. The caller
+// must make sure that nothing is left between the new and the old break.
+pub unsafe fn brk(addr: Address) -> Result<()> {
+    address(memop_num::BRK, addr.0).map(|_| ())
+}
+
+// Moves the app break by `increment` bytes, which can be negative. Returns the
+// previous break.
+//
+// Unsafe for the same reason as `brk`, when `increment` is negative.
+pub unsafe fn sbrk(increment: isize) -> Result<Address> {
+    address(memop_num::SBRK, increment as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::syscalls::fake::{Kernel, SyscallClass, SyscallRecord};
+
+    fn set_memop(kernel: &Kernel, major: u32, res: Result<usize>) {
+        kernel.set_result(SyscallClass::Memop, major as usize, 0, res);
+    }
+
+    #[test]
+    fn layout() {
+        let kernel = Kernel::new();
+
+        set_memop(&kernel, memop_num::MEMORY_START, Ok(0x2000_4000));
+        set_memop(&kernel, memop_num::MEMORY_END, Ok(0x2000_8000));
+        set_memop(&kernel, memop_num::GRANT_START, Ok(0x2000_7000));
+        set_memop(&kernel, memop_num::FLASH_START, Ok(0x3_0000));
+        set_memop(&kernel, memop_num::FLASH_END, Ok(0x3_8000));
+
+        let memory = memory().unwrap();
+        assert_eq!(memory.len(), 0x4000);
+        assert!(memory.contains(grant_start().unwrap()));
+        assert!(!memory.contains(memory.end));
+        assert_eq!(flash().unwrap().start, Address::new(0x3_0000));
+    }
+
+    #[test]
+    fn high_addresses_are_not_errors() {
+        let kernel = Kernel::new();
+        let top = usize::max_value() - 0xfff;
+
+        set_memop(&kernel, memop_num::MEMORY_START, Ok(top - 0x4000));
+        set_memop(&kernel, memop_num::MEMORY_END, Ok(top));
+
+        assert_eq!(memory().map(|m| m.len()), Ok(0x4000));
+
+        set_memop(&kernel, memop_num::GRANT_START, Err(Error::Unknown(-20)));
+        assert_eq!(grant_start(), Err(Error::Unknown(-20)));
+    }
+
+    #[test]
+    fn writeable_flash_regions_are_checked() {
+        let kernel = Kernel::new();
+
+        set_memop(&kernel, memop_num::WRITEABLE_FLASH_REGIONS, Ok(1));
+        set_memop(
+            &kernel,
+            memop_num::WRITEABLE_FLASH_REGION_START,
+            Ok(0x3_4000),
+        );
+        set_memop(&kernel, memop_num::WRITEABLE_FLASH_REGION_END, Ok(0x3_4800));
+
+        assert_eq!(writeable_flash_region(0).map(|r| r.len()), Ok(0x800));
+        assert_eq!(writeable_flash_region(1), Err(Error::EINVAL));
+    }
+
+    #[test]
+    fn sbrk_returns_previous_break() {
+        let kernel = Kernel::new();
+
+        set_memop(&kernel, memop_num::SBRK, Ok(0x2000_5000));
+
+        assert_eq!(unsafe { sbrk(-16) }, Ok(Address::new(0x2000_5000)));
+        assert_eq!(
+            kernel.syscalls().last(),
+            Some(&SyscallRecord::Memop {
+                major: memop_num::SBRK,
+                arg1: -16isize as usize,
+            })
+        );
+
+        set_memop(&kernel, memop_num::BRK, Err(Error::ENOMEM));
+        assert_eq!(
+            unsafe { brk(Address::new(0x2000_9000)) },
+            Err(Error::ENOMEM)
+        );
+
+        set_memop(&kernel, memop_num::SBRK, Ok(0x2000_4ff0));
+        assert_eq!(app_break(), Ok(Address::new(0x2000_4ff0)));
+    }
+}
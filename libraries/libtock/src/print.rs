@@ -0,0 +1,407 @@
+use core::cmp;
+use core::fmt;
+
+use crate::console_write::{self, ConsoleWrite, ConsoleWriteClient};
+use crate::result::Error;
+use crate::syscalls;
+use crate::task::DriverTask;
+
+// Output written with `print!`, `println!`, `eprint!` and `eprintln!` is
+// buffered here, and drained to the console in the background by the
+// `ConsoleWrite` task, one kernel write buffer sized chunk at a time.
+//
+// While output is draining the console is busy, so
+// `ConsoleWriteClient::initiate_write` returns `Err(Error::EBUSY)` until it has
+// drained, see `is_drained`, and `ConsoleWriteClient::write` waits for it.
+// Printed output that arrives during a client write is held back until the
+// client write completes.
+pub const PRINT_BUF_SIZE: usize = 256;
+
+// Appended to a message cut short by `FullPolicy::Truncate`
+pub const TRUNCATION_MARKER: &[u8] = b"[...]\n";
+
+// What `print!` and `println!` do with a message that does not fit in the
+// buffer.
+//
+// `Drop` discards the whole message. `Truncate` keeps as much of the message as
+// fits, followed by `TRUNCATION_MARKER`. `Wait` runs the console write driver
+// in place, calling `yieldk` while the buffer is full, until the whole message
+// has been buffered. Callbacks of other drivers are queued for their tasks in
+// the meantime.
+//
+// `eprint!` and `eprintln!` always use `Wait`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FullPolicy {
+    Drop,
+    Truncate,
+    Wait,
+}
+
+// Ring buffer of output. Only committed bytes, those of complete messages, are
+// drained, so that a message can still be dropped or truncated while it is
+// being formatted.
+struct OutputBuffer {
+    buf: [u8; PRINT_BUF_SIZE],
+    head: usize,
+    len: usize,
+    committed: usize,
+    // Bytes at `head` that have been handed to the kernel
+    in_flight: usize,
+}
+
+impl OutputBuffer {
+    const fn new() -> OutputBuffer {
+        OutputBuffer {
+            buf: [0; PRINT_BUF_SIZE],
+            head: 0,
+            len: 0,
+            committed: 0,
+            in_flight: 0,
+        }
+    }
+
+    fn free(&self) -> usize {
+        PRINT_BUF_SIZE - self.len
+    }
+
+    // Bytes of the message that is being formatted
+    fn pending(&self) -> usize {
+        self.len - self.committed
+    }
+
+    // Appends as much of `bytes` as fits. Returns the number of bytes appended.
+    fn push(&mut self, bytes: &[u8]) -> usize {
+        let n = cmp::min(bytes.len(), self.free());
+
+        for (i, &b) in bytes[..n].iter().enumerate() {
+            self.buf[(self.head + self.len + i) % PRINT_BUF_SIZE] = b;
+        }
+        self.len += n;
+
+        n
+    }
+
+    // Removes the last `n` pending bytes
+    fn unpush(&mut self, n: usize) {
+        self.len -= cmp::min(n, self.pending());
+    }
+
+    fn commit(&mut self) {
+        self.committed = self.len;
+    }
+
+    // Discards the pending bytes
+    fn rollback(&mut self) {
+        self.len = self.committed;
+    }
+
+    // Committed bytes at `head` that are contiguous in `buf`
+    fn next_chunk(&self) -> &[u8] {
+        let n = cmp::min(self.committed, PRINT_BUF_SIZE - self.head);
+
+        &self.buf[self.head..(self.head + n)]
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.head = (self.head + n) % PRINT_BUF_SIZE;
+        self.len -= n;
+        self.committed -= n;
+    }
+}
+
+static mut PRINT_BUF: OutputBuffer = OutputBuffer::new();
+
+static mut PRINT_FULL_POLICY: FullPolicy = FullPolicy::Truncate;
+
+static mut PRINT_DROPPED: usize = 0;
+
+pub fn set_full_policy(policy: FullPolicy) {
+    unsafe {
+        PRINT_FULL_POLICY = policy;
+    }
+}
+
+pub fn full_policy() -> FullPolicy {
+    unsafe { PRINT_FULL_POLICY }
+}
+
+// All printed output has been written to the console
+pub fn is_drained() -> bool {
+    unsafe { PRINT_BUF.len == 0 }
+}
+
+// Number of times output was lost: messages dropped or truncated because the
+// buffer was full, and chunks that the console failed to write.
+pub fn dropped() -> usize {
+    unsafe { PRINT_DROPPED }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    print_with_policy(args, full_policy());
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    print_with_policy(args, FullPolicy::Wait);
+}
+
+fn print_with_policy(args: fmt::Arguments, policy: FullPolicy) {
+    let _ = fmt::write(&mut Printer { policy }, args);
+
+    unsafe {
+        PRINT_BUF.commit();
+        drain();
+    }
+}
+
+// Hands the next chunk of output to the console, unless there is an ongoing
+// write.
+pub(crate) unsafe fn drain() {
+    while !ConsoleWriteClient::new().is_active() && PRINT_BUF.committed > 0 {
+        match console_write::write_print_chunk(PRINT_BUF.next_chunk()) {
+            Ok(n) => PRINT_BUF.in_flight = n,
+            Err(_) => {
+                // Skip the chunk, so that the console can not stall output
+                // indefinitely
+                let n = PRINT_BUF.next_chunk().len();
+                PRINT_BUF.consume(n);
+                lost();
+            }
+        }
+    }
+}
+
+// The chunk handed out by `drain` has been written, or failed to
+pub(crate) unsafe fn chunk_written(error: Option<Error>) {
+    PRINT_BUF.consume(PRINT_BUF.in_flight);
+    PRINT_BUF.in_flight = 0;
+
+    if error.is_some() {
+        lost();
+    }
+}
+
+unsafe fn lost() {
+    PRINT_DROPPED = PRINT_DROPPED.wrapping_add(1);
+}
+
+// Runs the console write driver in place until draining has freed up space
+unsafe fn wait_for_space() {
+    drain();
+
+    while PRINT_BUF.free() == 0 {
+        if !ConsoleWrite::new().has_message() {
+            syscalls::yieldk();
+        }
+
+        console_write::handle_callback_messages();
+    }
+}
+
+struct Printer {
+    policy: FullPolicy,
+}
+
+impl fmt::Write for Printer {
+    // An error stops the formatting of the rest of the message
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+
+        unsafe {
+            match self.policy {
+                FullPolicy::Drop => {
+                    if PRINT_BUF.free() < bytes.len() {
+                        PRINT_BUF.rollback();
+                        lost();
+                        return Err(fmt::Error);
+                    }
+
+                    PRINT_BUF.push(bytes);
+                    Ok(())
+                }
+                FullPolicy::Truncate => {
+                    if PRINT_BUF.push(bytes) == bytes.len() {
+                        return Ok(());
+                    }
+
+                    // Give back the end of the message to make room for the
+                    // marker. The message is dropped if that is not enough.
+                    let needed = TRUNCATION_MARKER.len().saturating_sub(PRINT_BUF.free());
+                    if PRINT_BUF.pending() < needed {
+                        PRINT_BUF.rollback();
+                    } else {
+                        PRINT_BUF.unpush(needed);
+                        PRINT_BUF.push(TRUNCATION_MARKER);
+                    }
+
+                    lost();
+                    Err(fmt::Error)
+                }
+                FullPolicy::Wait => loop {
+                    let n = PRINT_BUF.push(bytes);
+                    bytes = &bytes[n..];
+
+                    if bytes.is_empty() {
+                        return Ok(());
+                    }
+
+                    // The message does not fit, so it is drained in parts
+                    PRINT_BUF.commit();
+                    wait_for_space();
+                },
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    PRINT_BUF = OutputBuffer::new();
+    PRINT_FULL_POLICY = FullPolicy::Truncate;
+    PRINT_DROPPED = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use crate::console_write::tests::complete_chunk;
+    use crate::console_write::{allow_num, command_num, subscribe_num, DRIVER_NUM};
+    use crate::syscalls::fake::{Kernel, SyscallClass, SyscallRecord};
+    use crate::task::DriverTaskClient;
+
+    fn fill(n: usize) {
+        for _ in 0..(n / 8) {
+            print!("{}", "01234567");
+        }
+    }
+
+    fn written(kernel: &Kernel) -> Vec<u8> {
+        kernel.allowed(DRIVER_NUM, allow_num::WRITE).unwrap()
+    }
+
+    #[test]
+    fn drains_in_the_background() {
+        let kernel = Kernel::new();
+        let console_write_client = ConsoleWriteClient::new();
+
+        println!("hello {}", 42);
+        assert_eq!(written(&kernel), b"hello 42\n");
+
+        // Buffered while the first message is written
+        print!("world");
+        assert_eq!(written(&kernel), b"hello 42\n");
+        assert!(!is_drained());
+
+        complete_chunk(&kernel, 9);
+        assert_eq!(written(&kernel), b"world");
+
+        complete_chunk(&kernel, 5);
+        assert!(is_drained());
+        assert!(!console_write_client.is_active());
+        assert!(!console_write_client.has_message());
+    }
+
+    #[test]
+    fn waits_for_client_writes() {
+        let kernel = Kernel::new();
+        let console_write_client = ConsoleWriteClient::new();
+
+        console_write_client.initiate_write(b"client").unwrap();
+        print!("printed");
+        assert_eq!(written(&kernel), b"client");
+
+        complete_chunk(&kernel, 6);
+        assert_eq!(written(&kernel), b"printed");
+        assert_eq!(
+            console_write_client
+                .reap_bytes_written_message()
+                .map(|b| b.count()),
+            Ok(6)
+        );
+
+        // Busy until the printed output has drained
+        assert_eq!(console_write_client.initiate_write(b"a"), Err(Error::EBUSY));
+        complete_chunk(&kernel, 7);
+        assert_eq!(console_write_client.initiate_write(b"a"), Ok(()));
+    }
+
+    #[test]
+    fn drop_policy() {
+        let _kernel = Kernel::new();
+
+        set_full_policy(FullPolicy::Drop);
+        fill(PRINT_BUF_SIZE - 8);
+        print!("{}{}", "0123", "456789");
+
+        assert_eq!(dropped(), 1);
+        unsafe {
+            assert_eq!(PRINT_BUF.len, PRINT_BUF_SIZE - 8);
+        }
+    }
+
+    #[test]
+    fn truncate_policy() {
+        let _kernel = Kernel::new();
+
+        fill(PRINT_BUF_SIZE - 16);
+        print!("{}", "abcdefghijklmnopqrstuvwxyz");
+
+        assert_eq!(dropped(), 1);
+        unsafe {
+            let buf = &PRINT_BUF.buf[(PRINT_BUF_SIZE - 16)..];
+
+            assert_eq!(PRINT_BUF.len, PRINT_BUF_SIZE);
+            assert_eq!(&buf[..10], b"abcdefghij");
+            assert_eq!(&buf[10..], TRUNCATION_MARKER);
+        }
+
+        // No room for the marker either
+        print!("x");
+        assert_eq!(dropped(), 2);
+    }
+
+    #[test]
+    fn eprint_waits() {
+        let kernel = Kernel::new();
+
+        set_full_policy(FullPolicy::Drop);
+        fill(PRINT_BUF_SIZE);
+
+        // The first chunk was handed out by the first `print!`
+        kernel.schedule_upcall(DRIVER_NUM, subscribe_num::WRITE, 8, 0, 0);
+        for _ in 0..(PRINT_BUF_SIZE / 64) {
+            kernel.schedule_upcall(DRIVER_NUM, subscribe_num::WRITE, 64, 0, 0);
+        }
+
+        eprint!("{}", "abcdefghijklmnopqrstuvwxyz");
+
+        assert_eq!(dropped(), 0);
+        assert!(kernel.syscalls().contains(&SyscallRecord::Yield));
+        unsafe {
+            assert_eq!(PRINT_BUF.len, PRINT_BUF_SIZE - 8 - 64 + 26);
+        }
+    }
+
+    #[test]
+    fn failed_chunks_are_skipped() {
+        let kernel = Kernel::new();
+
+        kernel.push_result(
+            SyscallClass::Command,
+            DRIVER_NUM,
+            command_num::WRITE,
+            Err(Error::FAIL),
+        );
+        print!("lost");
+
+        assert!(is_drained());
+        assert_eq!(dropped(), 1);
+
+        print!("kept");
+        assert_eq!(written(&kernel), b"kept");
+    }
+}
@@ -0,0 +1,234 @@
+use crate::result::{Error, Result};
+
+// Storage reserved for every queue. The capacity of a queue can be configured
+// at runtime to anything from 1 to `QUEUE_CAPACITY`.
+pub const QUEUE_CAPACITY: usize = 8;
+
+// What to do when a message is pushed to a full queue.
+//
+// `DropOldest` makes room by discarding the oldest message. `DropNewest`
+// discards the incoming message. `Error` also discards the incoming message,
+// but in addition latches an overflow error that is reported to the consumer
+// in-band by the next reap (see `Queue::take_overflow`).
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+    Error,
+}
+
+// Fixed-capacity FIFO ring buffer of messages.
+pub struct Queue<T: Copy> {
+    buf: [Option<T>; QUEUE_CAPACITY],
+    head: usize,
+    len: usize,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: usize,
+    overflow: bool,
+}
+
+impl<T: Copy> Queue<T> {
+    pub const fn new() -> Queue<T> {
+        Queue {
+            buf: [None; QUEUE_CAPACITY],
+            head: 0,
+            len: 0,
+            capacity: QUEUE_CAPACITY,
+            policy: OverflowPolicy::DropOldest,
+            dropped: 0,
+            overflow: false,
+        }
+    }
+
+    pub fn configure(&mut self, capacity: usize, policy: OverflowPolicy) -> Result<()> {
+        if capacity == 0 || capacity > QUEUE_CAPACITY {
+            return Err(Error::EINVAL);
+        }
+
+        // Would have to drop messages that are already queued
+        if self.len > capacity {
+            return Err(Error::EBUSY);
+        }
+
+        self.capacity = capacity;
+        self.policy = policy;
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Number of messages discarded because the queue was full
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    // Returns `Err(Error::ENOMEM)` if a message, `msg` or the oldest one, had
+    // to be discarded.
+    pub fn push(&mut self, msg: T) -> Result<()> {
+        if self.len == self.capacity {
+            self.dropped = self.dropped.wrapping_add(1);
+
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    self.pop();
+                    self.push_unchecked(msg);
+                }
+                OverflowPolicy::DropNewest => {}
+                OverflowPolicy::Error => self.overflow = true,
+            }
+
+            return Err(Error::ENOMEM);
+        }
+
+        self.push_unchecked(msg);
+
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let msg = self.buf[self.head].take();
+
+        self.head = (self.head + 1) % QUEUE_CAPACITY;
+        self.len -= 1;
+
+        msg
+    }
+
+    pub fn peek(&self) -> Option<T> {
+        if self.len == 0 {
+            None
+        } else {
+            self.buf[self.head]
+        }
+    }
+
+    // Returns and clears the overflow error latched by the `Error` policy.
+    pub fn take_overflow(&mut self) -> bool {
+        let overflow = self.overflow;
+        self.overflow = false;
+        overflow
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    fn push_unchecked(&mut self, msg: T) {
+        let tail = (self.head + self.len) % QUEUE_CAPACITY;
+
+        self.buf[tail] = Some(msg);
+        self.len += 1;
+    }
+}
+
+impl<T: Copy> Default for Queue<T> {
+    fn default() -> Queue<T> {
+        Queue::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(capacity: usize, policy: OverflowPolicy) -> Queue<usize> {
+        let mut q = Queue::new();
+        q.configure(capacity, policy).unwrap();
+        for i in 0..capacity {
+            assert_eq!(q.push(i), Ok(()));
+        }
+        q
+    }
+
+    #[test]
+    fn fifo_order_across_wraparound() {
+        let mut q = Queue::new();
+
+        for i in 0..(3 * QUEUE_CAPACITY) {
+            assert_eq!(q.push(i), Ok(()));
+            assert_eq!(q.peek(), Some(i));
+            assert_eq!(q.pop(), Some(i));
+        }
+
+        assert!(q.is_empty());
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut q = filled(3, OverflowPolicy::DropOldest);
+
+        assert_eq!(q.push(3), Err(Error::ENOMEM));
+        assert_eq!(q.dropped(), 1);
+        assert!(!q.take_overflow());
+
+        assert_eq!(q.pop(), Some(1));
+        assert_eq!(q.pop(), Some(2));
+        assert_eq!(q.pop(), Some(3));
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let mut q = filled(2, OverflowPolicy::DropNewest);
+
+        assert_eq!(q.push(2), Err(Error::ENOMEM));
+        assert_eq!(q.dropped(), 1);
+        assert!(!q.take_overflow());
+
+        assert_eq!(q.pop(), Some(0));
+        assert_eq!(q.pop(), Some(1));
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn error_latches_overflow() {
+        let mut q = filled(1, OverflowPolicy::Error);
+
+        assert_eq!(q.push(1), Err(Error::ENOMEM));
+        assert_eq!(q.push(2), Err(Error::ENOMEM));
+        assert_eq!(q.dropped(), 2);
+
+        assert!(q.take_overflow());
+        assert!(!q.take_overflow());
+        assert_eq!(q.pop(), Some(0));
+    }
+
+    #[test]
+    fn configure_limits() {
+        let mut q = filled(4, OverflowPolicy::DropOldest);
+
+        assert_eq!(
+            q.configure(0, OverflowPolicy::DropOldest),
+            Err(Error::EINVAL)
+        );
+        assert_eq!(
+            q.configure(QUEUE_CAPACITY + 1, OverflowPolicy::DropOldest),
+            Err(Error::EINVAL)
+        );
+        assert_eq!(
+            q.configure(3, OverflowPolicy::DropOldest),
+            Err(Error::EBUSY)
+        );
+
+        q.clear();
+        assert_eq!(q.configure(3, OverflowPolicy::DropNewest), Ok(()));
+        assert_eq!(q.capacity(), 3);
+    }
+}
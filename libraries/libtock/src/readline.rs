@@ -0,0 +1,442 @@
+use core::cmp;
+use core::str;
+
+use crate::console_read::ConsoleReadClient;
+use crate::result::{Error, Result};
+
+// Line editing on top of `ConsoleReadClient`. Bytes are received one at a
+// time and echoed with `print!`, so the echo drains in the background like any
+// other printed output.
+//
+//     Backspace, DEL   erase the last character
+//     Ctrl-U           erase the line
+//     Ctrl-C           cancel the line, see `Readline::read_line`
+//     Up, Down         recall older and newer lines from the history
+//     CR, LF, CR LF    complete the line
+//
+// Characters beyond the maximum line length are refused with a bell.
+
+pub const MAX_LINE_LEN: usize = 64;
+
+// Lines kept in the history
+pub const HISTORY_LEN: usize = 4;
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const ESC: u8 = 0x1b;
+const DEL: u8 = 0x7f;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LineStatus {
+    // The line has `len` bytes, see `Readline::line`
+    Complete(usize),
+    Cancelled,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Escape {
+    None,
+    // After ESC
+    Start,
+    // After ESC [ or ESC O
+    Sequence,
+}
+
+pub struct Readline {
+    buf: [u8; MAX_LINE_LEN],
+    len: usize,
+    max_len: usize,
+    escape: Escape,
+    // The line has been completed or cancelled
+    finished: bool,
+    // The previous byte was a CR, so that a following LF does not complete an
+    // empty line
+    after_cr: bool,
+    history_enabled: bool,
+    history: [[u8; MAX_LINE_LEN]; HISTORY_LEN],
+    history_lens: [usize; HISTORY_LEN],
+    // Number of lines in the history, and slot of the newest one
+    history_count: usize,
+    history_newest: usize,
+    // Lines back from the newest one that is being shown, if any
+    history_pos: Option<usize>,
+}
+
+impl Readline {
+    // History is enabled, lines can be up to `MAX_LINE_LEN` long
+    pub fn new() -> Readline {
+        Readline {
+            buf: [0; MAX_LINE_LEN],
+            len: 0,
+            max_len: MAX_LINE_LEN,
+            escape: Escape::None,
+            finished: false,
+            after_cr: false,
+            history_enabled: true,
+            history: [[0; MAX_LINE_LEN]; HISTORY_LEN],
+            history_lens: [0; HISTORY_LEN],
+            history_count: 0,
+            history_newest: 0,
+            history_pos: None,
+        }
+    }
+
+    // Capped at `MAX_LINE_LEN`
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = cmp::min(max_len, MAX_LINE_LEN);
+    }
+
+    // Disabling the history also clears it
+    pub fn set_history_enabled(&mut self, enabled: bool) {
+        self.history_enabled = enabled;
+
+        if !enabled {
+            self.history_count = 0;
+            self.history_pos = None;
+        }
+    }
+
+    // The line being edited, or the completed line
+    pub fn line(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    // `Err(Error::EINVAL)` if the line is not valid UTF-8
+    pub fn line_str(&self) -> Result<&str> {
+        str::from_utf8(self.line()).map_err(|_| Error::EINVAL)
+    }
+
+    // Reads and echoes a line from `console`. `Err(Error::ECANCEL)` if it was
+    // cancelled with Ctrl-C. The line is available from `line` until the next
+    // call.
+    //
+    // Bytes are received continuously into the buffer of the library, see
+    // `ConsoleReadClient::read_until`, so that typed ahead or pasted input is
+    // not lost. Receiving goes on after the line, until it is stopped with
+    // `ReceiveClient::stop`.
+    pub async fn read_line(&mut self, console: &ConsoleReadClient) -> Result<&[u8]> {
+        let receiver = console.receiver();
+
+        self.clear();
+
+        loop {
+            let mut byte = [0];
+
+            receiver.start_intake(1)?;
+            if receiver.read(&mut byte) == 0 {
+                receiver.wait_for_bytes(1).await?;
+                continue;
+            }
+
+            match self.feed(byte[0], |echo| {
+                print!("{}", str::from_utf8(echo).unwrap_or(""))
+            }) {
+                Some(LineStatus::Complete(_)) => return Ok(self.line()),
+                Some(LineStatus::Cancelled) => return Err(Error::ECANCEL),
+                None => {}
+            }
+        }
+    }
+
+    // Starts a new line, without echo
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.escape = Escape::None;
+        self.finished = false;
+        self.history_pos = None;
+    }
+
+    // Edits the line with `byte`. `echo` is called with the bytes to send back
+    // to the terminal. `None` while the line is not complete. The next byte
+    // after completion starts a new line.
+    pub fn feed<F>(&mut self, byte: u8, mut echo: F) -> Option<LineStatus>
+    where
+        F: FnMut(&[u8]),
+    {
+        if self.finished {
+            self.clear();
+        }
+
+        let after_cr = self.after_cr;
+        self.after_cr = false;
+
+        match self.escape {
+            Escape::Start => {
+                self.escape = if byte == b'[' || byte == b'O' {
+                    Escape::Sequence
+                } else {
+                    Escape::None
+                };
+                return None;
+            }
+            Escape::Sequence => {
+                // Parameters, like the 1 in ESC [ 1 A, are skipped
+                if byte.is_ascii_digit() || byte == b';' {
+                    return None;
+                }
+
+                self.escape = Escape::None;
+                match byte {
+                    b'A' => self.recall_older(&mut echo),
+                    b'B' => self.recall_newer(&mut echo),
+                    _ => {}
+                }
+                return None;
+            }
+            Escape::None => {}
+        }
+
+        match byte {
+            b'\r' | b'\n' => {
+                if byte == b'\n' && after_cr {
+                    return None;
+                }
+
+                self.after_cr = byte == b'\r';
+                echo(b"\r\n");
+                self.add_to_history();
+                let len = self.len;
+                self.finish();
+
+                return Some(LineStatus::Complete(len));
+            }
+            CTRL_C => {
+                echo(b"^C\r\n");
+                self.finish();
+                self.len = 0;
+
+                return Some(LineStatus::Cancelled);
+            }
+            BACKSPACE | DEL => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    echo(b"\x08 \x08");
+                }
+            }
+            CTRL_U => self.erase(&mut echo),
+            ESC => self.escape = Escape::Start,
+            0x20..=0x7e => {
+                if self.len < self.max_len {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                    echo(&[byte]);
+                } else {
+                    echo(b"\x07");
+                }
+            }
+            // Other control characters are ignored
+            _ => {}
+        }
+
+        None
+    }
+
+    // The line stays available from `line` until the next byte
+    fn finish(&mut self) {
+        self.escape = Escape::None;
+        self.history_pos = None;
+        self.finished = true;
+    }
+
+    fn erase<F>(&mut self, echo: &mut F)
+    where
+        F: FnMut(&[u8]),
+    {
+        for _ in 0..self.len {
+            echo(b"\x08 \x08");
+        }
+        self.len = 0;
+    }
+
+    // Replaces the line with history entry `pos`, or an empty line
+    fn show<F>(&mut self, pos: Option<usize>, echo: &mut F)
+    where
+        F: FnMut(&[u8]),
+    {
+        self.erase(echo);
+        self.history_pos = pos;
+
+        if let Some(pos) = pos {
+            let slot = (self.history_newest + HISTORY_LEN - pos) % HISTORY_LEN;
+            let len = cmp::min(self.history_lens[slot], self.max_len);
+
+            self.buf[..len].copy_from_slice(&self.history[slot][..len]);
+            self.len = len;
+            echo(self.line());
+        }
+    }
+
+    fn recall_older<F>(&mut self, echo: &mut F)
+    where
+        F: FnMut(&[u8]),
+    {
+        let pos = self.history_pos.map_or(0, |pos| pos + 1);
+
+        if pos < self.history_count {
+            self.show(Some(pos), echo);
+        } else {
+            echo(b"\x07");
+        }
+    }
+
+    fn recall_newer<F>(&mut self, echo: &mut F)
+    where
+        F: FnMut(&[u8]),
+    {
+        match self.history_pos {
+            Some(0) => self.show(None, echo),
+            Some(pos) => self.show(Some(pos - 1), echo),
+            None => echo(b"\x07"),
+        }
+    }
+
+    // Empty lines and repeats of the newest line are not added
+    fn add_to_history(&mut self) {
+        if !self.history_enabled || self.len == 0 {
+            return;
+        }
+
+        let newest = self.history_newest;
+        if self.history_count > 0
+            && self.history[newest][..self.history_lens[newest]] == self.buf[..self.len]
+        {
+            return;
+        }
+
+        let slot = if self.history_count == 0 {
+            0
+        } else {
+            (newest + 1) % HISTORY_LEN
+        };
+
+        self.history[slot][..self.len].copy_from_slice(&self.buf[..self.len]);
+        self.history_lens[slot] = self.len;
+        self.history_newest = slot;
+        self.history_count = cmp::min(self.history_count + 1, HISTORY_LEN);
+    }
+}
+
+impl Default for Readline {
+    fn default() -> Readline {
+        Readline::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use crate::drivers::Drivers;
+    use crate::executor::Executor;
+    use crate::syscalls::fake::Kernel;
+
+    // Feeds `input` and returns the status of the last byte and the echo
+    fn feed(readline: &mut Readline, input: &[u8]) -> (Option<LineStatus>, Vec<u8>) {
+        let mut echo = Vec::new();
+        let mut status = None;
+
+        for &byte in input {
+            status = readline.feed(byte, |e| echo.extend_from_slice(e));
+        }
+
+        (status, echo)
+    }
+
+    #[test]
+    fn editing() {
+        let mut readline = Readline::new();
+
+        let (status, echo) = feed(&mut readline, b"helo\x7f\x08lp\r");
+        assert_eq!(status, Some(LineStatus::Complete(4)));
+        assert_eq!(readline.line(), b"help");
+        assert_eq!(echo, b"helo\x08 \x08\x08 \x08lp\r\n".to_vec());
+
+        // The LF of CR LF does not complete another line
+        assert_eq!(feed(&mut readline, b"\n").0, None);
+        assert_eq!(readline.line(), b"");
+
+        let (status, echo) = feed(&mut readline, b"ab\x15c\n");
+        assert_eq!(status, Some(LineStatus::Complete(1)));
+        assert_eq!(readline.line_str(), Ok("c"));
+        assert_eq!(echo, b"ab\x08 \x08\x08 \x08c\r\n".to_vec());
+
+        assert_eq!(
+            feed(&mut readline, b"abc\x03"),
+            (Some(LineStatus::Cancelled), b"abc^C\r\n".to_vec())
+        );
+        assert_eq!(readline.line(), b"");
+    }
+
+    #[test]
+    fn max_len() {
+        let mut readline = Readline::new();
+        readline.set_max_len(3);
+
+        let (status, echo) = feed(&mut readline, b"abcd\r");
+        assert_eq!(status, Some(LineStatus::Complete(3)));
+        assert_eq!(echo, b"abc\x07\r\n".to_vec());
+    }
+
+    #[test]
+    fn history() {
+        let mut readline = Readline::new();
+
+        for line in [&b"one\r"[..], b"two\r", b"two\r", b"\r"].iter() {
+            feed(&mut readline, line);
+        }
+
+        // Up twice, then down once
+        let (status, echo) = feed(&mut readline, b"x\x1b[A\x1b[A\x1bOB");
+        assert_eq!(status, None);
+        assert_eq!(readline.line(), b"two");
+        assert_eq!(
+            echo,
+            b"x\x08 \x08two\x08 \x08\x08 \x08\x08 \x08one\x08 \x08\x08 \x08\x08 \x08two".to_vec()
+        );
+
+        // No older line, and down past the newest line clears it
+        let (_, echo) = feed(&mut readline, b"\x1b[A\x1b[A\x1b[B\x1b[B");
+        assert_eq!(readline.line(), b"");
+        assert_eq!(echo.iter().filter(|&&b| b == 0x07).count(), 1);
+
+        readline.set_history_enabled(false);
+        let (_, echo) = feed(&mut readline, b"\x1b[A");
+        assert_eq!(echo, b"\x07".to_vec());
+    }
+
+    #[test]
+    fn history_wraps() {
+        let mut readline = Readline::new();
+
+        for i in 0..(HISTORY_LEN as u8 + 2) {
+            feed(&mut readline, &[b'a' + i, b'\r']);
+        }
+
+        for _ in 0..HISTORY_LEN {
+            feed(&mut readline, b"\x1b[A");
+        }
+        assert_eq!(readline.line(), [b'a' + 2]);
+    }
+
+    #[test]
+    fn read_line_from_the_console() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let console_read = drivers.console_read;
+        let mut executor = Executor::new(drivers.tasks);
+        let mut readline = Readline::new();
+
+        for &b in b"hi\r".iter() {
+            kernel.schedule_upcall_with_data(1, 2, &[b], 0, 1, 0);
+        }
+
+        let line = executor.block_on(readline.read_line(&console_read));
+        assert_eq!(line.map(|l| l.to_vec()), Ok(b"hi".to_vec()));
+
+        // Input typed after the line is still received
+        assert!(console_read.receiver().is_receiving());
+    }
+}
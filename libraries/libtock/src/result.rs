@@ -1,23 +1,133 @@
+use core::fmt;
 use core::result;
 
 pub type Result<T> = result::Result<T, Error>;
 
-#[derive(Clone, Copy, PartialEq, Debug)]
-#[repr(isize)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Error {
-    FAIL = -1,
-    EBUSY = -2,
-    EALREADY = -3,
-    EOFF = -4,
-    ERESERVE = -5,
-    EINVAL = -6,
-    ESIZE = -7,
-    ECANCEL = -8,
-    ENOMEM = -9,
-    ENOSUPPORT = -10,
-    ENODEVICE = -11,
-    EUNINSTALLED = -12,
-    ENOACK = -13,
+    FAIL,
+    EBUSY,
+    EALREADY,
+    EOFF,
+    ERESERVE,
+    EINVAL,
+    ESIZE,
+    ECANCEL,
+    ENOMEM,
+    ENOSUPPORT,
+    ENODEVICE,
+    EUNINSTALLED,
+    ENOACK,
+    // A code this library does not know about, for example from a newer kernel
+    Unknown(isize),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Syscall {
+    Subscribe(usize),
+    Command(usize),
+    Allow(usize),
+    Memop(u32),
+}
+
+// Error returned by a syscall, together with the syscall. Syscalls return a
+// plain `Error`, the context of the last one that failed is kept apart, see
+// `last_syscall_error`. `driver` is 0 for a memop, which is not addressed to a
+// driver.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SyscallError {
+    pub driver: usize,
+    pub syscall: Syscall,
+    error: Error,
+}
+
+impl SyscallError {
+    pub fn new(driver: usize, syscall: Syscall, error: Error) -> SyscallError {
+        SyscallError {
+            driver,
+            syscall,
+            error,
+        }
+    }
+
+    pub fn error(&self) -> Error {
+        self.error
+    }
+}
+
+static mut LAST_SYSCALL_ERROR: Option<SyscallError> = None;
+
+// Best-effort hint at where the error last returned by a driver came from.
+// Every subscribe, command, allow and memop that fails sets it. Errors that do
+// not come from a syscall clear it, both the ones the driver modules detect
+// themselves, such as `EBUSY` for a read that is already ongoing, and the ones
+// reported by a callback.
+//
+// The context is not carried by the `Error` itself. There is a single slot for
+// the whole process, and every failing syscall replaces it, also the ones the
+// caller does not make: clean up after an error, the driver tasks and upcalls,
+// such as the button upcall reading the clock, and the drain of printed output.
+// So it must be read straight after the call that returned the `Err`, before
+// awaiting, yielding, printing or any other driver call, and even then it may
+// describe a different failure. Errors of the modules built on top of the drivers, such
+// as `framing` or `shell`, leave it as it is.
+pub fn last_syscall_error() -> Option<SyscallError> {
+    unsafe { LAST_SYSCALL_ERROR }
+}
+
+pub(crate) fn set_last_syscall_error(e: SyscallError) {
+    unsafe {
+        LAST_SYSCALL_ERROR = Some(e);
+    }
+}
+
+// For an error that does not come from a syscall, see `last_syscall_error`
+pub(crate) fn library_error(e: Error) -> Error {
+    unsafe {
+        LAST_SYSCALL_ERROR = None;
+    }
+
+    e
+}
+
+impl Error {
+    pub fn code(&self) -> isize {
+        match *self {
+            Error::FAIL => -1,
+            Error::EBUSY => -2,
+            Error::EALREADY => -3,
+            Error::EOFF => -4,
+            Error::ERESERVE => -5,
+            Error::EINVAL => -6,
+            Error::ESIZE => -7,
+            Error::ECANCEL => -8,
+            Error::ENOMEM => -9,
+            Error::ENOSUPPORT => -10,
+            Error::ENODEVICE => -11,
+            Error::EUNINSTALLED => -12,
+            Error::ENOACK => -13,
+            Error::Unknown(code) => code,
+        }
+    }
+
+    fn name(&self) -> Option<&'static str> {
+        match *self {
+            Error::FAIL => Some("FAIL"),
+            Error::EBUSY => Some("EBUSY"),
+            Error::EALREADY => Some("EALREADY"),
+            Error::EOFF => Some("EOFF"),
+            Error::ERESERVE => Some("ERESERVE"),
+            Error::EINVAL => Some("EINVAL"),
+            Error::ESIZE => Some("ESIZE"),
+            Error::ECANCEL => Some("ECANCEL"),
+            Error::ENOMEM => Some("ENOMEM"),
+            Error::ENOSUPPORT => Some("ENOSUPPORT"),
+            Error::ENODEVICE => Some("ENODEVICE"),
+            Error::EUNINSTALLED => Some("EUNINSTALLED"),
+            Error::ENOACK => Some("ENOACK"),
+            _ => None,
+        }
+    }
 }
 
 impl From<isize> for Error {
@@ -36,37 +146,143 @@ impl From<isize> for Error {
             -11 => Error::ENODEVICE,
             -12 => Error::EUNINSTALLED,
             -13 => Error::ENOACK,
-            _ => panic!("invalid isize"),
+            _ => Error::Unknown(i),
+        }
+    }
+}
+
+// "EBUSY" or "unknown error -42"
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown error {}", self.code()),
         }
     }
 }
 
+// "console allow 1 failed: ESIZE"
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Syscall::Memop(n) = self.syscall {
+            return write!(f, "memop {} failed: {}", n, self.error);
+        }
+
+        match self.driver {
+            0 => f.write_str("alarm")?,
+            1 => f.write_str("console")?,
+            2 => f.write_str("led")?,
+            3 => f.write_str("button")?,
+            driver => write!(f, "driver {}", driver)?,
+        }
+
+        match self.syscall {
+            Syscall::Subscribe(n) => write!(f, " subscribe {}", n)?,
+            Syscall::Command(n) => write!(f, " command {}", n)?,
+            Syscall::Allow(n) => write!(f, " allow {}", n)?,
+            Syscall::Memop(_) => (),
+        }
+
+        write!(f, " failed: {}", self.error)
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub(crate) unsafe fn reset_state() {
+    LAST_SYSCALL_ERROR = None;
+}
+
 // `newtype` to take care of errors returned via `usize` with a `isize` value.
+// Used for the errors reported by callbacks, see `last_syscall_error`.
 pub struct UsizeError(pub Option<Error>);
 
 impl From<usize> for UsizeError {
     fn from(u: usize) -> UsizeError {
-        match u {
-            fail if Error::FAIL as usize == fail => UsizeError(Some(Error::FAIL)),
-            ebusy if Error::EBUSY as usize == ebusy => UsizeError(Some(Error::EBUSY)),
-            ealready if Error::EALREADY as usize == ealready => UsizeError(Some(Error::EALREADY)),
-            eoff if Error::EOFF as usize == eoff => UsizeError(Some(Error::EOFF)),
-            ereserve if Error::ERESERVE as usize == ereserve => UsizeError(Some(Error::ERESERVE)),
-            einval if Error::EINVAL as usize == einval => UsizeError(Some(Error::EINVAL)),
-            esize if Error::ESIZE as usize == esize => UsizeError(Some(Error::ESIZE)),
-            ecancel if Error::ECANCEL as usize == ecancel => UsizeError(Some(Error::ECANCEL)),
-            enomem if Error::ENOMEM as usize == enomem => UsizeError(Some(Error::ENOMEM)),
-            enosupport if Error::ENOSUPPORT as usize == enosupport => {
-                UsizeError(Some(Error::ENOSUPPORT))
-            }
-            enodevice if Error::ENODEVICE as usize == enodevice => {
-                UsizeError(Some(Error::ENODEVICE))
-            }
-            euninstalled if Error::EUNINSTALLED as usize == euninstalled => {
-                UsizeError(Some(Error::EUNINSTALLED))
-            }
-            enoack if Error::ENOACK as usize == enoack => UsizeError(Some(Error::ENOACK)),
-            _ => UsizeError(None),
+        let i = u as isize;
+
+        if i < 0 {
+            UsizeError(Some(library_error(Error::from(i))))
+        } else {
+            UsizeError(None)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::ptr;
+    use std::format;
+    use std::string::String;
+
+    use crate::syscalls;
+    use crate::syscalls::fake::{Kernel, SyscallClass};
+
+    #[test]
+    fn unknown_codes() {
+        assert_eq!(Error::from(-7), Error::ESIZE);
+        assert_eq!(Error::from(-42).code(), -42);
+        assert_eq!(format!("{}", Error::from(-42)), "unknown error -42");
+        assert_eq!(format!("{:?}", Error::from(-42)), "Unknown(-42)");
+        assert_eq!(format!("{}", Error::ENOACK), "ENOACK");
+    }
+
+    #[test]
+    fn syscall_context() {
+        let e = SyscallError::new(1, Syscall::Allow(1), Error::ESIZE);
+
+        assert_eq!(format!("{}", e), "console allow 1 failed: ESIZE");
+        assert_eq!(e.error(), Error::ESIZE);
+
+        let e = SyscallError::new(9, Syscall::Command(4), Error::Unknown(-20));
+        assert_eq!(
+            format!("{}", e),
+            "driver 9 command 4 failed: unknown error -20"
+        );
+    }
+
+    #[test]
+    fn syscalls_report_context() {
+        let kernel = Kernel::new();
+
+        kernel.set_result(SyscallClass::Allow, 1, 1, Err(Error::ESIZE));
+
+        // The error itself still matches as a plain code
+        match unsafe { syscalls::allow(1, 1, ptr::null_mut(), 0) } {
+            Err(Error::ESIZE) => {}
+            _ => panic!("allow did not fail with ESIZE"),
+        }
+        assert_eq!(
+            last_syscall_error().map(|e| format!("{}", e)),
+            Some(String::from("console allow 1 failed: ESIZE"))
+        );
+
+        // An error that does not come from a syscall has no context
+        assert_eq!(library_error(Error::EBUSY), Error::EBUSY);
+        assert_eq!(last_syscall_error(), None);
+
+        kernel.set_result(SyscallClass::Memop, 7, 0, Err(Error::ENOSUPPORT));
+        assert_eq!(unsafe { syscalls::memop(7, 0) }, Err(Error::ENOSUPPORT));
+        assert_eq!(
+            last_syscall_error().map(|e| format!("{}", e)),
+            Some(String::from("memop 7 failed: ENOSUPPORT"))
+        );
+
+        assert_eq!(UsizeError::from(-2isize as usize).0, Some(Error::EBUSY));
+        assert_eq!(last_syscall_error(), None);
+    }
+
+    #[test]
+    fn usize_errors() {
+        assert_eq!(
+            UsizeError::from(Error::FAIL.code() as usize).0,
+            Some(Error::FAIL)
+        );
+        assert_eq!(
+            UsizeError::from(-30isize as usize).0,
+            Some(Error::Unknown(-30))
+        );
+        assert_eq!(UsizeError::from(5).0, None);
+    }
+}
@@ -0,0 +1,496 @@
+use core::ops::Generator;
+use core::str;
+
+use crate::alarm::AlarmClient;
+use crate::button::{ButtonClient, ButtonState};
+use crate::console_read::{ConsoleReadClient, ReceiveClient};
+use crate::heap;
+use crate::led::Led;
+use crate::memory;
+use crate::readline::{LineStatus, Readline, MAX_LINE_LEN};
+use crate::result::{Error, Result};
+use crate::task::DriverTaskClient;
+
+// Interactive command shell over the console. Lines are edited with
+// `Readline`, split into whitespace separated arguments, where double quotes
+// group words, and handed to the command named by the first one. Output goes
+// through `print!`, and so through `ConsoleWrite`.
+//
+// The shell runs as a user task, see `Shell::task`, that receives into the
+// buffer of the library and yields while there is no input, so it never blocks
+// other tasks. It owns the console read client and the handles its built-in
+// commands use, which are given to `Shell::new` from `Drivers::take`.
+//
+// Built-in commands:
+//
+//     help                    list the commands
+//     led on|off|toggle N
+//     button state N
+//     alarm now
+//     mem                     memory layout and heap usage
+pub const MAX_COMMANDS: usize = 16;
+
+// Arguments of a line, including the command name
+pub const MAX_ARGS: usize = 8;
+
+// `args[0]` is the name of the command. `Err(Error::EINVAL)` for bad usage.
+// The shell gives access to its driver handles.
+pub type Handler = fn(shell: &Shell, args: &[&str]) -> Result<()>;
+
+#[derive(Copy, Clone)]
+pub struct Command {
+    pub name: &'static str,
+    // Usage and a short description, shown by `help`
+    pub help: &'static str,
+    pub handler: Handler,
+}
+
+const BUILTINS: [Command; 4] = [
+    Command {
+        name: "led",
+        help: "led on|off|toggle N",
+        handler: led,
+    },
+    Command {
+        name: "button",
+        help: "button state N",
+        handler: button,
+    },
+    Command {
+        name: "alarm",
+        help: "alarm now",
+        handler: alarm,
+    },
+    Command {
+        name: "mem",
+        help: "mem",
+        handler: mem,
+    },
+];
+
+// Splits `line` into `args`. Returns the number of arguments.
+//
+// `Err(Error::ESIZE)` if there are more than `args.len()`, `Err(Error::EINVAL)`
+// for an unterminated quote.
+pub fn tokenize<'a>(line: &'a str, args: &mut [&'a str]) -> Result<usize> {
+    let mut count = 0;
+    let mut rest = line.trim_start();
+
+    while !rest.is_empty() {
+        let (arg, next) = if rest.starts_with('"') {
+            let end = rest[1..].find('"').ok_or(Error::EINVAL)?;
+            (&rest[1..end + 1], &rest[end + 2..])
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            rest.split_at(end)
+        };
+
+        if count == args.len() {
+            return Err(Error::ESIZE);
+        }
+        args[count] = arg;
+        count += 1;
+
+        rest = next.trim_start();
+    }
+
+    Ok(count)
+}
+
+pub struct Shell {
+    prompt: &'static str,
+    commands: [Option<Command>; MAX_COMMANDS],
+    readline: Readline,
+    console_read: ConsoleReadClient,
+    alarm: AlarmClient,
+    button: ButtonClient,
+    led: Led,
+}
+
+impl Shell {
+    // With the built-in commands
+    pub fn new(
+        console_read: ConsoleReadClient,
+        alarm: AlarmClient,
+        button: ButtonClient,
+        led: Led,
+    ) -> Shell {
+        let mut commands = [None; MAX_COMMANDS];
+
+        for (slot, &command) in commands.iter_mut().zip(BUILTINS.iter()) {
+            *slot = Some(command);
+        }
+
+        Shell {
+            prompt: "> ",
+            commands,
+            readline: Readline::new(),
+            console_read,
+            alarm,
+            button,
+            led,
+        }
+    }
+
+    pub fn alarm(&self) -> &AlarmClient {
+        &self.alarm
+    }
+
+    pub fn button(&self) -> &ButtonClient {
+        &self.button
+    }
+
+    pub fn led(&self) -> &Led {
+        &self.led
+    }
+
+    pub fn set_prompt(&mut self, prompt: &'static str) {
+        self.prompt = prompt;
+    }
+
+    // `Err(Error::EALREADY)` if there is a command `name` already,
+    // `Err(Error::ENOMEM)` if there are `MAX_COMMANDS` commands.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        handler: Handler,
+    ) -> Result<()> {
+        if name == "help" || self.find(name).is_some() {
+            return Err(Error::EALREADY);
+        }
+
+        let slot = self
+            .commands
+            .iter_mut()
+            .find(|c| c.is_none())
+            .ok_or(Error::ENOMEM)?;
+
+        *slot = Some(Command {
+            name,
+            help,
+            handler,
+        });
+
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Option<&Command> {
+        self.commands
+            .iter()
+            .filter_map(|c| c.as_ref())
+            .find(|c| c.name == name)
+    }
+
+    // Runs the command on `line`. Empty lines do nothing.
+    //
+    // `Err(Error::ENOSUPPORT)` for an unknown command, or the error of the
+    // command.
+    pub fn execute(&self, line: &str) -> Result<()> {
+        let mut args = [""; MAX_ARGS];
+        let n = tokenize(line, &mut args)?;
+        let args = &args[..n];
+
+        match args.first() {
+            None => Ok(()),
+            Some(&"help") => {
+                println!("help");
+                for c in self.commands.iter().filter_map(|c| c.as_ref()) {
+                    println!("{}", c.help);
+                }
+                Ok(())
+            }
+            Some(name) => match self.find(name) {
+                Some(c) => (c.handler)(self, args),
+                None => Err(Error::ENOSUPPORT),
+            },
+        }
+    }
+
+    // Runs `line` and reports errors
+    fn run_line(&self, line: &str) {
+        match self.execute(line) {
+            Ok(()) => {}
+            Err(Error::ENOSUPPORT) => println!("unknown command, try help"),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    // The shell as a user task. Spawn it with `Executor::spawn_with_wake` and
+    // `shell::has_input`. It never completes.
+    pub fn task(mut self) -> impl Generator<Yield = (), Return = ()> {
+        move || {
+            let receiver = self.console_read.receiver();
+            let mut reported = false;
+
+            print!("{}", self.prompt);
+
+            loop {
+                let mut byte = [0];
+
+                // The console may be busy with other reads, or receiving may
+                // have been stopped by an error. Then this is retried once the
+                // task is woken again, and the error is only reported once.
+                match receiver.start_intake(1) {
+                    Ok(_) => reported = false,
+                    Err(e) => {
+                        if !reported {
+                            println!("console read: {}", e);
+                            reported = true;
+                        }
+
+                        yield;
+                        continue;
+                    }
+                }
+
+                if receiver.read(&mut byte) == 0 {
+                    yield;
+                    continue;
+                }
+
+                let status = self.readline.feed(byte[0], |echo| {
+                    print!("{}", str::from_utf8(echo).unwrap_or(""))
+                });
+
+                match status {
+                    Some(LineStatus::Complete(len)) => {
+                        // The line is only borrowed from `readline` until
+                        // the next byte
+                        let mut line = [0; MAX_LINE_LEN];
+                        line[..len].copy_from_slice(self.readline.line());
+
+                        match str::from_utf8(&line[..len]) {
+                            Ok(line) => self.run_line(line),
+                            Err(_) => println!("error: {}", Error::EINVAL),
+                        }
+                        print!("{}", self.prompt);
+                    }
+                    Some(LineStatus::Cancelled) => print!("{}", self.prompt),
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+// Received bytes are waiting for the shell, or receiving has ended and has to
+// be started again. The wake condition for `Shell::task`.
+pub fn has_input() -> bool {
+    let receiver = ReceiveClient::new();
+    receiver.available() > 0 || !receiver.is_receiving()
+}
+
+fn arg_num(args: &[&str], i: usize) -> Result<usize> {
+    args.get(i)
+        .and_then(|a| a.parse().ok())
+        .ok_or(Error::EINVAL)
+}
+
+fn led(shell: &Shell, args: &[&str]) -> Result<()> {
+    if args.len() != 3 {
+        return Err(Error::EINVAL);
+    }
+
+    let led = shell.led();
+    let n = arg_num(args, 2)?;
+
+    match args[1] {
+        "on" => led.on(n),
+        "off" => led.off(n),
+        "toggle" => led.toggle(n),
+        _ => Err(Error::EINVAL),
+    }
+}
+
+fn button(shell: &Shell, args: &[&str]) -> Result<()> {
+    if args.len() != 3 || args[1] != "state" {
+        return Err(Error::EINVAL);
+    }
+
+    let button_client = shell.button();
+    let button = button_client.index(arg_num(args, 2)?)?;
+
+    match button_client.get_button_state(button)? {
+        ButtonState::Pressed => println!("pressed"),
+        ButtonState::NotPressed => println!("released"),
+    }
+
+    Ok(())
+}
+
+fn alarm(shell: &Shell, args: &[&str]) -> Result<()> {
+    if args.len() != 2 || args[1] != "now" {
+        return Err(Error::EINVAL);
+    }
+
+    let alarm_client = shell.alarm();
+    let now = alarm_client.now()?;
+    let hz = alarm_client.frequency()?.hz();
+
+    println!(
+        "{} ticks ({} ms)",
+        now.ticks(),
+        u64::from(now.ticks()) * 1000 / u64::from(hz)
+    );
+
+    Ok(())
+}
+
+fn mem(_shell: &Shell, args: &[&str]) -> Result<()> {
+    if args.len() != 1 {
+        return Err(Error::EINVAL);
+    }
+
+    let ram = memory::memory()?;
+    let brk = memory::app_break()?;
+    let grant = memory::grant_start()?;
+    let heap = heap::stats();
+
+    println!(
+        "ram   {:#x}..{:#x} ({} bytes)",
+        ram.start.as_usize(),
+        ram.end.as_usize(),
+        ram.len()
+    );
+    println!("break {:#x}", brk.as_usize());
+    println!("grant {:#x}", grant.as_usize());
+    println!(
+        "heap  used {} free {} peak {}",
+        heap.used, heap.free, heap.high_water_mark
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::console_read::ConsoleRead;
+    use crate::syscalls::fake::{Kernel, SyscallRecord};
+    use crate::syscalls::yieldk;
+
+    #[test]
+    fn tokenizing() {
+        let mut args = [""; 4];
+
+        assert_eq!(tokenize("  led  on 1 ", &mut args), Ok(3));
+        assert_eq!(args[..3], ["led", "on", "1"]);
+
+        assert_eq!(tokenize("say \"hi there\" x", &mut args), Ok(3));
+        assert_eq!(args[..3], ["say", "hi there", "x"]);
+
+        assert_eq!(tokenize("", &mut args), Ok(0));
+        assert_eq!(tokenize("a \"b", &mut args), Err(Error::EINVAL));
+        assert_eq!(tokenize("a b c d e", &mut args), Err(Error::ESIZE));
+    }
+
+    fn shell() -> Shell {
+        Shell::new(
+            ConsoleReadClient::new(),
+            AlarmClient::new(),
+            ButtonClient::new(),
+            Led::new(),
+        )
+    }
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(_shell: &Shell, args: &[&str]) -> Result<()> {
+        CALLS.fetch_add(args.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[test]
+    fn commands() {
+        let kernel = Kernel::new();
+        let mut shell = shell();
+
+        shell.register("count", "count ARGS...", count).unwrap();
+        assert_eq!(shell.register("count", "", count), Err(Error::EALREADY));
+        assert_eq!(shell.register("help", "", count), Err(Error::EALREADY));
+
+        assert_eq!(shell.execute("count a b"), Ok(()));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+
+        assert_eq!(shell.execute(""), Ok(()));
+        assert_eq!(shell.execute("nope"), Err(Error::ENOSUPPORT));
+        assert_eq!(shell.execute("led blink 1"), Err(Error::EINVAL));
+
+        kernel.clear_syscalls();
+        assert_eq!(shell.execute("led toggle 1"), Ok(()));
+        assert_eq!(
+            kernel.syscalls(),
+            [SyscallRecord::Command {
+                major: 2,
+                minor: 3,
+                arg1: 1,
+                arg2: 0,
+            }]
+        );
+
+        for i in 0..(MAX_COMMANDS - BUILTINS.len() - 1) {
+            let name = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k"][i];
+            shell.register(name, "", count).unwrap();
+        }
+        assert_eq!(shell.register("z", "", count), Err(Error::ENOMEM));
+    }
+
+    #[test]
+    fn task_reads_and_runs_lines() {
+        let kernel = Kernel::new();
+        let console_read = ConsoleRead::new();
+        let mut console_read_task = console_read.get_task();
+        let mut shell_task = shell().task();
+
+        Pin::new(&mut shell_task).resume();
+        assert!(!has_input());
+
+        // Bytes that arrive before the shell runs again are kept
+        for &b in b"led on 0\r".iter() {
+            kernel.schedule_upcall_with_data(1, 2, &[b], 0, 1, 0);
+            yieldk();
+            Pin::new(&mut console_read_task).resume();
+        }
+        assert!(has_input());
+        Pin::new(&mut shell_task).resume();
+
+        assert!(kernel.syscalls().contains(&SyscallRecord::Command {
+            major: 2,
+            minor: 1,
+            arg1: 0,
+            arg2: 0,
+        }));
+        assert!(!has_input());
+        assert!(ConsoleReadClient::new().receiver().is_receiving());
+    }
+
+    #[test]
+    fn task_retries_a_busy_console() {
+        let kernel = Kernel::new();
+        let console_read = ConsoleRead::new();
+        let mut console_read_task = console_read.get_task();
+        let mut shell_task = shell().task();
+        let receiver = ConsoleReadClient::new().receiver();
+
+        let buf = Box::leak(vec![0; 4].into_boxed_slice());
+        receiver.start(buf, 1).unwrap();
+        Pin::new(&mut shell_task).resume();
+
+        // The app hands its buffer back, then the shell receives
+        receiver.stop().unwrap();
+        kernel.schedule_upcall(1, 2, 0, 0, 0);
+        yieldk();
+        Pin::new(&mut console_read_task).resume();
+        receiver.release().unwrap();
+        assert!(has_input());
+
+        Pin::new(&mut shell_task).resume();
+        assert!(receiver.is_receiving());
+        assert_eq!(receiver.release().err(), Some(Error::EBUSY));
+    }
+}
@@ -1,4 +1,10 @@
-use crate::result::Result;
+use crate::result::{set_last_syscall_error, Result, Syscall, SyscallError};
+
+#[cfg(target_arch = "arm")]
+mod arm;
+
+#[cfg(not(target_arch = "arm"))]
+pub mod fake;
 
 // Some drivers might pass error via a callback in `arg0`. If the driver wants
 // to be cheeky, it can also use `arg1` or `arg2`. So even though its `usize` at
@@ -7,6 +13,7 @@ use crate::result::Result;
 //
 // We get around a simliar issue in `subscribe`, `command`, `allow` and `memop`
 // by doing implicit type conversion from usize to size in the `asm!` block.
+#[derive(Copy, Clone)]
 pub(crate) struct CallbackMessage {
     arg0: usize,
     arg1: usize,
@@ -42,52 +49,42 @@ impl CallbackMessage {
     }
 }
 
-pub fn yieldk() {
-    // Note: A process stops yielding when there is a callback ready to run,
-    // which the kernel executes by modifying the stack frame pushed by the
-    // hardware. The kernel copies the PC value from the stack frame to the LR
-    // field, and sets the PC value to callback to run. When this frame is
-    // unstacked during the interrupt return, the effectively clobbers the LR
-    // register.
-    //
-    // At this point, the callback function is now executing, which may itself
-    // clobber any of the other caller-saved registers. Thus we mark this
-    // inline assembly as conservatively clobbering all caller-saved registers,
-    // forcing yield to save any live registers.
-    //
-    // Upon direct observation of this function, the LR is the only register
-    // that is live across the SVC invocation, however, if the yield call is
-    // inlined, it is possible that the LR won't be live at all (commonly seen
-    // for the `loop { yieldk(); }` idiom) or that other registers are live,
-    // thus it is important to let the compiler do the work here.
-    //
-    // According to the AAPCS: A subroutine must preserve the contents of the
-    // registers r4-r8, r10, r11 and SP (and r9 in PCS variants that designate
-    // r9 as v6) As our compilation flags mark r9 as the PIC base register, it
-    // does not need to be saved. Thus we must clobber r0-3, r12, and LR
-    unsafe {
-        asm!(
-            "svc 0"
-            :
-            :
-            : "memory", "r0", "r1", "r2", "r3", "r12", "lr"
-            : "volatile");
-    }
+// A syscall backend. Drivers never talk to a backend directly, they go through
+// the free functions below which pick the backend for the target we are being
+// built for. On ARM that is the `svc` based `arm::ArmSyscalls`. Everywhere else
+// it is the in-process `fake::FakeSyscalls`, which lets drivers be exercised
+// with `cargo test` on the host.
+//
+// Backends return the raw value the kernel would have left in `r0`. Negative
+// values are error codes and are converted to `Error` by the wrappers.
+pub trait Syscalls {
+    fn yieldk();
+
+    unsafe fn subscribe(
+        major: usize,
+        minor: usize,
+        callback: *const unsafe extern "C" fn(usize, usize, usize, usize),
+        userdata: usize,
+    ) -> isize;
+
+    unsafe fn command(major: usize, minor: usize, arg1: usize, arg2: usize) -> isize;
+
+    unsafe fn allow(major: usize, minor: usize, ptr: *mut u8, len: usize) -> isize;
+
+    unsafe fn memop(major: u32, arg1: usize) -> isize;
 }
 
-pub(crate) unsafe fn subscribe(
-    major: usize,
-    minor: usize,
-    callback: *const unsafe extern "C" fn(usize, usize, usize, usize),
-    userdata: usize,
-) -> Result<usize> {
-    let res: isize;
+#[cfg(target_arch = "arm")]
+type Backend = arm::ArmSyscalls;
 
-    asm!("svc 1" : "={r0}"(res)
-         : "{r0}"(major) "{r1}"(minor) "{r2}"(callback) "{r3}"(userdata)
-         : "memory"
-         : "volatile");
+#[cfg(not(target_arch = "arm"))]
+type Backend = fake::FakeSyscalls;
 
+// Largest error code we expect from the kernel, known or not, see
+// `memop_address`
+const MAX_ERROR_CODE: isize = 1024;
+
+fn into_result(res: isize) -> Result<usize> {
     if res < 0 {
         Err(res.into())
     } else {
@@ -95,51 +92,65 @@ pub(crate) unsafe fn subscribe(
     }
 }
 
+// Like `into_result`, but a failing syscall is recorded, see
+// `result::last_syscall_error`
+fn into_syscall_result(res: isize, driver: usize, syscall: Syscall) -> Result<usize> {
+    into_result(res).map_err(|e| {
+        set_last_syscall_error(SyscallError::new(driver, syscall, e));
+        e
+    })
+}
+
+pub fn yieldk() {
+    Backend::yieldk()
+}
+
+pub(crate) unsafe fn subscribe(
+    major: usize,
+    minor: usize,
+    callback: *const unsafe extern "C" fn(usize, usize, usize, usize),
+    userdata: usize,
+) -> Result<usize> {
+    into_syscall_result(
+        Backend::subscribe(major, minor, callback, userdata),
+        major,
+        Syscall::Subscribe(minor),
+    )
+}
+
 pub(crate) unsafe fn command(
     major: usize,
     minor: usize,
     arg1: usize,
     arg2: usize,
 ) -> Result<usize> {
-    let res: isize;
-
-    asm!("svc 2" : "={r0}"(res)
-         : "{r0}"(major) "{r1}"(minor) "{r2}"(arg1) "{r3}"(arg2)
-         : "memory"
-         : "volatile");
-
-    if res < 0 {
-        Err(res.into())
-    } else {
-        Ok(res as usize)
-    }
+    into_syscall_result(
+        Backend::command(major, minor, arg1, arg2),
+        major,
+        Syscall::Command(minor),
+    )
 }
 
 pub(crate) unsafe fn allow(major: usize, minor: usize, ptr: *mut u8, len: usize) -> Result<usize> {
-    let res: isize;
-
-    asm!("svc 3" : "={r0}"(res)
-         : "{r0}"(major) "{r1}"(minor) "{r2}"(ptr) "{r3}"(len)
-         : "memory"
-         : "volatile");
-
-    if res < 0 {
-        Err(res.into())
-    } else {
-        Ok(res as usize)
-    }
+    into_syscall_result(
+        Backend::allow(major, minor, ptr, len),
+        major,
+        Syscall::Allow(minor),
+    )
 }
 
 pub(crate) unsafe fn memop(major: u32, arg1: usize) -> Result<usize> {
-    let res: isize;
+    into_syscall_result(Backend::memop(major, arg1), 0, Syscall::Memop(major))
+}
 
-    asm!("svc 4" : "={r0}"(res)
-                 : "{r0}"(major) "{r1}"(arg1)
-                 : "memory"
-                 : "volatile");
+// Memop queries that return an address. Addresses above
+// `isize::max_value()` look like negative values, so only values just below 0
+// are error codes.
+pub(crate) unsafe fn memop_address(major: u32, arg1: usize) -> Result<usize> {
+    let res = Backend::memop(major, arg1);
 
-    if res < 0 {
-        Err(res.into())
+    if res < 0 && res >= -MAX_ERROR_CODE {
+        into_syscall_result(res, 0, Syscall::Memop(major))
     } else {
         Ok(res as usize)
     }
@@ -0,0 +1,360 @@
+// In-process fake kernel, used in place of `svc` when libtock is built for a
+// non-ARM host (for example `x86_64-unknown-linux-gnu` under `cargo test`).
+//
+// A test creates a `Kernel`, which resets the fake and all driver state, and
+// then drives the code under test:
+//
+//     let kernel = Kernel::new();
+//     kernel.set_result(SyscallClass::Command, 0, 1, Ok(32768));
+//
+//     AlarmClient::new().get_clock_frequency(); // returns Ok(32768)
+//
+//     kernel.schedule_upcall(0, 0, 10, 20, 0);
+//     syscalls::yieldk();                       // runs `alarm_callback`
+//
+//     assert_eq!(kernel.syscalls()[0], SyscallRecord::Command { .. });
+//
+// Like the real kernel, upcalls are only delivered from within `yieldk`, one
+// upcall per `yieldk`. If there is no upcall pending `yieldk` returns
+// immediately instead of blocking.
+//
+// Driver state lives in `static mut`s, so only one `Kernel` can exist at any
+// point in time. `Kernel::new` blocks until the previous instance is dropped,
+// which serializes tests that are run on multiple threads.
+
+use std::collections::{HashMap, VecDeque};
+use std::thread;
+use std::vec::Vec;
+
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::Syscalls;
+use crate::result::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SyscallClass {
+    Subscribe,
+    Command,
+    Allow,
+    Memop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyscallRecord {
+    Yield,
+    Subscribe {
+        major: usize,
+        minor: usize,
+        userdata: usize,
+    },
+    Command {
+        major: usize,
+        minor: usize,
+        arg1: usize,
+        arg2: usize,
+    },
+    Allow {
+        major: usize,
+        minor: usize,
+        len: usize,
+    },
+    Memop {
+        major: u32,
+        arg1: usize,
+    },
+}
+
+struct Upcall {
+    major: usize,
+    minor: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    // Written to the buffer allowed for (major, minor) on delivery
+    data: Option<Vec<u8>>,
+}
+
+struct State {
+    syscalls: Vec<SyscallRecord>,
+    // Returned for every call to (class, major, minor) unless there is a
+    // scripted value
+    results: HashMap<(SyscallClass, usize, usize), Result<usize>>,
+    // One-shot values, consumed in order
+    scripted: HashMap<(SyscallClass, usize, usize), VecDeque<Result<usize>>>,
+    // (major, minor) -> (callback address, userdata)
+    callbacks: HashMap<(usize, usize), (usize, usize)>,
+    // (major, minor) -> (buffer address, length)
+    allowed: HashMap<(usize, usize), (usize, usize)>,
+    upcalls: VecDeque<Upcall>,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            syscalls: Vec::new(),
+            results: HashMap::new(),
+            scripted: HashMap::new(),
+            callbacks: HashMap::new(),
+            allowed: HashMap::new(),
+            upcalls: VecDeque::new(),
+        }
+    }
+
+    fn write_allowed(&self, major: usize, minor: usize, data: &[u8]) -> usize {
+        let &(ptr, len) = self
+            .allowed
+            .get(&(major, minor))
+            .expect("no buffer allowed");
+
+        let n = core::cmp::min(len, data.len());
+
+        unsafe {
+            std::slice::from_raw_parts_mut(ptr as *mut u8, n).copy_from_slice(&data[..n]);
+        }
+
+        n
+    }
+
+    fn result(&mut self, class: SyscallClass, major: usize, minor: usize) -> isize {
+        let key = (class, major, minor);
+
+        let res = self
+            .scripted
+            .get_mut(&key)
+            .and_then(|q| q.pop_front())
+            .or_else(|| self.results.get(&key).cloned())
+            .unwrap_or(Ok(0));
+
+        match res {
+            Ok(v) => v as isize,
+            Err(e) => e.code(),
+        }
+    }
+}
+
+static KERNEL_IN_USE: AtomicBool = AtomicBool::new(false);
+
+static mut STATE: Option<State> = None;
+
+fn state() -> &'static mut State {
+    unsafe {
+        STATE
+            .as_mut()
+            .expect("no fake kernel, create one using `Kernel::new()`")
+    }
+}
+
+pub struct Kernel {
+    _private: (),
+}
+
+impl Kernel {
+    pub fn new() -> Kernel {
+        while KERNEL_IN_USE
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            thread::yield_now();
+        }
+
+        unsafe {
+            STATE = Some(State::new());
+            crate::reset_driver_state();
+        }
+
+        Kernel { _private: () }
+    }
+
+    // Syscalls made since the kernel was created or last cleared, in order.
+    pub fn syscalls(&self) -> Vec<SyscallRecord> {
+        state().syscalls.clone()
+    }
+
+    pub fn clear_syscalls(&self) {
+        state().syscalls.clear();
+    }
+
+    // Value returned by every subsequent (class, major, minor) syscall. The
+    // default is `Ok(0)`.
+    pub fn set_result(&self, class: SyscallClass, major: usize, minor: usize, res: Result<usize>) {
+        state().results.insert((class, major, minor), res);
+    }
+
+    // Value returned by the next (class, major, minor) syscall only. Queued
+    // values take precedence over `set_result`.
+    pub fn push_result(&self, class: SyscallClass, major: usize, minor: usize, res: Result<usize>) {
+        state()
+            .scripted
+            .entry((class, major, minor))
+            .or_insert_with(VecDeque::new)
+            .push_back(res);
+    }
+
+    // Queue an upcall for the callback subscribed to (major, minor). It is
+    // delivered by a later `yieldk`. If nothing is subscribed at delivery time,
+    // the upcall is dropped like the real kernel would.
+    pub fn schedule_upcall(
+        &self,
+        major: usize,
+        minor: usize,
+        arg0: usize,
+        arg1: usize,
+        arg2: usize,
+    ) {
+        state().upcalls.push_back(Upcall {
+            major,
+            minor,
+            arg0,
+            arg1,
+            arg2,
+            data: None,
+        });
+    }
+
+    // Like `schedule_upcall`, but `data` is first copied into the buffer
+    // allowed for (major, minor) when the upcall is delivered, the way a read
+    // completes. This allows scripting reads that reuse the same buffer.
+    pub fn schedule_upcall_with_data(
+        &self,
+        major: usize,
+        minor: usize,
+        data: &[u8],
+        arg0: usize,
+        arg1: usize,
+        arg2: usize,
+    ) {
+        state().upcalls.push_back(Upcall {
+            major,
+            minor,
+            arg0,
+            arg1,
+            arg2,
+            data: Some(data.to_vec()),
+        });
+    }
+
+    pub fn pending_upcalls(&self) -> usize {
+        state().upcalls.len()
+    }
+
+    pub fn is_subscribed(&self, major: usize, minor: usize) -> bool {
+        state().callbacks.contains_key(&(major, minor))
+    }
+
+    // Copy of the buffer currently allowed for (major, minor)
+    pub fn allowed(&self, major: usize, minor: usize) -> Option<Vec<u8>> {
+        state().allowed.get(&(major, minor)).map(|&(ptr, len)| {
+            // Drivers only ever allow `static` buffers, so the buffer is still
+            // alive.
+            unsafe { std::slice::from_raw_parts(ptr as *const u8, len).to_vec() }
+        })
+    }
+
+    // Copy `data` into the start of the buffer allowed for (major, minor), the
+    // way a driver filling a read buffer would. Returns the number of bytes
+    // copied.
+    pub fn write_allowed(&self, major: usize, minor: usize, data: &[u8]) -> usize {
+        state().write_allowed(major, minor, data)
+    }
+}
+
+impl Drop for Kernel {
+    fn drop(&mut self) {
+        unsafe {
+            STATE = None;
+        }
+
+        KERNEL_IN_USE.store(false, Ordering::Release);
+    }
+}
+
+pub struct FakeSyscalls;
+
+impl Syscalls for FakeSyscalls {
+    fn yieldk() {
+        let s = state();
+
+        s.syscalls.push(SyscallRecord::Yield);
+
+        if let Some(upcall) = s.upcalls.pop_front() {
+            if let Some(data) = &upcall.data {
+                s.write_allowed(upcall.major, upcall.minor, data);
+            }
+
+            if let Some(&(callback, userdata)) = s.callbacks.get(&(upcall.major, upcall.minor)) {
+                let callback: extern "C" fn(usize, usize, usize, usize) =
+                    unsafe { mem::transmute(callback) };
+
+                callback(upcall.arg0, upcall.arg1, upcall.arg2, userdata);
+            }
+        }
+    }
+
+    unsafe fn subscribe(
+        major: usize,
+        minor: usize,
+        callback: *const unsafe extern "C" fn(usize, usize, usize, usize),
+        userdata: usize,
+    ) -> isize {
+        let s = state();
+
+        s.syscalls.push(SyscallRecord::Subscribe {
+            major,
+            minor,
+            userdata,
+        });
+
+        let res = s.result(SyscallClass::Subscribe, major, minor);
+
+        if res >= 0 {
+            if callback.is_null() {
+                s.callbacks.remove(&(major, minor));
+            } else {
+                s.callbacks
+                    .insert((major, minor), (callback as usize, userdata));
+            }
+        }
+
+        res
+    }
+
+    unsafe fn command(major: usize, minor: usize, arg1: usize, arg2: usize) -> isize {
+        let s = state();
+
+        s.syscalls.push(SyscallRecord::Command {
+            major,
+            minor,
+            arg1,
+            arg2,
+        });
+
+        s.result(SyscallClass::Command, major, minor)
+    }
+
+    unsafe fn allow(major: usize, minor: usize, ptr: *mut u8, len: usize) -> isize {
+        let s = state();
+
+        s.syscalls.push(SyscallRecord::Allow { major, minor, len });
+
+        let res = s.result(SyscallClass::Allow, major, minor);
+
+        if res >= 0 {
+            if ptr.is_null() {
+                s.allowed.remove(&(major, minor));
+            } else {
+                s.allowed.insert((major, minor), (ptr as usize, len));
+            }
+        }
+
+        res
+    }
+
+    unsafe fn memop(major: u32, arg1: usize) -> isize {
+        let s = state();
+
+        s.syscalls.push(SyscallRecord::Memop { major, arg1 });
+
+        s.result(SyscallClass::Memop, major as usize, 0)
+    }
+}
@@ -1,9 +1,17 @@
+use crate::queue::OverflowPolicy;
+use crate::result::Result;
+
 pub trait DriverTask {
-    // unsafe fn get_task(&self) -> impl Generator<Yield = (), Return = ()>;
+    // fn get_task(&self) -> impl Generator<Yield = (), Return = ()>;
     //
     // should also be here, but rust does not allow it.
 
     fn has_message(&self) -> bool;
+
+    // Number of callback messages dropped because the callback queue was full
+    fn dropped_messages(&self) -> usize;
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()>;
 }
 
 pub trait DriverTaskWithState: DriverTask {
@@ -13,5 +21,11 @@ pub trait DriverTaskWithState: DriverTask {
 pub trait DriverTaskClient {
     fn has_message(&self) -> bool;
 
+    // Reap the oldest message
     fn reap_message(&self);
+
+    // Number of client messages dropped because the client queue was full
+    fn dropped_messages(&self) -> usize;
+
+    fn configure_queue(&self, capacity: usize, policy: OverflowPolicy) -> Result<()>;
 }
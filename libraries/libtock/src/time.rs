@@ -0,0 +1,218 @@
+// Time as seen by the alarm driver. The tick counter is 32 bits wide and wraps
+// around, so `Instant`s are only comparable when they are less than 2^31 ticks
+// apart.
+
+const MS_PER_S: u64 = 1_000;
+const US_PER_S: u64 = 1_000_000;
+
+// Longest duration that `from_ms` and `from_us` give, so that a deadline that
+// far ahead still compares as after now
+pub const MAX_TICKS: u32 = i32::max_value() as u32;
+
+// Frequency of the tick counter, see `AlarmClient::frequency`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Frequency {
+    hz: u32,
+}
+
+impl Frequency {
+    // `None` if `hz` is 0
+    pub fn from_hz(hz: u32) -> Option<Frequency> {
+        if hz == 0 {
+            None
+        } else {
+            Some(Frequency { hz })
+        }
+    }
+
+    pub fn hz(&self) -> u32 {
+        self.hz
+    }
+
+    // Rounds up, so that waiting for the result takes at least `n` units
+    fn ticks_for(self, n: u32, units_per_s: u64) -> Option<u32> {
+        let ticks = (u64::from(n) * u64::from(self.hz) + units_per_s - 1) / units_per_s;
+
+        if ticks > u64::from(MAX_TICKS) {
+            None
+        } else {
+            Some(ticks as u32)
+        }
+    }
+
+    // Rounds down
+    fn units_in(self, ticks: u32, units_per_s: u64) -> Option<u32> {
+        let n = u64::from(ticks) * units_per_s / u64::from(self.hz);
+
+        if n > u64::from(u32::max_value()) {
+            None
+        } else {
+            Some(n as u32)
+        }
+    }
+}
+
+// Span of time in ticks
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Duration {
+    ticks: u32,
+}
+
+impl Duration {
+    pub const fn from_ticks(ticks: u32) -> Duration {
+        Duration { ticks }
+    }
+
+    // `None` if the duration is longer than `MAX_TICKS`. Conversions to ticks
+    // round up.
+    pub fn from_ms(ms: u32, frequency: Frequency) -> Option<Duration> {
+        frequency.ticks_for(ms, MS_PER_S).map(Duration::from_ticks)
+    }
+
+    pub fn from_us(us: u32, frequency: Frequency) -> Option<Duration> {
+        frequency.ticks_for(us, US_PER_S).map(Duration::from_ticks)
+    }
+
+    pub fn ticks(&self) -> u32 {
+        self.ticks
+    }
+
+    // `None` if the result does not fit in a `u32`. Conversions from ticks
+    // round down.
+    pub fn as_ms(&self, frequency: Frequency) -> Option<u32> {
+        frequency.units_in(self.ticks, MS_PER_S)
+    }
+
+    pub fn as_us(&self, frequency: Frequency) -> Option<u32> {
+        frequency.units_in(self.ticks, US_PER_S)
+    }
+
+    pub fn checked_add(&self, other: Duration) -> Option<Duration> {
+        self.ticks
+            .checked_add(other.ticks)
+            .map(Duration::from_ticks)
+    }
+
+    pub fn checked_sub(&self, other: Duration) -> Option<Duration> {
+        self.ticks
+            .checked_sub(other.ticks)
+            .map(Duration::from_ticks)
+    }
+}
+
+// Value of the tick counter, see `AlarmClient::now`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Instant {
+    ticks: u32,
+}
+
+impl Instant {
+    pub const fn from_ticks(ticks: u32) -> Instant {
+        Instant { ticks }
+    }
+
+    pub fn ticks(&self) -> u32 {
+        self.ticks
+    }
+
+    // Wraps around together with the tick counter
+    pub fn wrapping_add(&self, duration: Duration) -> Instant {
+        Instant::from_ticks(self.ticks.wrapping_add(duration.ticks))
+    }
+
+    pub fn wrapping_sub(&self, duration: Duration) -> Instant {
+        Instant::from_ticks(self.ticks.wrapping_sub(duration.ticks))
+    }
+
+    // Time from `earlier` to `self`. `earlier` must not be after `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_ticks(self.ticks.wrapping_sub(earlier.ticks))
+    }
+
+    // Correct across counter wraparound, as long as the two instants are less
+    // than 2^31 ticks apart.
+    pub fn is_before(&self, other: Instant) -> bool {
+        (other.ticks.wrapping_sub(self.ticks) as i32) > 0
+    }
+
+    pub fn is_after(&self, other: Instant) -> bool {
+        other.is_before(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hz(hz: u32) -> Frequency {
+        Frequency::from_hz(hz).unwrap()
+    }
+
+    #[test]
+    fn zero_frequency() {
+        assert_eq!(Frequency::from_hz(0), None);
+    }
+
+    #[test]
+    fn ms_and_us_to_ticks() {
+        assert_eq!(Duration::from_ms(2000, hz(32768)).unwrap().ticks(), 65536);
+        assert_eq!(Duration::from_ms(500, hz(32768)).unwrap().ticks(), 16384);
+
+        // 32.768 ticks, sub-millisecond precision is kept and rounded up
+        assert_eq!(Duration::from_ms(1, hz(32768)).unwrap().ticks(), 33);
+        assert_eq!(Duration::from_us(100, hz(32768)).unwrap().ticks(), 4);
+        assert_eq!(Duration::from_us(1, hz(16_000_000)).unwrap().ticks(), 16);
+        assert_eq!(Duration::from_us(0, hz(32768)).unwrap().ticks(), 0);
+    }
+
+    #[test]
+    fn ticks_to_ms_and_us() {
+        let d = Duration::from_ticks(65536 + 33);
+
+        assert_eq!(d.as_ms(hz(32768)), Some(2001));
+        assert_eq!(d.as_us(hz(32768)), Some(2_001_007));
+        assert_eq!(Duration::from_ticks(1).as_us(hz(16_000_000)), Some(0));
+    }
+
+    #[test]
+    fn conversions_are_checked() {
+        // 2^31 ticks at 1 MHz
+        assert_eq!(Duration::from_ms(2_147_484, hz(1_000_000)), None);
+        assert_eq!(
+            Duration::from_ms(2_147_483, hz(1_000_000)).map(|d| d.ticks()),
+            Some(2_147_483_000)
+        );
+        assert_eq!(
+            Duration::from_us(MAX_TICKS, hz(1_000_000)).map(|d| d.ticks()),
+            Some(MAX_TICKS)
+        );
+        assert_eq!(Duration::from_us(MAX_TICKS + 1, hz(1_000_000)), None);
+
+        assert_eq!(
+            Duration::from_ticks(u32::max_value()).as_us(hz(32768)),
+            None
+        );
+        assert_eq!(
+            Duration::from_ticks(u32::max_value()).as_ms(hz(32768)),
+            Some(131_071_999)
+        );
+
+        let max = Duration::from_ticks(u32::max_value());
+        assert_eq!(max.checked_add(Duration::from_ticks(1)), None);
+        assert_eq!(Duration::from_ticks(0).checked_sub(max), None);
+    }
+
+    #[test]
+    fn comparisons_across_wraparound() {
+        let before = Instant::from_ticks(u32::max_value() - 5);
+        let after = before.wrapping_add(Duration::from_ticks(10));
+
+        assert_eq!(after.ticks(), 4);
+        assert!(before.is_before(after));
+        assert!(after.is_after(before));
+        assert!(!after.is_before(before));
+        assert!(!before.is_before(before));
+        assert_eq!(after.duration_since(before).ticks(), 10);
+        assert_eq!(after.wrapping_sub(Duration::from_ticks(10)), before);
+    }
+}
@@ -0,0 +1,292 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::alarm::{AlarmClient, VirtualAlarm};
+use crate::futures::register_all;
+use crate::result::Result;
+use crate::task::DriverTaskClient;
+use crate::time::Duration;
+
+// Driver operation that completes with a client message and can be cancelled
+// before it does, for example `ConsoleReadClient::read_op`.
+pub trait Cancellable {
+    type Output;
+
+    fn start(&mut self) -> Result<()>;
+
+    // The client message that completes the operation has arrived
+    fn is_complete(&self) -> bool;
+
+    // Reaps the client message that completes the operation
+    fn complete(&mut self) -> Result<Self::Output>;
+
+    // Runs the abort path of the driver. Returns `true` if the driver still
+    // completes the aborted operation with a client message, which is then
+    // reaped and discarded by `WithTimeout`.
+    fn abort(&mut self) -> Result<bool>;
+
+    // The client message of an aborted operation is dropped by the driver
+    // instead of being queued, for a `WithTimeout` that is dropped before the
+    // operation completes.
+    fn discard(&mut self);
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Timeout<T> {
+    Completed(T),
+    TimedOut,
+}
+
+impl<T> Timeout<T> {
+    pub fn is_timed_out(&self) -> bool {
+        match self {
+            Timeout::Completed(_) => false,
+            Timeout::TimedOut => true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum WithTimeoutState {
+    Running,
+    Aborting,
+    Done,
+}
+
+// Operation racing a virtual alarm, see `with_timeout`. Dropping it before it
+// is done aborts the operation and cancels the alarm.
+pub struct WithTimeout<O: Cancellable> {
+    op: O,
+    alarm: VirtualAlarm,
+    state: WithTimeoutState,
+}
+
+// Starts `op` and a virtual alarm that expires after `duration`. If the alarm
+// expires first, the operation is aborted and the result is
+// `Timeout::TimedOut`.
+//
+// The returned `WithTimeout` is a future, and can be waited on from a task
+// with `await_timeout!`.
+pub fn with_timeout<O: Cancellable>(duration: Duration, op: O) -> Result<WithTimeout<O>> {
+    let mut op = op;
+
+    let alarm = AlarmClient::new().oneshot(duration)?;
+    op.start()?;
+
+    Ok(WithTimeout {
+        op,
+        alarm,
+        state: WithTimeoutState::Running,
+    })
+}
+
+impl<O: Cancellable> WithTimeout<O> {
+    // `None` while the operation, or aborting it, is still ongoing
+    pub fn check(&mut self) -> Option<Result<Timeout<O::Output>>> {
+        let res = self.step();
+
+        if res.is_some() {
+            self.state = WithTimeoutState::Done;
+        }
+
+        res
+    }
+
+    fn step(&mut self) -> Option<Result<Timeout<O::Output>>> {
+        if self.state == WithTimeoutState::Running {
+            // An operation that completed together with the alarm has
+            // completed in time.
+            if self.op.is_complete() {
+                let _ = self.alarm.cancel();
+                return Some(self.op.complete().map(Timeout::Completed));
+            }
+
+            if !self.alarm.has_message() {
+                return None;
+            }
+
+            self.alarm.reap_message();
+
+            match self.op.abort() {
+                Ok(true) => self.state = WithTimeoutState::Aborting,
+                Ok(false) => return Some(Ok(Timeout::TimedOut)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        if self.op.is_complete() {
+            let _ = self.op.complete();
+            Some(Ok(Timeout::TimedOut))
+        } else {
+            None
+        }
+    }
+}
+
+impl<O: Cancellable> Drop for WithTimeout<O> {
+    fn drop(&mut self) {
+        if self.state == WithTimeoutState::Done {
+            return;
+        }
+
+        let _ = self.alarm.cancel();
+
+        if self.op.is_complete() {
+            let _ = self.op.complete();
+            return;
+        }
+
+        // An aborted operation that still completes does so after we are gone
+        if self.state == WithTimeoutState::Aborting || self.op.abort() == Ok(true) {
+            self.op.discard();
+        }
+    }
+}
+
+impl<O: Cancellable + Unpin> Future for WithTimeout<O> {
+    type Output = Result<Timeout<O::Output>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.get_mut().check() {
+            Some(res) => Poll::Ready(res),
+            None => {
+                register_all(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::ops::Generator;
+    use core::pin::Pin;
+
+    use crate::console_read::{ConsoleRead, ConsoleReadClient};
+    use crate::drivers::Drivers;
+    use crate::executor::Executor;
+    use crate::has_client_messages;
+    use crate::syscalls::fake::{Kernel, SyscallRecord};
+    use crate::syscalls::yieldk;
+
+    #[test]
+    fn completes_in_time() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let console_read = drivers.console_read;
+        let mut executor = Executor::new(drivers.tasks);
+
+        kernel.schedule_upcall(1, 2, 0, 3, 0);
+
+        let res = executor.block_on(async {
+            let read = with_timeout(Duration::from_ticks(100), console_read.read_op(3))?;
+            kernel.write_allowed(1, 2, b"abc");
+
+            read.await
+        });
+
+        match res {
+            Ok(Timeout::Completed(buf)) => assert_eq!(buf.as_bytes(), b"abc"),
+            _ => panic!("read did not complete"),
+        }
+
+        // The alarm was cancelled
+        assert_eq!(
+            kernel.syscalls().last(),
+            Some(&SyscallRecord::Command {
+                major: 0,
+                minor: 3,
+                arg1: 100,
+                arg2: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn timed_out_read_is_aborted() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let console_read = drivers.console_read;
+        let mut executor = Executor::new(drivers.tasks);
+
+        // Alarm expires, then the aborted read completes with 2 bytes
+        kernel.schedule_upcall(0, 0, 100, 100, 0);
+        kernel.schedule_upcall(1, 2, 0, 2, 0);
+
+        let res = executor.block_on(async {
+            with_timeout(Duration::from_ticks(100), console_read.read_op(5))?.await
+        });
+
+        match res {
+            Ok(Timeout::TimedOut) => (),
+            _ => panic!("read did not time out"),
+        }
+        assert!(kernel.syscalls().contains(&SyscallRecord::Command {
+            major: 1,
+            minor: 3,
+            arg1: 0,
+            arg2: 0,
+        }));
+        assert_eq!(kernel.pending_upcalls(), 0);
+        assert!(!console_read.is_active());
+        assert!(!has_client_messages());
+    }
+
+    #[test]
+    fn dropped_read_is_aborted() {
+        let kernel = Kernel::new();
+        let console_read = ConsoleReadClient::new();
+        let console_read_driver = ConsoleRead::new();
+        let mut console_read_task = console_read_driver.get_task();
+
+        let read = with_timeout(Duration::from_ticks(100), console_read.read_op(5)).unwrap();
+        kernel.clear_syscalls();
+        drop(read);
+
+        // The alarm was cancelled and the read aborted
+        assert_eq!(
+            kernel.syscalls()[1..],
+            [
+                SyscallRecord::Command {
+                    major: 0,
+                    minor: 3,
+                    arg1: 100,
+                    arg2: 0,
+                },
+                SyscallRecord::Command {
+                    major: 1,
+                    minor: 3,
+                    arg1: 0,
+                    arg2: 0,
+                },
+            ]
+        );
+
+        // The aborted read completes without a client message
+        kernel.schedule_upcall(1, 2, 0, 1, 0);
+        yieldk();
+        Pin::new(&mut console_read_task).resume();
+        assert!(!console_read.is_active());
+        assert!(!has_client_messages());
+    }
+
+    #[test]
+    fn timed_out_button_wait() {
+        let kernel = Kernel::new();
+        let drivers = Drivers::take().unwrap();
+        let button = drivers.button;
+        let mut executor = Executor::new(drivers.tasks);
+
+        kernel.schedule_upcall(0, 0, 10, 10, 0);
+
+        let res = executor.block_on(async {
+            with_timeout(Duration::from_ticks(10), button.wait_pressed_op())?.await
+        });
+
+        assert_eq!(res.map(|t| t.is_timed_out()), Ok(true));
+        assert!(!has_client_messages());
+    }
+}
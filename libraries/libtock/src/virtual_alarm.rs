@@ -0,0 +1,258 @@
+use crate::result::{Error, Result};
+
+// Number of virtual alarms that can be armed at the same time
+pub const MAX_VIRTUAL_ALARMS: usize = 8;
+
+#[derive(Copy, Clone)]
+struct Deadline {
+    alarm: usize,
+    // Expires `dt` ticks after `reference`. Keeping the two apart, instead of
+    // storing `reference + dt`, is what lets us tell an expired deadline from
+    // one that is far in the future when the tick counter wraps.
+    reference: usize,
+    dt: usize,
+    // 0 for one-shot alarms
+    period: usize,
+}
+
+impl Deadline {
+    fn expiration(&self) -> usize {
+        self.reference.wrapping_add(self.dt)
+    }
+
+    fn has_expired(&self, now: usize) -> bool {
+        now.wrapping_sub(self.reference) >= self.dt
+    }
+
+    fn remaining(&self, now: usize) -> usize {
+        if self.has_expired(now) {
+            0
+        } else {
+            self.dt - now.wrapping_sub(self.reference)
+        }
+    }
+}
+
+// Set of armed virtual alarms, sorted by expiration with the earliest first.
+// The hardware alarm only needs to be programmed for `next_expiration`.
+//
+// Virtual alarms are identified by a number in `0..MAX_VIRTUAL_ALARMS`, which
+// is chosen by the caller.
+pub struct AlarmMux {
+    deadlines: [Option<Deadline>; MAX_VIRTUAL_ALARMS],
+    len: usize,
+}
+
+impl AlarmMux {
+    pub const fn new() -> AlarmMux {
+        AlarmMux {
+            deadlines: [None; MAX_VIRTUAL_ALARMS],
+            len: 0,
+        }
+    }
+
+    // Arm `alarm` to expire `dt` ticks after `now`, and then every `period`
+    // ticks unless `period` is 0. An alarm that is already armed is re-armed.
+    pub fn arm(&mut self, now: usize, alarm: usize, dt: usize, period: usize) -> Result<()> {
+        if alarm >= MAX_VIRTUAL_ALARMS {
+            return Err(Error::EINVAL);
+        }
+
+        self.disarm(alarm);
+
+        self.insert(
+            now,
+            Deadline {
+                alarm,
+                reference: now,
+                dt,
+                period,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Returns `false` if `alarm` was not armed
+    pub fn disarm(&mut self, alarm: usize) -> bool {
+        match self.position(alarm) {
+            Some(i) => {
+                self.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_armed(&self, alarm: usize) -> bool {
+        self.position(alarm).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Tick at which the earliest alarm expires
+    pub fn next_expiration(&self) -> Option<usize> {
+        self.deadlines[0].map(|d| d.expiration())
+    }
+
+    pub fn has_expired(&self, now: usize) -> bool {
+        self.deadlines[0].map_or(false, |d| d.has_expired(now))
+    }
+
+    // Calls `f(alarm, expiration)` for every alarm that has expired at `now`,
+    // earliest first. One-shot alarms are disarmed. Periodic alarms are
+    // re-armed for their next period after `now`; periods that were missed
+    // entirely are coalesced into a single call.
+    pub fn expire<F>(&mut self, now: usize, mut f: F)
+    where
+        F: FnMut(usize, usize),
+    {
+        while self.has_expired(now) {
+            let d = self.remove(0);
+            let expiration = d.expiration();
+
+            f(d.alarm, expiration);
+
+            if d.period != 0 {
+                let missed = now.wrapping_sub(expiration) / d.period;
+
+                self.insert(
+                    now,
+                    Deadline {
+                        reference: expiration.wrapping_add(missed * d.period),
+                        dt: d.period,
+                        ..d
+                    },
+                );
+            }
+        }
+    }
+
+    fn position(&self, alarm: usize) -> Option<usize> {
+        self.deadlines[..self.len]
+            .iter()
+            .position(|d| d.map_or(false, |d| d.alarm == alarm))
+    }
+
+    // As long as no deadline is left unexpired for more than a full counter
+    // period, deadlines never change order relative to each other. Sorting
+    // once on insertion is therefore enough.
+    fn insert(&mut self, now: usize, deadline: Deadline) {
+        let remaining = deadline.remaining(now);
+
+        let i = self.deadlines[..self.len]
+            .iter()
+            .position(|d| d.map_or(true, |d| d.remaining(now) > remaining))
+            .unwrap_or(self.len);
+
+        let mut j = self.len;
+        while j > i {
+            self.deadlines[j] = self.deadlines[j - 1];
+            j -= 1;
+        }
+
+        self.deadlines[i] = Some(deadline);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, i: usize) -> Deadline {
+        let d = self.deadlines[i].take().unwrap();
+
+        for j in i..(self.len - 1) {
+            self.deadlines[j] = self.deadlines[j + 1];
+        }
+
+        self.deadlines[self.len - 1] = None;
+        self.len -= 1;
+
+        d
+    }
+}
+
+impl Default for AlarmMux {
+    fn default() -> AlarmMux {
+        AlarmMux::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    fn expired(mux: &mut AlarmMux, now: usize) -> Vec<(usize, usize)> {
+        let mut v = Vec::new();
+        mux.expire(now, |alarm, expiration| v.push((alarm, expiration)));
+        v
+    }
+
+    #[test]
+    fn earliest_first() {
+        let mut mux = AlarmMux::new();
+
+        mux.arm(0, 0, 300, 0).unwrap();
+        mux.arm(0, 1, 100, 0).unwrap();
+        mux.arm(50, 2, 100, 0).unwrap();
+
+        assert_eq!(mux.next_expiration(), Some(100));
+        assert_eq!(expired(&mut mux, 99), []);
+        assert_eq!(expired(&mut mux, 160), [(1, 100), (2, 150)]);
+        assert_eq!(mux.next_expiration(), Some(300));
+        assert_eq!(expired(&mut mux, 300), [(0, 300)]);
+        assert!(mux.is_empty());
+    }
+
+    #[test]
+    fn ordering_across_wraparound() {
+        let mut mux = AlarmMux::new();
+        let now = usize::max_value() - 10;
+
+        // Expires after the counter wraps, but before alarm 1
+        mux.arm(now, 0, 20, 0).unwrap();
+        mux.arm(now, 1, 30, 0).unwrap();
+        mux.arm(now, 2, 5, 0).unwrap();
+
+        assert_eq!(mux.next_expiration(), Some(now + 5));
+        assert_eq!(expired(&mut mux, now + 5), [(2, now + 5)]);
+        assert_eq!(mux.next_expiration(), Some(9));
+        assert_eq!(expired(&mut mux, 8), []);
+        assert_eq!(expired(&mut mux, 20), [(0, 9), (1, 19)]);
+    }
+
+    #[test]
+    fn periodic_rearms() {
+        let mut mux = AlarmMux::new();
+
+        mux.arm(0, 3, 10, 10).unwrap();
+
+        assert_eq!(expired(&mut mux, 10), [(3, 10)]);
+        assert_eq!(mux.next_expiration(), Some(20));
+
+        // Periods 30 and 40 were missed
+        assert_eq!(expired(&mut mux, 45), [(3, 20)]);
+        assert_eq!(mux.next_expiration(), Some(50));
+        assert!(mux.is_armed(3));
+    }
+
+    #[test]
+    fn disarm_and_rearm() {
+        let mut mux = AlarmMux::new();
+
+        mux.arm(0, 0, 10, 0).unwrap();
+        mux.arm(0, 1, 20, 0).unwrap();
+
+        assert!(mux.disarm(0));
+        assert!(!mux.disarm(0));
+        assert_eq!(mux.next_expiration(), Some(20));
+
+        // Re-arming replaces the previous deadline
+        mux.arm(0, 1, 5, 0).unwrap();
+        assert_eq!(mux.next_expiration(), Some(5));
+        assert_eq!(expired(&mut mux, 100), [(1, 5)]);
+
+        assert_eq!(mux.arm(0, MAX_VIRTUAL_ALARMS, 10, 0), Err(Error::EINVAL));
+    }
+}
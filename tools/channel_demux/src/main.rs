@@ -0,0 +1,330 @@
+// Host side of `libtock::channel`. Splits console output back into its
+// channels, or frames input for a channel.
+//
+//     channel_demux [INPUT]               every channel, one line per "[id] ..."
+//     channel_demux -c ID [INPUT]         payload of channel ID only, as is
+//     channel_demux -f ID [OUTPUT]        frames stdin for channel ID
+//
+// INPUT and OUTPUT default to stdin and stdout. A serial port can be used
+// directly once it has been configured, for example with `stty`. Bytes outside
+// of frames, like printed output, are shown as channel "-".
+//
+// The frame format has to match `libraries/libtock/src/channel.rs`.
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process;
+
+const MAX_CHANNELS: usize = 4;
+const FRAME_SYNC: u8 = 0xa5;
+const FRAME_OVERHEAD: usize = 4;
+const MAX_PAYLOAD: usize = 64 - FRAME_OVERHEAD;
+
+#[derive(Debug, PartialEq)]
+enum Event<'a> {
+    // Byte outside of any frame
+    Raw(u8),
+    Frame(usize, &'a [u8]),
+    Corrupt,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Parse {
+    Sync,
+    Channel,
+    Len,
+    Payload,
+    Checksum,
+}
+
+struct Decoder {
+    state: Parse,
+    channel: usize,
+    payload: Vec<u8>,
+    len: usize,
+    sum: u8,
+}
+
+impl Decoder {
+    fn new() -> Decoder {
+        Decoder {
+            state: Parse::Sync,
+            channel: 0,
+            payload: Vec::with_capacity(MAX_PAYLOAD),
+            len: 0,
+            sum: 0,
+        }
+    }
+
+    fn feed(&mut self, byte: u8) -> Option<Event<'_>> {
+        match self.state {
+            Parse::Sync => {
+                if byte != FRAME_SYNC {
+                    return Some(Event::Raw(byte));
+                }
+
+                self.state = Parse::Channel;
+            }
+            Parse::Channel => {
+                if byte as usize >= MAX_CHANNELS {
+                    return self.corrupt();
+                }
+
+                self.channel = byte as usize;
+                self.sum = byte;
+                self.state = Parse::Len;
+            }
+            Parse::Len => {
+                if byte as usize > MAX_PAYLOAD {
+                    return self.corrupt();
+                }
+
+                self.len = byte as usize;
+                self.payload.clear();
+                self.sum = self.sum.wrapping_add(byte);
+                self.state = if self.len == 0 {
+                    Parse::Checksum
+                } else {
+                    Parse::Payload
+                };
+            }
+            Parse::Payload => {
+                self.payload.push(byte);
+                self.sum = self.sum.wrapping_add(byte);
+
+                if self.payload.len() == self.len {
+                    self.state = Parse::Checksum;
+                }
+            }
+            Parse::Checksum => {
+                if byte != self.sum {
+                    return self.corrupt();
+                }
+
+                self.state = Parse::Sync;
+                return Some(Event::Frame(self.channel, &self.payload));
+            }
+        }
+
+        None
+    }
+
+    fn corrupt(&mut self) -> Option<Event<'_>> {
+        self.state = Parse::Sync;
+        Some(Event::Corrupt)
+    }
+}
+
+fn frame(channel: u8, payload: &[u8]) -> Vec<u8> {
+    let mut f = vec![FRAME_SYNC, channel, payload.len() as u8];
+    f.extend_from_slice(payload);
+    f.push(f[1..].iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+    f
+}
+
+// Lines of each channel, and of the bytes outside of frames, are collected
+// separately so that interleaved frames do not mix up lines.
+struct Lines {
+    // Index `MAX_CHANNELS` is for bytes outside of frames
+    pending: Vec<Vec<u8>>,
+}
+
+impl Lines {
+    fn new() -> Lines {
+        Lines {
+            pending: vec![Vec::new(); MAX_CHANNELS + 1],
+        }
+    }
+
+    fn push<W: Write>(&mut self, stream: usize, bytes: &[u8], out: &mut W) -> io::Result<()> {
+        for &b in bytes {
+            if b == b'\n' {
+                self.flush(stream, out)?;
+            } else if b != b'\r' {
+                self.pending[stream].push(b);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush<W: Write>(&mut self, stream: usize, out: &mut W) -> io::Result<()> {
+        let line = &mut self.pending[stream];
+
+        if stream == MAX_CHANNELS {
+            write!(out, "[-] ")?;
+        } else {
+            write!(out, "[{}] ", stream)?;
+        }
+        writeln!(out, "{}", String::from_utf8_lossy(line))?;
+        line.clear();
+
+        out.flush()
+    }
+
+    fn flush_all<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        for stream in 0..self.pending.len() {
+            if !self.pending[stream].is_empty() {
+                self.flush(stream, out)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Returns the number of corrupt frames
+fn demux<R: Read, W: Write>(input: R, only: Option<usize>, out: &mut W) -> io::Result<usize> {
+    let mut decoder = Decoder::new();
+    let mut lines = Lines::new();
+    let mut corrupt = 0;
+
+    for byte in BufReader::new(input).bytes() {
+        match (decoder.feed(byte?), only) {
+            (Some(Event::Frame(c, payload)), Some(only)) if c == only => {
+                out.write_all(payload)?;
+                out.flush()?;
+            }
+            (Some(Event::Frame(c, payload)), None) => lines.push(c, payload, out)?,
+            (Some(Event::Raw(b)), None) => lines.push(MAX_CHANNELS, &[b], out)?,
+            (Some(Event::Corrupt), _) => corrupt += 1,
+            _ => {}
+        }
+    }
+
+    lines.flush_all(out)?;
+
+    Ok(corrupt)
+}
+
+fn send<R: BufRead, W: Write>(channel: u8, mut input: R, out: &mut W) -> io::Result<()> {
+    loop {
+        let n = {
+            let buf = input.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+
+            for payload in buf.chunks(MAX_PAYLOAD) {
+                out.write_all(&frame(channel, payload))?;
+            }
+            out.flush()?;
+
+            buf.len()
+        };
+
+        input.consume(n);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: channel_demux [-c ID] [INPUT]");
+    eprintln!("       channel_demux -f ID [OUTPUT]");
+    process::exit(2);
+}
+
+fn channel_arg(arg: Option<String>) -> usize {
+    match arg.and_then(|a| a.parse().ok()) {
+        Some(id) if id < MAX_CHANNELS => id,
+        _ => usage(),
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut only = None;
+    let mut frame_for = None;
+    let mut path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" => only = Some(channel_arg(args.next())),
+            "-f" => frame_for = Some(channel_arg(args.next())),
+            _ if arg.starts_with('-') || path.is_some() => usage(),
+            _ => path = Some(arg),
+        }
+    }
+
+    let res = match frame_for {
+        Some(_) if only.is_some() => usage(),
+        Some(channel) => {
+            let stdin = io::stdin();
+
+            match path {
+                Some(p) => OpenOptions::new()
+                    .write(true)
+                    .open(&p)
+                    .and_then(|mut f| send(channel as u8, stdin.lock(), &mut f)),
+                None => send(channel as u8, stdin.lock(), &mut io::stdout()),
+            }
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+
+            let corrupt = match path {
+                Some(p) => File::open(&p).and_then(|f| demux(f, only, &mut out)),
+                None => demux(io::stdin(), only, &mut out),
+            };
+
+            corrupt.map(|n| {
+                if n > 0 {
+                    eprintln!("{} corrupt frames", n);
+                }
+            })
+        }
+    };
+
+    if let Err(e) = res {
+        eprintln!("channel_demux: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_per_channel() {
+        let mut input = b"boot\n".to_vec();
+        input.extend(frame(0, b"log "));
+        input.extend(frame(2, b"a\nb"));
+        input.extend(frame(0, b"line\n"));
+
+        let mut out = Vec::new();
+        assert_eq!(demux(&input[..], None, &mut out).unwrap(), 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[-] boot\n[2] a\n[0] log line\n[2] b\n"
+        );
+    }
+
+    #[test]
+    fn single_channel_resyncs() {
+        let mut corrupt = frame(1, b"bad");
+        corrupt[4] = b'B';
+
+        let mut input = corrupt;
+        input.extend(frame(1, &[0, 1, 2]));
+        input.extend(frame(3, b"other"));
+
+        let mut out = Vec::new();
+        assert_eq!(demux(&input[..], Some(1), &mut out).unwrap(), 1);
+        assert_eq!(out, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn send_frames_input() {
+        let input = vec![b'x'; MAX_PAYLOAD + 1];
+
+        let mut out = Vec::new();
+        send(2, &input[..], &mut out).unwrap();
+
+        let mut decoded = Vec::new();
+        assert_eq!(demux(&out[..], Some(2), &mut decoded).unwrap(), 0);
+        assert_eq!(decoded, input);
+        assert_eq!(out.len(), input.len() + 2 * FRAME_OVERHEAD);
+    }
+}